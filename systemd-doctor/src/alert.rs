@@ -0,0 +1,565 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::collector::{DISK_COLUMNS, MEMINFO_COLUMNS, TEMPERATURE_COLUMNS};
+use crate::config::{AlertConfig, Config};
+use crate::event::{EventKind, HealthEvent};
+use crate::persist::{instant_at, wall_clock};
+use crate::sample::{Metric, Sample, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Ok,
+    // above the trigger threshold for less than `for`
+    Pending,
+    Firing,
+    // back at or below the clear threshold for `clear_for`, behaves like Ok
+    Resolved,
+}
+
+impl AlertState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertState::Ok => "ok",
+            AlertState::Pending => "pending",
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+impl fmt::Display for AlertState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Current state of one service/metric alert, as shown in the state store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    // None for device-wide alerts
+    pub service: Option<String>,
+    pub metric: Metric,
+    pub state: AlertState,
+    pub value: f64,
+    pub threshold: f64,
+    // when the alert entered its state
+    pub since: DateTime<Local>,
+}
+
+impl Alert {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "service": self.service,
+            "metric": self.metric.name(),
+            "state": self.state.name(),
+            "value": self.value,
+            "threshold": self.threshold,
+            "since": self.since.to_rfc3339(),
+        })
+    }
+}
+
+// Settings of one alert, per-alert values over the [alerts] defaults
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AlertRule {
+    pub trigger: f64,
+    pub clear: f64,
+    pub pending: Duration,
+    pub clear_for: Duration,
+    pub renotify: Duration,
+}
+
+impl AlertRule {
+    pub fn new(trigger: f64, defaults: &AlertConfig, own: Option<&AlertConfig>) -> Self {
+        let pick = |field: fn(&AlertConfig) -> Option<u64>| {
+            Duration::from_secs(own.and_then(field).or(field(defaults)).unwrap_or(0))
+        };
+        Self {
+            trigger,
+            // the clear threshold can only be below the trigger
+            clear: own
+                .and_then(|own| own.clear)
+                .or(defaults.clear)
+                .unwrap_or(trigger)
+                .min(trigger),
+            pending: pick(|config| config.pending),
+            clear_for: pick(|config| config.clear_for),
+            renotify: pick(|config| config.renotify),
+        }
+    }
+}
+
+/// A tracked alert as written to the state file, so a restart does not announce it again.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SavedAlert {
+    pub service: Option<String>,
+    pub metric: Metric,
+    pub state: AlertState,
+    pub since: DateTime<Local>,
+    pub entered: DateTime<Local>,
+    pub clearing_since: Option<DateTime<Local>>,
+    pub last_notified: Option<DateTime<Local>>,
+    pub notified: bool,
+    pub value: f64,
+    pub threshold: f64,
+}
+
+struct Track {
+    state: AlertState,
+    entered: Instant,
+    since: DateTime<Local>,
+    // first sample at or below the clear threshold while firing
+    clearing_since: Option<Instant>,
+    last_notified: Option<Instant>,
+    // the current firing was announced, so its resolution is too
+    notified: bool,
+    value: f64,
+    threshold: f64,
+}
+
+impl Track {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: AlertState::Ok,
+            entered: now,
+            since: Local::now(),
+            clearing_since: None,
+            last_notified: None,
+            notified: false,
+            value: 0.0,
+            threshold: 0.0,
+        }
+    }
+
+    fn restore(saved: &SavedAlert, now: Instant) -> Self {
+        let instant = |time| instant_at(time, now);
+        Self {
+            state: saved.state,
+            entered: instant(saved.entered).unwrap_or(now),
+            since: saved.since,
+            clearing_since: saved.clearing_since.and_then(instant),
+            last_notified: saved.last_notified.and_then(instant),
+            notified: saved.notified,
+            value: saved.value,
+            threshold: saved.threshold,
+        }
+    }
+
+    fn save(&self, service: &str, metric: Metric, now: Instant) -> SavedAlert {
+        SavedAlert {
+            service: Some(service.to_string()).filter(|service| !service.is_empty()),
+            metric,
+            state: self.state,
+            since: self.since,
+            entered: wall_clock(self.entered, now),
+            clearing_since: self.clearing_since.map(|time| wall_clock(time, now)),
+            last_notified: self.last_notified.map(|time| wall_clock(time, now)),
+            notified: self.notified,
+            value: self.value,
+            threshold: self.threshold,
+        }
+    }
+
+    fn enter(&mut self, state: AlertState, now: Instant) {
+        self.state = state;
+        self.entered = now;
+        self.since = Local::now();
+    }
+
+    // Moves the state machine, returns the event kind to announce
+    fn update(&mut self, value: f64, rule: &AlertRule, now: Instant) -> Option<EventKind> {
+        self.value = value;
+        self.threshold = rule.trigger;
+        match self.state {
+            AlertState::Ok | AlertState::Resolved => {
+                if value > rule.trigger {
+                    self.enter(AlertState::Pending, now);
+                    return self.fire_if_due(rule, now);
+                }
+            }
+            AlertState::Pending => {
+                if value <= rule.trigger {
+                    self.enter(AlertState::Ok, now);
+                } else {
+                    return self.fire_if_due(rule, now);
+                }
+            }
+            AlertState::Firing => {
+                if value > rule.clear {
+                    self.clearing_since = None;
+                    return None;
+                }
+                let clearing_since = *self.clearing_since.get_or_insert(now);
+                if now.duration_since(clearing_since) >= rule.clear_for {
+                    self.enter(AlertState::Resolved, now);
+                    self.clearing_since = None;
+                    if self.notified {
+                        return Some(EventKind::Recovered);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn fire_if_due(&mut self, rule: &AlertRule, now: Instant) -> Option<EventKind> {
+        if now.duration_since(self.entered) < rule.pending {
+            return None;
+        }
+        self.enter(AlertState::Firing, now);
+        // an alert firing again within `renotify` of the last announcement stays quiet
+        self.notified = self
+            .last_notified
+            .is_none_or(|last| now.duration_since(last) >= rule.renotify);
+        if self.notified {
+            self.last_notified = Some(now);
+            Some(EventKind::Breach)
+        } else {
+            None
+        }
+    }
+}
+
+fn number(sample: &Sample, key: &str) -> Option<f64> {
+    sample
+        .readings
+        .iter()
+        .find(|reading| reading.service.is_none() && reading.key == key)
+        .and_then(|reading| match reading.value {
+            Value::Number(value) => Some(value),
+            _ => None,
+        })
+}
+
+/*
+Alert state of every service/metric with a threshold: OK -> Pending -> Firing -> Resolved.
+An alert fires once the value stayed above the trigger threshold for `for` seconds and resolves
+once it stayed at or below the (lower) clear threshold for `clear_for` seconds, so a value
+hovering around the limit does not flap. Only firing and resolving are announced, as `breach`
+and `recovered` events, which remediation and hooks act on.
+Per service: cpu in %, memory in % of the total memory. For the device: cpu_temperature and
+disk as % of the root filesystem used, one alert however many services set a disk threshold.
+*/
+#[derive(Default)]
+pub struct AlertWatch {
+    // (service, metric), service is empty for device-wide alerts
+    tracks: BTreeMap<(String, Metric), Track>,
+    // from the last meminfo reading
+    mem_total_mb: Option<f64>,
+    // read from the state file, taken over when the alert is first checked
+    saved: BTreeMap<(String, Metric), SavedAlert>,
+    // a track changed state since the last export
+    changed: bool,
+}
+
+impl AlertWatch {
+    /// Continues the alerts saved by a previous run.
+    pub fn restore(saved: &[SavedAlert]) -> Self {
+        Self {
+            saved: saved
+                .iter()
+                .map(|alert| {
+                    let service = alert.service.clone().unwrap_or_default();
+                    ((service, alert.metric), alert.clone())
+                })
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// The tracked alerts to save, None when none changed state since the last call.
+    pub fn export(&mut self, now: Instant) -> Option<Vec<SavedAlert>> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some(
+            self.tracks
+                .iter()
+                .map(|((service, metric), track)| track.save(service, *metric, now))
+                .collect(),
+        )
+    }
+
+    pub fn check(&mut self, config: &Config, sample: &Sample, now: Instant) -> Vec<HealthEvent> {
+        let mut events = Vec::new();
+        for (service, value) in self.values(config, sample) {
+            let Some(rule) = Self::rule(config, &service, sample.metric) else {
+                continue;
+            };
+            let key = (service.clone(), sample.metric);
+            let saved = self.saved.remove(&key);
+            let track = self.tracks.entry(key).or_insert_with(|| match &saved {
+                Some(saved) => Track::restore(saved, now),
+                None => Track::new(now),
+            });
+            let state = track.state;
+            let kind = track.update(value, &rule, now);
+            self.changed |= track.state != state;
+            let Some(kind) = kind else {
+                continue;
+            };
+            let metric = sample.metric;
+            let message = match kind {
+                EventKind::Breach => format!(
+                    "{} {:.1} above threshold {} for {}s",
+                    metric,
+                    value,
+                    rule.trigger,
+                    rule.pending.as_secs()
+                ),
+                _ => format!("{} {:.1} back at or below {}", metric, value, rule.clear),
+            };
+            let service = Some(service.as_str()).filter(|service| !service.is_empty());
+            events.push(HealthEvent::new(service, kind, message).with_reading(
+                metric,
+                value,
+                rule.trigger,
+            ));
+        }
+        events
+    }
+
+    /// Alerts of `metric` in their current state.
+    pub fn alerts(&self, metric: Metric) -> Vec<Alert> {
+        self.tracks
+            .iter()
+            .filter(|((_, tracked), _)| *tracked == metric)
+            .map(|((service, metric), track)| Alert {
+                service: Some(service.clone()).filter(|service| !service.is_empty()),
+                metric: *metric,
+                state: track.state,
+                value: track.value,
+                threshold: track.threshold,
+                since: track.since,
+            })
+            .collect()
+    }
+
+    fn rule(config: &Config, service: &str, metric: Metric) -> Option<AlertRule> {
+        if service.is_empty() {
+            let thresholds = config.global_thresholds.clone().unwrap_or_default();
+            let (trigger, name) = match metric {
+                Metric::Temperature => (thresholds.cpu_temperature?, "cpu_temperature"),
+                // the root filesystem is shared, so the strictest disk threshold of the services
+                Metric::Disk => {
+                    let trigger = thresholds.disk.or_else(|| {
+                        config
+                            .thresholds
+                            .values()
+                            .filter_map(|threshold| threshold.disk)
+                            .min()
+                    })?;
+                    (trigger as f64, "disk")
+                }
+                _ => return None,
+            };
+            return Some(AlertRule::new(
+                trigger,
+                &config.alerts,
+                thresholds.alerts.get(name),
+            ));
+        }
+        let threshold = config.thresholds.get(service)?;
+        let trigger = match metric {
+            Metric::Cpu => f64::from(threshold.cpu?),
+            Metric::Memory => threshold.memory?,
+            _ => return None,
+        };
+        Some(AlertRule::new(
+            trigger,
+            &config.alerts,
+            threshold.alerts.get(metric.name()),
+        ))
+    }
+
+    // (service, value) pairs of a sample in the unit of the thresholds
+    fn values(&mut self, config: &Config, sample: &Sample) -> Vec<(String, f64)> {
+        // the memory sample carries the RSS and the smaps totals, the service's measure is used
+        let service_values = || {
+            sample
+                .readings
+                .iter()
+                .filter_map(|reading| match (&reading.service, &reading.value) {
+                    (Some(service), Value::Number(value)) => Some((service, reading, *value)),
+                    _ => None,
+                })
+                .filter(|(service, reading, _)| match sample.metric {
+                    Metric::Memory => reading.key == config.memory_measure(service).key(),
+                    metric => reading.key == metric.name(),
+                })
+                .map(|(service, _, value)| (service.clone(), value))
+        };
+        match sample.metric {
+            Metric::Memory => {
+                if let Some(total) = number(sample, MEMINFO_COLUMNS[0]).filter(|total| *total > 0.0)
+                {
+                    self.mem_total_mb = Some(total);
+                }
+                let Some(total) = self.mem_total_mb else {
+                    return Vec::new();
+                };
+                service_values()
+                    .map(|(service, value)| (service, value / total * 100.0))
+                    .collect()
+            }
+            Metric::Cpu => service_values().collect(),
+            Metric::Disk => {
+                let (Some(total), Some(used)) = (
+                    number(sample, DISK_COLUMNS[0]),
+                    number(sample, DISK_COLUMNS[1]),
+                ) else {
+                    return Vec::new();
+                };
+                if total <= 0.0 {
+                    return Vec::new();
+                }
+                vec![(String::new(), used / total * 100.0)]
+            }
+            Metric::Temperature => number(sample, TEMPERATURE_COLUMNS[0])
+                .map(|temperature| vec![(String::new(), temperature)])
+                .unwrap_or_default(),
+            Metric::Unit => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alert::{AlertState, AlertWatch};
+    use crate::collector::{DISK_COLUMNS, MEMINFO_COLUMNS};
+    use crate::config::{AlertConfig, Config, ServiceThreshold};
+    use crate::event::EventKind;
+    use crate::sample::{cpu_sample, Metric, Reading, Sample, Value};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn memory_sample(total: Option<f64>, used: f64) -> Sample {
+        let mut sample = Sample::new(Metric::Memory);
+        if let Some(total) = total {
+            sample
+                .readings
+                .push(Reading::global(MEMINFO_COLUMNS[0], Value::Number(total)));
+        }
+        sample
+            .readings
+            .push(Reading::service("ota", Metric::Memory, Value::Number(used)));
+        sample
+    }
+
+    #[test]
+    fn test_breach_and_recovery_of_memory() {
+        let mut config = Config::default();
+        config.thresholds.insert(
+            "ota".to_string(),
+            ServiceThreshold {
+                memory: Some(50.0),
+                ..Default::default()
+            },
+        );
+        let mut watch = AlertWatch::default();
+        let now = Instant::now();
+
+        let events = watch.check(&config, &memory_sample(Some(1000.0), 600.0), now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Breach);
+        assert_eq!(events[0].service.as_deref(), Some("ota"));
+        assert_eq!(events[0].value, Some(60.0));
+        // a restarted doctor continues the firing alert
+        let saved = watch.export(now).unwrap();
+        assert!(watch.export(now).is_none());
+        let mut watch = AlertWatch::restore(&saved);
+        assert!(watch
+            .check(&config, &memory_sample(Some(1000.0), 650.0), now)
+            .is_empty());
+        // the total of an earlier sample is used
+        assert!(watch
+            .check(&config, &memory_sample(None, 700.0), now)
+            .is_empty());
+
+        let events = watch.check(&config, &memory_sample(None, 400.0), now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Recovered);
+    }
+
+    #[test]
+    fn test_pending_hysteresis_and_renotify() {
+        let mut config = Config::default();
+        config.thresholds.insert(
+            "ota".to_string(),
+            ServiceThreshold {
+                cpu: Some(80.0),
+                alerts: HashMap::from([(
+                    "cpu".to_string(),
+                    AlertConfig {
+                        pending: Some(30),
+                        clear: Some(70.0),
+                        renotify: Some(600),
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            },
+        );
+        let mut watch = AlertWatch::default();
+        let start = Instant::now();
+        let mut check = |load, secs| {
+            watch
+                .check(
+                    &config,
+                    &cpu_sample(load),
+                    start + Duration::from_secs(secs),
+                )
+                .iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>()
+        };
+
+        // a short spike only goes pending
+        assert!(check(90.0, 0).is_empty());
+        assert!(check(75.0, 10).is_empty());
+        assert!(check(90.0, 20).is_empty());
+        assert_eq!(check(90.0, 50), vec![EventKind::Breach]);
+        // between the clear and trigger thresholds it keeps firing
+        assert!(check(75.0, 60).is_empty());
+        assert!(check(85.0, 70).is_empty());
+        assert_eq!(check(65.0, 80), vec![EventKind::Recovered]);
+        // firing again within renotify is not announced, nor is its recovery
+        assert!(check(90.0, 100).is_empty());
+        assert!(check(90.0, 140).is_empty());
+        assert!(check(60.0, 150).is_empty());
+        assert_eq!(watch.alerts(Metric::Cpu)[0].state, AlertState::Resolved);
+    }
+
+    #[test]
+    fn test_disk_is_one_device_alert() {
+        let mut config = Config::default();
+        for (service, disk) in [("ota", 90), ("logging", 85)] {
+            config.thresholds.insert(
+                service.to_string(),
+                ServiceThreshold {
+                    disk: Some(disk),
+                    ..Default::default()
+                },
+            );
+        }
+        let mut sample = Sample::new(Metric::Disk);
+        for (column, value) in DISK_COLUMNS.iter().zip([1000.0, 870.0, 130.0]) {
+            sample
+                .readings
+                .push(Reading::global(column, Value::Number(value)));
+        }
+        let mut watch = AlertWatch::default();
+
+        let events = watch.check(&config, &sample, Instant::now());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Breach);
+        assert_eq!(events[0].service, None);
+        assert_eq!(events[0].threshold, Some(85.0));
+    }
+}
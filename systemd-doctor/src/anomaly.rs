@@ -0,0 +1,232 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::event::{EventKind, HealthEvent};
+use crate::persist::StateFile;
+use crate::sample::{Metric, Sample, Value};
+
+// how often learned baselines are written to disk, on top of the final save on shutdown
+const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+// share of alpha anomalous samples are learned with, a lasting shift still becomes the baseline
+const ANOMALOUS_WEIGHT: f64 = 0.1;
+
+/// Exponentially weighted mean and variance of one service metric.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Baseline {
+    pub mean: f64,
+    pub variance: f64,
+    // samples learned so far
+    pub count: u64,
+}
+
+impl Baseline {
+    pub fn update(&mut self, value: f64, alpha: f64) {
+        if self.count == 0 {
+            self.mean = value;
+            self.variance = 0.0;
+        } else {
+            let diff = value - self.mean;
+            let increment = alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        }
+        self.count += 1;
+    }
+
+    pub fn stddev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+}
+
+/*
+Learns a rolling baseline (EWMA of mean and variance) of each service's cpu and memory and flags
+samples more than `k` standard deviations away from it, once it has seen `warmup` samples. An
+anomaly is announced when it starts, as an `anomaly` event carrying the bound it crossed, so it
+is handled like a threshold breach, and when the value is back within the bound as `recovered`.
+Anomalous samples are learned with a tenth of the weight, so an anomaly does not quickly become
+the baseline it is measured against. Baselines are saved in the state file so they survive
+restarts.
+*/
+pub struct AnomalyDetector {
+    baselines: BTreeMap<String, BTreeMap<String, Baseline>>,
+    // side of the band each anomalous value left it on, 1 above and -1 below
+    anomalous: HashMap<(String, Metric), f64>,
+    state_file: Arc<StateFile>,
+    last_saved: Instant,
+}
+
+impl AnomalyDetector {
+    /// Starts from the baselines saved in the state file, if any.
+    pub fn new(state_file: Arc<StateFile>) -> Self {
+        Self {
+            baselines: state_file.read(|state| state.baselines.clone()),
+            anomalous: HashMap::new(),
+            state_file,
+            last_saved: Instant::now(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn baseline(&self, service: &str, metric: Metric) -> Option<&Baseline> {
+        self.baselines.get(service)?.get(metric.name())
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        self.last_saved = Instant::now();
+        let baselines = self.baselines.clone();
+        self.state_file.update(|state| state.baselines = baselines)
+    }
+
+    pub fn check(&mut self, config: &Config, sample: &Sample, now: Instant) -> Vec<HealthEvent> {
+        let mut events = Vec::new();
+        let settings = &config.anomaly;
+        let metric = sample.metric;
+        if !settings.enabled || !settings.metrics.iter().any(|name| name == metric.name()) {
+            return events;
+        }
+
+        for reading in &sample.readings {
+            let (Some(service), Value::Number(value)) = (&reading.service, &reading.value) else {
+                continue;
+            };
+            let key = match metric {
+                Metric::Memory => config.memory_measure(service).key(),
+                _ => metric.name(),
+            };
+            if reading.key != key {
+                continue;
+            }
+            let value = *value;
+            let baseline = self
+                .baselines
+                .entry(service.clone())
+                .or_default()
+                .entry(metric.name().to_string())
+                .or_default();
+
+            let key = (service.clone(), metric);
+            if baseline.count >= settings.warmup {
+                let sigma = baseline.stddev().max(settings.min_deviation);
+                let deviation = (value - baseline.mean) / sigma;
+                if deviation.abs() > settings.k {
+                    if !self.anomalous.contains_key(&key) {
+                        self.anomalous.insert(key.clone(), deviation.signum());
+                        let bound = baseline.mean + settings.k * sigma * deviation.signum();
+                        events.push(
+                            HealthEvent::new(
+                                Some(service),
+                                EventKind::Anomaly,
+                                format!(
+                                    "{} {:.1} is {:.1}σ from its baseline {:.1}±{:.1}",
+                                    metric, value, deviation, baseline.mean, sigma
+                                ),
+                            )
+                            .with_reading(metric, value, bound),
+                        );
+                    }
+                } else if let Some(side) = self.anomalous.remove(&key) {
+                    events.push(
+                        HealthEvent::new(
+                            Some(service),
+                            EventKind::Recovered,
+                            format!(
+                                "{} {:.1} back within {}σ of its baseline {:.1}±{:.1}",
+                                metric, value, settings.k, baseline.mean, sigma
+                            ),
+                        )
+                        .with_reading(
+                            metric,
+                            value,
+                            baseline.mean + settings.k * sigma * side,
+                        ),
+                    );
+                }
+            }
+            let alpha = match self.anomalous.contains_key(&key) {
+                true => settings.alpha * ANOMALOUS_WEIGHT,
+                false => settings.alpha,
+            };
+            baseline.update(value, alpha);
+        }
+
+        if now.duration_since(self.last_saved) >= SAVE_INTERVAL {
+            if let Err(e) = self.save() {
+                error!("Failed to save baselines: {}", e);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::AnomalyDetector;
+    use crate::config::Config;
+    use crate::event::EventKind;
+    use crate::persist::StateFile;
+    use crate::sample::{cpu_sample, Metric};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_spike_flagged_and_baseline_persisted() {
+        let mut config = Config::default();
+        config.anomaly.enabled = true;
+        config.anomaly.warmup = 20;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut detector = AnomalyDetector::new(Arc::new(StateFile::load(path.clone())));
+        let now = Instant::now();
+
+        let load = |i: usize| 20.0 + (i % 5) as f64;
+        for i in 0..10 {
+            assert!(detector
+                .check(&config, &cpu_sample(load(i)), now)
+                .is_empty());
+        }
+        // a spike during warm-up is learned, not flagged
+        assert!(detector.check(&config, &cpu_sample(90.0), now).is_empty());
+        for i in 0..100 {
+            assert!(detector
+                .check(&config, &cpu_sample(load(i)), now)
+                .is_empty());
+        }
+        let events = detector.check(&config, &cpu_sample(90.0), now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Anomaly);
+        assert!(events[0].threshold.unwrap() < 90.0);
+        // still anomalous, not announced again, and barely learned
+        for _ in 0..10 {
+            assert!(detector.check(&config, &cpu_sample(95.0), now).is_empty());
+        }
+        let events = detector.check(&config, &cpu_sample(22.0), now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Recovered);
+        assert!(events[0].threshold.unwrap() > 22.0);
+
+        detector.save().unwrap();
+        let restored = AnomalyDetector::new(Arc::new(StateFile::load(path)));
+        let saved = detector.baseline("ota", Metric::Cpu).unwrap();
+        let loaded = restored.baseline("ota", Metric::Cpu).unwrap();
+        assert_eq!(loaded.count, 123);
+        assert!((loaded.mean - saved.mean).abs() < 1e-9);
+        assert!((loaded.variance - saved.variance).abs() < 1e-9);
+
+        // a drop recovers through the lower bound
+        let mut detector = AnomalyDetector::new(Arc::new(StateFile::default()));
+        for i in 0..30 {
+            detector.check(&config, &cpu_sample(50.0 + load(i) - 20.0), now);
+        }
+        let events = detector.check(&config, &cpu_sample(10.0), now);
+        assert_eq!(events[0].kind, EventKind::Anomaly);
+        assert!(events[0].threshold.unwrap() > 10.0);
+        let events = detector.check(&config, &cpu_sample(52.0), now);
+        assert_eq!(events[0].kind, EventKind::Recovered);
+        assert!(events[0].threshold.unwrap() < 52.0);
+    }
+}
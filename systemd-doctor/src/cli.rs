@@ -0,0 +1,76 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "systemd-doctor",
+    version,
+    about = "Health monitoring service for systemd services"
+)]
+pub struct Cli {
+    /// Path to the main config file, drop-ins are read from config.d/ next to it
+    #[arg(long, default_value = "config.toml")]
+    pub config: String,
+
+    /// Override a config value, e.g. --set monitor.interval=5 (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start monitoring the configured services (default)
+    Run,
+    /// Report the memory trend of each service from the csv history
+    Check,
+    /// Summarize the cpu, memory and events history of each service
+    Report {
+        /// Start of the range: 2024-06-03 08:00, 2024-06-03 or an age such as 24h (default: all)
+        #[arg(long)]
+        since: Option<String>,
+        /// End of the range, same forms as --since (default: now)
+        #[arg(long)]
+        until: Option<String>,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+        /// Write a self-contained HTML page with charts to this file instead
+        #[arg(long, value_name = "FILE")]
+        html: Option<PathBuf>,
+        /// Directory of the DrViet_*.csv files, e.g. pulled off a device (default: output_dir)
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Live view of the tracked services and the device, sampled with the collectors
+    Top {
+        /// Seconds between samples
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Query or command the running doctor through its control socket
+    Ctl {
+        /// status, snapshot, services, mute <service> <duration>, trigger-check, reload or dump-state
+        command: String,
+        args: Vec<String>,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective merged configuration and the source of each value
+    Dump,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ReportFormat {
+    Table,
+    Json,
+    Markdown,
+}
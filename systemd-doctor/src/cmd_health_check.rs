@@ -1,8 +1,11 @@
+use log::debug;
 use std::fs;
 use std::io;
+use std::process::Command;
+
+use crate::unit_state::UnitState;
 use std::str::FromStr;
 use std::{fs::File, io::BufRead};
-use std::{num::ParseIntError, process::Command};
 
 pub struct MemInfo {
     pub total_memory: u64,
@@ -87,18 +90,12 @@ impl CmdHealCheck {
             .trim()
             .parse()
             .unwrap_or(0.0);
-        println!("{}: cpu_load: {}", service, load);
+        debug!("{}: cpu_load: {}", service, load);
         Ok(load)
     }
 
-    // using the VmRSS field from the /proc/[pid]/status file
-    pub fn cmd_check_memory_usage_mb(
-        &self,
-        service: &str,
-        _threshold_mb: Option<u64>,
-    ) -> Result<f64, String> {
-        let mut total_memory_kb: u64 = 0;
-
+    // processes whose name matches `service`
+    pub fn cmd_find_pids(&self, service: &str) -> Result<Vec<u32>, String> {
         let output = Command::new("pgrep")
             .arg(service)
             .output()
@@ -108,45 +105,21 @@ impl CmdHealCheck {
             return Err(format!("Failed to execute command: {}", stderr));
         }
 
-        let pids = String::from_utf8_lossy(&output.stdout)
-            .trim()
+        let pids: Vec<u32> = String::from_utf8_lossy(&output.stdout)
             .split_whitespace()
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-
+            .filter_map(|pid| pid.parse().ok())
+            .collect();
         if pids.is_empty() {
             return Err(format!("Service {} not found", service));
         }
-
-        for pid in pids {
-            let status_path = format!("/proc/{}/status", pid);
-            let status = fs::read_to_string(&status_path)
-                .map_err(|e| format!("Failed to read file: {}", e))?;
-            for line in status.lines() {
-                if line.starts_with("VmRSS:") {
-                    let rss_kb: u64 = line
-                        .split_whitespace()
-                        .nth(1)
-                        .ok_or_else(|| format!("Failed to parse VmRSS for PID {}", pid))?
-                        .parse()
-                        .map_err(|e| {
-                            format!("Failed to parse memory usage for PID {}: {}", pid, e)
-                        })?;
-                    total_memory_kb += rss_kb;
-                }
-            }
-        }
-
-        // Convert the total memory from KB to MB and truncate to 1 decimal place
-        let total_memory_mb = (total_memory_kb as f64 / 1024.0 * 10.0).trunc() / 10.0;
-
-        Ok(total_memory_mb)
+        Ok(pids)
     }
 
+    // sizes in 1K blocks
     pub fn cmd_get_total_used_and_free_disk_space(&self) -> Result<(u64, u64, u64), String> {
         let output = Command::new("sh")
             .arg("-c")
-            .arg("df / --output=size,avail | tail -n1")
+            .arg("df / --output=size,used,avail | tail -n1")
             .output()
             .map_err(|e| format!("Failed to execute command: {}", e))?;
 
@@ -155,7 +128,7 @@ impl CmdHealCheck {
             return Err(format!("Failed to execute command: {}", stderr));
         }
         let stdout = String::from_utf8_lossy(&output.stdout);
-        let parts: Vec<&str> = stdout.trim().split_whitespace().collect();
+        let parts: Vec<&str> = stdout.split_whitespace().collect();
         if parts.len() != 3 {
             return Err(format!("Failed to parse command output: {}", stdout));
         }
@@ -163,8 +136,9 @@ impl CmdHealCheck {
         let total_space =
             u64::from_str(parts[0]).map_err(|e| format!("Failed to parse total space: {}", e))?;
         let used_space =
-            u64::from_str(parts[1]).map_err(|e| format!("Failed to parse free space: {}", e))?;
-        let free_space = total_space - used_space;
+            u64::from_str(parts[1]).map_err(|e| format!("Failed to parse used space: {}", e))?;
+        let free_space =
+            u64::from_str(parts[2]).map_err(|e| format!("Failed to parse free space: {}", e))?;
         Ok((total_space, used_space, free_space))
     }
 
@@ -182,4 +156,19 @@ impl CmdHealCheck {
             Err(e) => Err(format!("Failed to read temperature file: {}", e)),
         }
     }
+
+    pub fn cmd_get_unit_state(&self, unit: &str) -> Result<UnitState, String> {
+        let output = Command::new("systemctl")
+            .arg("show")
+            .arg(unit)
+            .arg("--property=ActiveState,SubState,NRestarts,ExecMainStatus,ExecMainPID,ActiveEnterTimestamp,ExecMainCode,Result,ControlGroup")
+            .output()
+            .map_err(|e| format!("Failed to execute command: {}", e))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("Failed to execute command: {}", stderr));
+        }
+
+        Ok(UnitState::parse(&String::from_utf8_lossy(&output.stdout)))
+    }
 }
@@ -0,0 +1,553 @@
+use log::{error, warn};
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::alert::{Alert, AlertState, AlertWatch};
+use crate::anomaly::AnomalyDetector;
+use crate::cmd_health_check::CmdHealCheck;
+use crate::config::{Config, MemoryMeasure};
+use crate::event::HealthEvent;
+use crate::persist::StateFile;
+use crate::processes::{mb, MemoryUsage};
+use crate::sample::{Metric, Reading, Sample, Value};
+use crate::trend::TrendWatch;
+use crate::unit_state::{unit_name, UnitReader, UnitState, UnitWatch, UNIT_FIELDS};
+
+pub const MEMINFO_COLUMNS: [&str; 5] = [
+    "Total Memory(MB)",
+    "Free Memory(MB)",
+    "Available Memory(MB)",
+    "Buffers Memory(MB)",
+    "Cached Memory(MB)",
+];
+pub const DISK_COLUMNS: [&str; 3] = ["Total Disk(MB)", "Used Disk(MB)", "Free Disk(MB)"];
+pub const TEMPERATURE_COLUMNS: [&str; 1] = ["CPU Temperature(C)"];
+// smaps totals of each service in MB, after the memory columns of every service
+pub const MEMORY_FIELDS: [&str; 4] = ["PSS", "USS", "Swap", "SwapPSS"];
+// a service whose cpu or memory is not read within this is N/A in the sample
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/*
+One scheduled collection: the global part of the metric (meminfo, df, thermal zone) and/or
+the services sharing the same cadence. Services with their own interval get their own job.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub metric: Metric,
+    pub global: bool,
+    pub services: Vec<String>,
+}
+
+pub struct Collection {
+    pub sample: Sample,
+    pub events: Vec<HealthEvent>,
+    // new cadence for the job if it should change
+    pub next_interval: Option<Duration>,
+    // state of the metric's alerts after this sample
+    pub alerts: Vec<Alert>,
+}
+
+impl MemoryMeasure {
+    // key of the service reading holding the measure
+    pub fn key(&self) -> &'static str {
+        match self {
+            MemoryMeasure::Rss => Metric::Memory.name(),
+            MemoryMeasure::Pss => MEMORY_FIELDS[0],
+            MemoryMeasure::Uss => MEMORY_FIELDS[1],
+        }
+    }
+}
+
+/// Column of an smaps total of a service, e.g. `ota PSS(MB)`, next to its RSS in `ota(MB)`.
+pub fn memory_column(service: &str, field: &str) -> String {
+    format!("{} {}({})", service, field, Metric::Memory.unit())
+}
+
+/// Csv columns of `metric` after the timestamp.
+pub fn columns(metric: Metric, services: &[String]) -> Vec<String> {
+    let global: &[&str] = match metric {
+        Metric::Memory => &MEMINFO_COLUMNS,
+        Metric::Disk => &DISK_COLUMNS,
+        Metric::Temperature => &TEMPERATURE_COLUMNS,
+        Metric::Cpu | Metric::Unit => &[],
+    };
+    let mut columns: Vec<String> = global.iter().map(|column| column.to_string()).collect();
+    match metric {
+        Metric::Unit => columns.extend(unit_columns(services)),
+        _ if metric.per_service() => columns.extend(
+            services
+                .iter()
+                .map(|service| format!("{}({})", service, metric.unit())),
+        ),
+        _ => {}
+    }
+    // appended so the columns of files written before them keep their place
+    if metric == Metric::Memory {
+        for service in services {
+            columns.extend(
+                MEMORY_FIELDS
+                    .iter()
+                    .map(|field| memory_column(service, field)),
+            );
+        }
+    }
+    columns
+}
+
+/*
+Runs `read` for every service on its own thread, so a service whose pgrep or ps hangs neither
+delays the others nor holds back the sample for more than the timeout. A read still running
+then is None and its thread is left to finish on its own, until it does the service is skipped
+(None as well), so a hung service holds one thread rather than one more every sample.
+*/
+#[derive(Default)]
+pub struct ServiceReads {
+    // services whose last read has not returned yet
+    running: Arc<Mutex<HashSet<String>>>,
+}
+
+impl ServiceReads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read<T: Send + 'static>(
+        &self,
+        services: &[String],
+        timeout: Duration,
+        read: impl Fn(&str) -> T + Clone + Send + 'static,
+    ) -> Vec<Option<T>> {
+        let receivers: Vec<_> = services
+            .iter()
+            .map(|service| {
+                if !self.running.lock().unwrap().insert(service.clone()) {
+                    return None;
+                }
+                let (sender, receiver) = mpsc::channel();
+                let service = service.clone();
+                let read = read.clone();
+                let running = self.running.clone();
+                thread::spawn(move || {
+                    let value = read(&service);
+                    running.lock().unwrap().remove(&service);
+                    let _ = sender.send(value);
+                });
+                Some(receiver)
+            })
+            .collect();
+        let deadline = Instant::now() + timeout;
+        receivers
+            .into_iter()
+            .map(|receiver| {
+                receiver?
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Unit state columns of `services`, e.g. `ota(ActiveState)`.
+pub fn unit_columns(services: &[String]) -> Vec<String> {
+    services
+        .iter()
+        .flat_map(|service| {
+            UNIT_FIELDS
+                .iter()
+                .map(move |field| format!("{}({})", service, field))
+        })
+        .collect()
+}
+
+/// Readings of one unit state, all N/A when it could not be read.
+pub fn unit_readings(service: &str, state: Option<&UnitState>) -> Vec<Reading> {
+    match state {
+        Some(state) => UNIT_FIELDS
+            .iter()
+            .zip(state.fields())
+            .map(|(field, value)| Reading::service_field(service, field, Value::Text(value)))
+            .collect(),
+        None => UNIT_FIELDS
+            .iter()
+            .map(|field| Reading::service_field(service, field, Value::Missing))
+            .collect(),
+    }
+}
+
+pub struct Collector {
+    config: Arc<Config>,
+    cmd_checker: CmdHealCheck,
+    unit_reader: UnitReader,
+    alert_watch: AlertWatch,
+    trend_watch: TrendWatch,
+    // shared with the D-Bus unit watcher
+    unit_watch: Arc<Mutex<UnitWatch>>,
+    // shared by every collector, saved on shutdown
+    anomaly: Arc<Mutex<AnomalyDetector>>,
+    state_file: Arc<StateFile>,
+    memory_reads: ServiceReads,
+    cpu_reads: ServiceReads,
+}
+
+impl Collector {
+    pub fn new(
+        config: Arc<Config>,
+        unit_watch: Arc<Mutex<UnitWatch>>,
+        anomaly: Arc<Mutex<AnomalyDetector>>,
+        state_file: Arc<StateFile>,
+    ) -> Self {
+        Self {
+            config,
+            cmd_checker: CmdHealCheck::new(),
+            unit_reader: UnitReader::new(),
+            alert_watch: state_file.read(|state| AlertWatch::restore(&state.alerts)),
+            trend_watch: TrendWatch::new(),
+            unit_watch,
+            anomaly,
+            state_file,
+            memory_reads: ServiceReads::new(),
+            cpu_reads: ServiceReads::new(),
+        }
+    }
+
+    /// Groups the collections of `metric` by cadence, one job per distinct interval.
+    pub fn jobs(config: &Config, metric: Metric) -> Vec<(Duration, Job)> {
+        let mut jobs: Vec<(Duration, Job)> = vec![(
+            config.metric_interval(metric),
+            Job {
+                metric,
+                global: !matches!(metric, Metric::Cpu | Metric::Unit),
+                services: Vec::new(),
+            },
+        )];
+
+        if metric.per_service() {
+            for service in config.services.list.iter().flatten() {
+                let interval = config.service_interval(service, metric);
+                match jobs
+                    .iter_mut()
+                    .find(|(job_interval, _)| *job_interval == interval)
+                {
+                    Some((_, job)) => job.services.push(service.clone()),
+                    None => jobs.push((
+                        interval,
+                        Job {
+                            metric,
+                            global: false,
+                            services: vec![service.clone()],
+                        },
+                    )),
+                }
+            }
+        }
+
+        jobs.retain(|(_, job)| job.global || !job.services.is_empty());
+        jobs
+    }
+
+    /// Runs one collection, stamped with the time of the actual sample.
+    pub fn collect(&mut self, job: &Job) -> Collection {
+        let mut collection = Collection {
+            sample: Sample::new(job.metric),
+            events: Vec::new(),
+            next_interval: None,
+            alerts: Vec::new(),
+        };
+        match job.metric {
+            Metric::Memory => self.collect_memory(job, &mut collection.sample),
+            Metric::Cpu => self.collect_cpuload(job, &mut collection.sample),
+            Metric::Disk => self.collect_disk(&mut collection.sample),
+            Metric::Temperature => {
+                collection.next_interval = Some(self.collect_temperature(&mut collection.sample))
+            }
+            Metric::Unit => self.collect_unit_state(job, &mut collection),
+        }
+        let alert_events = self
+            .alert_watch
+            .check(&self.config, &collection.sample, Instant::now());
+        collection.events.extend(alert_events);
+        let trend_events = self.trend_watch.update(&self.config, &collection.sample);
+        collection.events.extend(trend_events);
+        let anomalies =
+            self.anomaly
+                .lock()
+                .unwrap()
+                .check(&self.config, &collection.sample, Instant::now());
+        collection.events.extend(anomalies);
+        collection.alerts = self.alert_watch.alerts(job.metric);
+        self.save_alerts();
+        collection
+    }
+
+    fn collect_memory(&self, job: &Job, sample: &mut Sample) {
+        if job.global {
+            match self.cmd_checker.parse_meminfo() {
+                Ok(meminfo) => {
+                    let values = [
+                        meminfo.total_memory,
+                        meminfo.free_memory,
+                        meminfo.available_memory,
+                        meminfo.buffers_memory,
+                        meminfo.cached_memory,
+                    ];
+                    for (column, value) in MEMINFO_COLUMNS.iter().zip(values) {
+                        sample
+                            .readings
+                            .push(Reading::global(column, Value::Number(value as f64)));
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to retrieve memory information: {}", e);
+                }
+            }
+        }
+
+        let usages = self
+            .memory_reads
+            .read(&job.services, READ_TIMEOUT, |service| {
+                CmdHealCheck::new()
+                    .cmd_find_pids(service)
+                    .and_then(|pids| MemoryUsage::read(&pids))
+            });
+        for (service, usage) in job.services.iter().zip(usages) {
+            let usage = match usage {
+                Some(Ok(usage)) => Some(usage),
+                Some(Err(e)) => {
+                    warn!("Failed to get memory usage for {}: {}", service, e);
+                    None
+                }
+                None => {
+                    warn!(
+                        "Memory usage of {} not read within {}s",
+                        service,
+                        READ_TIMEOUT.as_secs()
+                    );
+                    None
+                }
+            };
+            let rss = match &usage {
+                Some(usage) => mb(usage.rss),
+                None => Value::Missing,
+            };
+            sample
+                .readings
+                .push(Reading::service(service, Metric::Memory, rss));
+
+            let values = match usage.map(|usage| usage.smaps) {
+                Some(Some(smaps)) => [smaps.pss, smaps.uss, smaps.swap, smaps.swap_pss].map(mb),
+                Some(None) if self.config.memory_measure(service) != MemoryMeasure::Rss => {
+                    warn!(
+                        "Failed to read the smaps of {}, memory_metric has no value",
+                        service
+                    );
+                    [(); 4].map(|_| Value::Missing)
+                }
+                _ => [(); 4].map(|_| Value::Missing),
+            };
+            for (field, value) in MEMORY_FIELDS.iter().zip(values) {
+                sample.readings.push(Reading {
+                    column: memory_column(service, field),
+                    ..Reading::service_field(service, field, value)
+                });
+            }
+        }
+    }
+
+    fn collect_cpuload(&self, job: &Job, sample: &mut Sample) {
+        let loads = self.cpu_reads.read(&job.services, READ_TIMEOUT, |service| {
+            CmdHealCheck::new().cmd_check_cpu_load(service, None)
+        });
+        for (service, load) in job.services.iter().zip(loads) {
+            let value = match load {
+                Some(Ok(cpu_load)) => Value::Number(cpu_load as f64),
+                Some(Err(e)) => {
+                    warn!("Failed to get cpu usage for {}: {}", service, e);
+                    Value::Missing
+                }
+                None => {
+                    warn!(
+                        "Cpu usage of {} not read within {}s",
+                        service,
+                        READ_TIMEOUT.as_secs()
+                    );
+                    Value::Missing
+                }
+            };
+            sample
+                .readings
+                .push(Reading::service(service, Metric::Cpu, value));
+        }
+    }
+
+    fn collect_disk(&self, sample: &mut Sample) {
+        match self.cmd_checker.cmd_get_total_used_and_free_disk_space() {
+            Ok((total, used, free)) => {
+                for (column, value_kb) in DISK_COLUMNS.iter().zip([total, used, free]) {
+                    sample.readings.push(Reading::global(
+                        column,
+                        Value::Number((value_kb / 1024) as f64),
+                    ));
+                }
+            }
+            Err(e) => {
+                warn!("Failed to get disk space: {}", e);
+                for column in DISK_COLUMNS {
+                    sample
+                        .readings
+                        .push(Reading::global(column, Value::Missing));
+                }
+            }
+        }
+    }
+
+    // Samples faster while the CPU temperature is at or above its threshold
+    fn collect_temperature(&self, sample: &mut Sample) -> Duration {
+        let mut next_interval = self.config.metric_interval(Metric::Temperature);
+
+        match self.cmd_checker.get_cpu_temperature() {
+            Ok(temperature) => {
+                sample.readings.push(Reading::global(
+                    TEMPERATURE_COLUMNS[0],
+                    Value::Number(temperature),
+                ));
+                let limit = self
+                    .config
+                    .global_thresholds
+                    .as_ref()
+                    .and_then(|thresholds| thresholds.cpu_temperature);
+                if limit.is_some_and(|limit| temperature >= limit) {
+                    next_interval = self.config.temperature_event_interval();
+                }
+            }
+            Err(e) => {
+                warn!("Failed to get cpu temperature: {}", e);
+                sample
+                    .readings
+                    .push(Reading::global(TEMPERATURE_COLUMNS[0], Value::Missing));
+            }
+        }
+
+        next_interval
+    }
+
+    // replaces the saved alerts this collector tracks, those back to ok are dropped
+    fn save_alerts(&mut self) {
+        let Some(alerts) = self.alert_watch.export(Instant::now()) else {
+            return;
+        };
+        let result = self.state_file.update(|state| {
+            state.alerts.retain(|saved| {
+                !alerts
+                    .iter()
+                    .any(|alert| alert.service == saved.service && alert.metric == saved.metric)
+            });
+            state.alerts.extend(
+                alerts
+                    .into_iter()
+                    .filter(|alert| alert.state != AlertState::Ok),
+            );
+        });
+        if let Err(e) = result {
+            error!("Failed to save the alert state: {}", e);
+        }
+    }
+
+    fn collect_unit_state(&mut self, job: &Job, collection: &mut Collection) {
+        for service in &job.services {
+            match self.unit_reader.unit_state(&unit_name(service)) {
+                Ok(state) => {
+                    collection
+                        .sample
+                        .readings
+                        .extend(unit_readings(service, Some(&state)));
+                    let events = self.unit_watch.lock().unwrap().update(
+                        &self.config,
+                        service,
+                        &state,
+                        Instant::now(),
+                    );
+                    collection.events.extend(events);
+                }
+                Err(e) => {
+                    warn!("Failed to get unit state for {}: {}", service, e);
+                    collection
+                        .sample
+                        .readings
+                        .extend(unit_readings(service, None));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collector::{Collector, Job, ServiceReads};
+    use crate::config::{Config, Intervals, ServiceThreshold};
+    use crate::sample::Metric;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_jobs_grouped_by_interval() {
+        let mut config = Config::default();
+        config.monitor.interval = 10;
+        config.services.list = Some(
+            ["ota", "logging", "can-parser"]
+                .map(str::to_string)
+                .to_vec(),
+        );
+        for service in ["ota", "can-parser"] {
+            config.thresholds.insert(
+                service.to_string(),
+                ServiceThreshold {
+                    intervals: Some(Intervals {
+                        memory: Some(30),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            );
+        }
+        let job = |global: bool, services: &[&str]| Job {
+            metric: Metric::Memory,
+            global,
+            services: services.iter().map(|service| service.to_string()).collect(),
+        };
+        assert_eq!(
+            Collector::jobs(&config, Metric::Memory),
+            vec![
+                (Duration::from_secs(10), job(true, &["logging"])),
+                (Duration::from_secs(30), job(false, &["ota", "can-parser"])),
+            ]
+        );
+        // cpu has no device-wide part, the services all share the default interval
+        let jobs = Collector::jobs(&config, Metric::Cpu);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].1.services.len(), 3);
+    }
+
+    #[test]
+    fn test_hung_service_read_is_missing() {
+        let services = ["ota", "hung", "logging"].map(str::to_string);
+        let reads = ServiceReads::new();
+        let read = |service: &str| {
+            if service == "hung" {
+                thread::sleep(Duration::from_secs(5));
+            }
+            service.len()
+        };
+        let start = Instant::now();
+        let values = reads.read(&services, Duration::from_millis(300), read);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(values, [Some(3), None, Some(7)]);
+        // the hung read is not started again while it runs
+        let values = reads.read(&services, Duration::from_millis(300), read);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(values, [Some(3), None, Some(7)]);
+        assert_eq!(reads.running.lock().unwrap().len(), 1);
+    }
+}
@@ -1,36 +1,1156 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::{Table, Value};
 
-#[derive(Clone, Debug, Deserialize)]
+use crate::sample::Metric;
+
+// Drop-in directory looked up next to the main config file
+pub const DROP_IN_DIR: &str = "config.d";
+// SYSTEMD_DOCTOR_<SECTION>__<KEY>, nested keys are separated by a double underscore
+pub const ENV_PREFIX: &str = "SYSTEMD_DOCTOR_";
+// values `config dump` masks, its output ends up in bug reports and terminals
+const SECRET_KEYS: &[&str] = &["http.token", "mqtt.password"];
+const REDACTED: &str = "\"********\"";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Config {
+    pub monitor: MonitorConfig,
     pub services: Services,
-    // pub thresholds: HashMap<String, ServiceThreshold>,
-    // pub global_thresholds: GlobalThresholds,
+    pub thresholds: HashMap<String, ServiceThreshold>,
+    pub global_thresholds: Option<GlobalThresholds>,
+    // run for the events of every service
+    pub hooks: Hooks,
+    // defaults of every alert
+    pub alerts: AlertConfig,
+    pub trend: TrendConfig,
+    pub anomaly: AnomalyConfig,
+    pub store: StoreConfig,
+    pub control: ControlConfig,
+    pub http: HttpConfig,
+    pub mqtt: MqttConfig,
+    pub syslog: SyslogConfig,
+    pub logging: LoggingConfig,
+    pub processes: ProcessConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MonitorConfig {
+    // seconds between two samples
+    pub interval: u64,
+    // directory of the DrViet_*.csv files, the working directory if unset
+    pub output_dir: Option<PathBuf>,
+    // persisted state (baselines, ...), $STATE_DIRECTORY or `output_dir` if unset
+    pub state_dir: Option<PathBuf>,
+    // per-metric cadence, `interval` is used for the ones not set
+    pub intervals: Intervals,
+    // seconds given to collectors and sinks to finish on SIGTERM
+    pub shutdown_timeout: u64,
+    // journal lines of the unit attached to failure and OOM kill events, 0 to disable
+    pub journal_lines: usize,
+    // seconds a hook may run before it is killed
+    pub hook_timeout: u64,
+    // hooks running at the same time, later ones wait for a free slot
+    pub hook_concurrency: usize,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            output_dir: None,
+            state_dir: None,
+            intervals: Intervals::default(),
+            shutdown_timeout: 5,
+            journal_lines: 10,
+            hook_timeout: 10,
+            hook_concurrency: 4,
+        }
+    }
+}
+
+// Sampling intervals in seconds
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Intervals {
+    pub memory: Option<u64>,
+    pub cpu: Option<u64>,
+    pub disk: Option<u64>,
+    pub temperature: Option<u64>,
+    pub unit: Option<u64>,
+    // temperature cadence while cpu_temperature is at or above its threshold
+    pub temperature_event: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Services {
     pub list: Option<Vec<String>>,
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ServiceThreshold {
+    pub cpu: Option<f32>,
+    pub memory: Option<f64>,
+    // what the memory threshold, the leak and the anomaly detection apply to (rss)
+    pub memory_metric: Option<MemoryMeasure>,
+    pub disk: Option<u64>,
+    pub status: Option<String>,
+    // overrides [monitor.intervals] for this service's memory, cpu and unit state
+    pub intervals: Option<Intervals>,
+    // NRestarts increases within flap_window seconds that count as flapping (3 in 600s)
+    pub flap_restarts: Option<u32>,
+    pub flap_window: Option<u64>,
+    // escalation ladder taken when the unit fails
+    pub remediation: Option<Remediation>,
+    // run for this service's events, on top of the global ones
+    pub hooks: Option<Hooks>,
+    // per metric (cpu, memory) settings over the [alerts] defaults
+    #[serde(default)]
+    pub alerts: HashMap<String, AlertConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryMeasure {
+    // resident pages, shared libraries counted in full by every process
+    #[default]
+    Rss,
+    // shared pages split between the processes mapping them
+    Pss,
+    // pages private to the service's processes
+    Uss,
+}
+
+// Memory leak detection over each service's memory history
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrendConfig {
+    // seconds of history the trend is fitted over
+    pub window: u64,
+    // samples needed before a trend is reported
+    pub min_samples: usize,
+    // r² the growth must reach to be called a leak
+    pub confidence: f64,
+}
+
+impl Default for TrendConfig {
+    fn default() -> Self {
+        Self {
+            window: 3600,
+            min_samples: 10,
+            confidence: 0.9,
+        }
+    }
+}
+
+// Deviation from each service's learned cpu and memory baseline
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    pub enabled: bool,
+    // metrics learned, of cpu and memory
+    pub metrics: Vec<String>,
+    // weight of a new sample in the moving mean and variance
+    pub alpha: f64,
+    // standard deviations away from the mean that are anomalous
+    pub k: f64,
+    // samples learned before anything is flagged
+    pub warmup: u64,
+    // floor of the standard deviation, so a flat baseline does not flag noise
+    pub min_deviation: f64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            metrics: vec!["cpu".to_string(), "memory".to_string()],
+            alpha: 0.05,
+            k: 3.0,
+            warmup: 30,
+            min_deviation: 1.0,
+        }
+    }
+}
+
+// Embedded time-series store, see tsdb.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub enabled: bool,
+    // `tsdb` in the output directory if unset
+    pub dir: Option<PathBuf>,
+    // also write samples to the DrViet_<metric>.csv files, unit state always is
+    pub csv: bool,
+    // seconds kept at full resolution, as 1-minute and as 1-hour rollups
+    pub raw_retention: u64,
+    pub minute_retention: u64,
+    pub hour_retention: u64,
+    // size the store is kept under, oldest raw data goes first
+    pub max_size_mb: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            csv: true,
+            raw_retention: 48 * 3600,
+            minute_retention: 7 * 86400,
+            hour_retention: 365 * 86400,
+            max_size_mb: 32,
+        }
+    }
+}
+
+// Unix socket answering `systemd-doctor ctl` and `top`, see control.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    pub socket: PathBuf,
+    // file mode of the socket, e.g. 0o660 to let a group connect
+    pub mode: u32,
+    // peers let in besides root and the doctor's own user
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            socket: PathBuf::from("/run/systemd-doctor/control.sock"),
+            mode: 0o600,
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+        }
+    }
+}
+
+// JSON API over HTTP, see http.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    // address and port, e.g. 0.0.0.0:8080 to be reachable from the network
+    pub listen: String,
+    // required as `Authorization: Bearer <token>` by /api/ when set
+    pub token: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_string(),
+            token: None,
+        }
+    }
+}
+
+// Publishes snapshots, events and an online status to a broker, see mqtt.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub version: MqttVersion,
+    // systemd-doctor-<device> if unset
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // topics are <prefix>/<device>/metrics, /events and /status
+    pub prefix: String,
+    // the hostname if unset
+    pub device: Option<String>,
+    // 0, 1 or 2
+    pub qos: u8,
+    // seconds between two snapshots
+    pub snapshot_interval: u64,
+    pub keep_alive: u64,
+    pub tls: bool,
+    // PEM bundle, /etc/ssl/certs/ca-certificates.crt if unset
+    pub ca_file: Option<PathBuf>,
+    // PEM client certificate and key, for brokers that authenticate devices
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    // messages kept while disconnected, the oldest are dropped first
+    pub queue_size: usize,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            version: MqttVersion::V311,
+            client_id: None,
+            username: None,
+            password: None,
+            prefix: "systemd-doctor".to_string(),
+            device: None,
+            qos: 1,
+            snapshot_interval: 60,
+            keep_alive: 30,
+            tls: false,
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+            queue_size: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum MqttVersion {
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+// Per-process detail of each service in DrViet_processes.csv, see processes.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProcessConfig {
+    pub enabled: bool,
+    // seconds between two tables of every service, 0 to only write them on breaches
+    pub interval: u64,
+    // table of a service as soon as it breaches a threshold, leaks or turns anomalous
+    pub on_breach: bool,
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 300,
+            on_breach: true,
+        }
+    }
+}
+
+// The doctor's own log, see logging.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    // off, error, warn, info, debug or trace
+    pub level: String,
+    // levels of single targets, e.g. "systemd_doctor::collector" = "debug"
+    pub modules: BTreeMap<String, String>,
+    pub output: LogOutput,
+    // <output_dir>/systemd-doctor.log if unset
+    pub file: Option<PathBuf>,
+    // MB written before the file is rotated
+    pub max_size: u64,
+    // rotated files kept next to the current one
+    pub files: u32,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            output: LogOutput::Auto,
+            file: None,
+            max_size: 10,
+            files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    // the journal when started by systemd, the file otherwise
+    Auto,
+    Journal,
+    File,
+    Stderr,
+}
+
+// Forwards events to syslog or the journal, see syslog.rs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SyslogConfig {
+    pub enabled: bool,
+    pub transport: SyslogTransport,
+    // socket path or host:port, the default of the transport if unset
+    pub address: Option<String>,
+    pub facility: SyslogFacility,
+    pub app_name: String,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            transport: SyslogTransport::Unix,
+            address: None,
+            facility: SyslogFacility::Daemon,
+            app_name: "systemd-doctor".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    // RFC 5424 datagrams on /dev/log
+    Unix,
+    Udp,
+    // RFC 5424 with octet counting framing (RFC 6587)
+    Tcp,
+    // native journal entries with DOCTOR_* fields
+    Journal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    pub fn code(&self) -> u8 {
+        match self {
+            SyslogFacility::User => 1,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+// Debounce and hysteresis of threshold alerts
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    // seconds above the threshold before the alert fires (0)
+    #[serde(rename = "for")]
+    pub pending: Option<u64>,
+    // value at or below which a firing alert resolves, the threshold itself if unset
+    pub clear: Option<f64>,
+    // seconds at or below `clear` before the alert resolves (0)
+    pub clear_for: Option<u64>,
+    // minimum seconds between two announcements of the same alert firing (0)
+    pub renotify: Option<u64>,
+}
+
+// Executables run on events, with the event as JSON on stdin and DOCTOR_* variables
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Hooks {
+    pub on_breach: Vec<String>,
+    pub on_recover: Vec<String>,
+    pub on_restart: Vec<String>,
+    // unit failures and OOM kills
+    pub on_unit_failed: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Remediation {
+    // seconds without a failure after which the ladder starts over (3600)
+    pub healthy_period: Option<u64>,
+    pub steps: Vec<RemediationStep>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RemediationStep {
+    pub action: RemediationAction,
+    // times the step is taken within `window` seconds before moving to the next one (1)
+    pub count: Option<u32>,
+    // no window: attempts count until the ladder is reset
+    pub window: Option<u64>,
+    // unit to isolate, rescue.target if unset
+    pub target: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemediationAction {
+    Log,
+    Reload,
+    Restart,
+    // restart the units the service requires, then the service
+    RestartDependencies,
+    Isolate,
+    Reboot,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GlobalThresholds {
+    pub cpu_temperature: Option<f64>,
+    // % of the root filesystem used, else the lowest `disk` of [thresholds]
+    pub disk: Option<u64>,
+    pub board_temperature: Option<f64>,
+    pub network_bandwidth: Option<f64>,
+    // settings of the cpu_temperature and disk alerts over the [alerts] defaults
+    #[serde(default)]
+    pub alerts: HashMap<String, AlertConfig>,
+}
+
+/// Where the effective value of a config key comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+            ConfigSource::Env(name) => write!(f, "env {}", name),
+            ConfigSource::Cli => write!(f, "--set"),
+        }
+    }
+}
+
+impl Config {
+    fn secs(value: Option<u64>, default: u64) -> Duration {
+        Duration::from_secs(value.unwrap_or(default).max(1))
+    }
+
+    pub fn metric_interval(&self, metric: Metric) -> Duration {
+        let intervals = &self.monitor.intervals;
+        let interval = self.monitor.interval;
+        match metric {
+            Metric::Memory => Self::secs(intervals.memory, interval),
+            Metric::Cpu => Self::secs(intervals.cpu, interval),
+            Metric::Disk => Self::secs(intervals.disk, interval),
+            Metric::Temperature => Self::secs(intervals.temperature, interval),
+            Metric::Unit => Self::secs(intervals.unit, interval),
+        }
+    }
+
+    /// Memory measure the thresholds of `service` apply to.
+    pub fn memory_measure(&self, service: &str) -> MemoryMeasure {
+        self.thresholds
+            .get(service)
+            .and_then(|threshold| threshold.memory_metric)
+            .unwrap_or_default()
+    }
+
+    /// Cadence of a per-service metric, falling back to the global one.
+    pub fn service_interval(&self, service: &str, metric: Metric) -> Duration {
+        let value = self
+            .thresholds
+            .get(service)
+            .and_then(|threshold| threshold.intervals.as_ref())
+            .and_then(|intervals| match metric {
+                Metric::Memory => intervals.memory,
+                Metric::Cpu => intervals.cpu,
+                Metric::Unit => intervals.unit,
+                // device-wide, rejected per service on load
+                Metric::Disk | Metric::Temperature => None,
+            });
+        match value {
+            Some(value) => Duration::from_secs(value.max(1)),
+            None => self.metric_interval(metric),
+        }
+    }
+
+    /// How long the doctor may go without a sample, three of the shortest intervals.
+    pub fn stale_after(&self) -> Duration {
+        Metric::ALL
+            .iter()
+            .map(|metric| self.metric_interval(*metric))
+            .min()
+            .unwrap_or_default()
+            * 3
+    }
+
+    pub fn temperature_event_interval(&self) -> Duration {
+        match self.monitor.intervals.temperature_event {
+            Some(value) => Duration::from_secs(value.max(1)),
+            None => self.metric_interval(Metric::Temperature),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ConfigParser {
     pub config: Config,
+    // dotted key -> layer that set it last
+    sources: BTreeMap<String, ConfigSource>,
 }
 
 impl ConfigParser {
-    pub fn new(config_path: &str) -> Self {
-        let config_contents = fs::read_to_string(config_path).expect("Failed to read config file");
-        let config: Config = toml::from_str(&config_contents).expect("Failed to parse config file");
-        ConfigParser { config }
+    #[allow(dead_code)]
+    pub fn new(config_path: &str) -> io::Result<Self> {
+        Self::with_overrides(config_path, &[])
+    }
+
+    // Layers, each one overriding the previous:
+    // config file -> config.d drop-ins (lexical order) -> SYSTEMD_DOCTOR_* env -> --set key=value
+    pub fn with_overrides(config_path: &str, cli_overrides: &[String]) -> io::Result<Self> {
+        Self::load(config_path, env::vars().collect(), cli_overrides)
+    }
+
+    fn load(
+        config_path: &str,
+        env_vars: Vec<(String, String)>,
+        cli_overrides: &[String],
+    ) -> io::Result<Self> {
+        let config_path = Path::new(config_path);
+        let mut sources = BTreeMap::new();
+
+        let mut table = Self::read_table(config_path)?;
+        record_sources(
+            &table,
+            "",
+            &ConfigSource::File(config_path.into()),
+            &mut sources,
+        );
+
+        for drop_in in Self::drop_in_files(config_path)? {
+            let overlay = Self::read_table(&drop_in)?;
+            record_sources(&overlay, "", &ConfigSource::File(drop_in), &mut sources);
+            merge_tables(&mut table, overlay);
+        }
+
+        // every overridden key, checked against the parsed config below
+        let mut overrides = Vec::new();
+        let mut services: Vec<String> = table
+            .get("thresholds")
+            .and_then(Value::as_table)
+            .into_iter()
+            .flat_map(|thresholds| thresholds.keys().cloned())
+            .collect();
+        let mut env_vars: Vec<(String, String)> = env_vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        env_vars.sort();
+        for (name, raw) in env_vars {
+            let path: Vec<String> = name[ENV_PREFIX.len()..]
+                .split("__")
+                .map(str::to_lowercase)
+                .collect();
+            let path = set_override(&mut table, &path, &raw)?;
+            let source = ConfigSource::Env(name);
+            sources.insert(dotted_key(&path), source.clone());
+            overrides.push((path, source));
+        }
+
+        for assignment in cli_overrides {
+            let (key, raw) = assignment.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid --set {}, expected key=value", assignment),
+                )
+            })?;
+            let path: Vec<String> = key.trim().split('.').map(str::to_string).collect();
+            let path = set_override(&mut table, &path, raw.trim())?;
+            sources.insert(dotted_key(&path), ConfigSource::Cli);
+            overrides.push((path, ConfigSource::Cli));
+        }
+
+        let config: Config = Value::Table(table).try_into().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse config file: {}", e),
+            )
+        })?;
+        Self::validate(&config)?;
+        services.extend(config.services.list.iter().flatten().cloned());
+        check_overrides(&config, &services, &overrides)?;
+        Ok(ConfigParser { config, sources })
+    }
+
+    // values serde accepts but the doctor cannot use
+    fn validate(config: &Config) -> io::Result<()> {
+        if config.mqtt.qos > 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid mqtt.qos {}, expected 0, 1 or 2", config.mqtt.qos),
+            ));
+        }
+        // disk and temperature are read for the whole device, once per global interval
+        for (service, threshold) in &config.thresholds {
+            let Some(intervals) = &threshold.intervals else {
+                continue;
+            };
+            let device_wide = [
+                ("disk", intervals.disk),
+                ("temperature", intervals.temperature),
+                ("temperature_event", intervals.temperature_event),
+            ];
+            if let Some((key, _)) = device_wide.iter().find(|(_, value)| value.is_some()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid thresholds.{}.intervals.{}, only memory, cpu and unit are \
+                         sampled per service, set it in [monitor.intervals]",
+                        service, key
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_table(path: &Path) -> io::Result<Table> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read config file {}: {}", path.display(), e),
+            )
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse config file {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Lists `*.toml` files of the drop-in directory, sorted by file name.
+    pub fn drop_in_files(config_path: &Path) -> io::Result<Vec<PathBuf>> {
+        let dir = config_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(DROP_IN_DIR);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+                files.push(path);
+            }
+        }
+        files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        Ok(files)
     }
 
     pub fn get_config(&self) -> &Config {
         &self.config
     }
 
+    #[allow(dead_code)]
     pub fn get_config_services(&self) -> &Option<Vec<String>> {
         &self.config.services.list
     }
+
+    pub fn get_source(&self, key: &str) -> ConfigSource {
+        self.sources
+            .get(key)
+            .cloned()
+            .unwrap_or(ConfigSource::Default)
+    }
+
+    /// Renders the effective configuration as TOML, each value annotated with its source.
+    pub fn dump(&self) -> io::Result<String> {
+        let table = Table::try_from(&self.config).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to serialize config: {}", e),
+            )
+        })?;
+
+        let mut out = String::new();
+        self.dump_table(&table, "", &mut out);
+        Ok(out)
+    }
+
+    fn dump_table(&self, table: &Table, prefix: &str, out: &mut String) {
+        let mut sub_tables = Vec::new();
+        let only_tables = !table.is_empty() && table.values().all(Value::is_table);
+        if !prefix.is_empty() && !only_tables {
+            out.push_str(&format!("[{}]\n", prefix));
+        }
+        for (key, value) in table {
+            let dotted = join_key(prefix, key);
+            match value {
+                Value::Table(sub_table) => sub_tables.push((dotted, sub_table)),
+                value => {
+                    let value = if SECRET_KEYS.contains(&dotted.as_str()) {
+                        REDACTED.to_string()
+                    } else {
+                        value.to_string()
+                    };
+                    out.push_str(&format!(
+                        "{} = {} # {}\n",
+                        quote_key(key),
+                        value,
+                        self.get_source(&dotted)
+                    ));
+                }
+            }
+        }
+        for (dotted, sub_table) in sub_tables {
+            if !out.is_empty() && !out.ends_with("\n\n") {
+                out.push('\n');
+            }
+            self.dump_table(sub_table, &dotted, out);
+        }
+    }
+}
+
+/*
+Override semantics follow systemd drop-ins:
+- tables are merged key by key,
+- scalar values of a later file replace earlier ones,
+- lists are appended to (duplicates skipped), and an empty list resets it.
+*/
+pub fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            (Some(Value::Array(base_list)), Value::Array(overlay_list)) => {
+                if overlay_list.is_empty() {
+                    base_list.clear();
+                }
+                for item in overlay_list {
+                    if !base_list.contains(&item) {
+                        base_list.push(item);
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        quote_key(key)
+    } else {
+        format!("{}.{}", prefix, quote_key(key))
+    }
+}
+
+fn quote_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+fn record_sources(
+    table: &Table,
+    prefix: &str,
+    source: &ConfigSource,
+    sources: &mut BTreeMap<String, ConfigSource>,
+) {
+    for (key, value) in table {
+        let dotted = join_key(prefix, key);
+        match value {
+            Value::Table(sub_table) => record_sources(sub_table, &dotted, source, sources),
+            _ => {
+                sources.insert(dotted, source.clone());
+            }
+        }
+    }
+}
+
+// Values are read as TOML (`5`, `true`, `["ota"]`), anything else is taken as a plain string
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/*
+Sets an env or --set value, as a string where the config takes one (a token of digits, a date
+like 2024-01-01), else as TOML. Returns the path of the key that was set.
+*/
+fn set_override(table: &mut Table, path: &[String], raw: &str) -> io::Result<Vec<String>> {
+    let path = set_path(table, path, Value::String(raw.to_string()))?;
+    if Value::Table(table.clone()).try_into::<Config>().is_err() {
+        set_path(table, &path, parse_value(raw))?;
+    }
+    Ok(path)
+}
+
+/*
+Serde skips keys it does not know, so an override that does not come back out of the parsed
+config is a misspelled key or a stray SYSTEMD_DOCTOR_* variable. Thresholds are only accepted
+for `services`, listed or configured in a file, a `_` of an env name matching none of them
+would otherwise create one.
+*/
+fn check_overrides(
+    config: &Config,
+    services: &[String],
+    overrides: &[(Vec<String>, ConfigSource)],
+) -> io::Result<()> {
+    let known = Table::try_from(config).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to serialize config: {}", e),
+        )
+    })?;
+    for (path, source) in overrides {
+        let mut value = known.get(&path[0]);
+        for segment in &path[1..] {
+            value = value.and_then(|value| value.get(segment));
+        }
+        let unknown_service = path[0] == "thresholds"
+            && path
+                .get(1)
+                .is_some_and(|service| !services.contains(service));
+        if value.is_none() || unknown_service {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown config key {} ({})", dotted_key(path), source),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn dotted_key(path: &[String]) -> String {
+    path.iter()
+        .fold(String::new(), |dotted, key| join_key(&dotted, key))
+}
+
+/*
+Replaces the value at `path`, creating missing tables on the way.
+Env names cannot carry '-', so `mqtt_client` also matches an existing `mqtt-client` key, or a
+listed service of that name. Returns the path of the key that was set.
+*/
+fn set_path(table: &mut Table, path: &[String], value: Value) -> io::Result<Vec<String>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid config key {}", path.join(".")),
+        )
+    };
+    let (last, parents) = path.split_last().ok_or_else(invalid)?;
+    if path.iter().any(|segment| segment.is_empty()) {
+        return Err(invalid());
+    }
+
+    let services: Vec<String> = table
+        .get("services")
+        .and_then(|services| services.get("list"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|service| service.as_str().map(str::to_string))
+        .collect();
+    let mut current = table;
+    let mut resolved = Vec::new();
+    for segment in parents {
+        let key = if resolved == ["thresholds"] {
+            resolve_key(current.keys().chain(&services).collect(), segment)
+        } else {
+            resolve_key(current.keys().collect(), segment)
+        };
+        resolved.push(key.clone());
+        let entry = current
+            .entry(key)
+            .or_insert_with(|| Value::Table(Table::new()));
+        current = entry.as_table_mut().ok_or_else(invalid)?;
+    }
+    let key = resolve_key(current.keys().collect(), last);
+    resolved.push(key.clone());
+    current.insert(key, value);
+    Ok(resolved)
+}
+
+fn resolve_key(existing: Vec<&String>, key: &str) -> String {
+    if existing.iter().any(|existing| *existing == key) {
+        return key.to_string();
+    }
+    existing
+        .into_iter()
+        .find(|existing| existing.replace('-', "_") == key)
+        .cloned()
+        .unwrap_or_else(|| key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ConfigParser, ConfigSource};
+    use std::fs;
+
+    #[test]
+    fn test_drop_ins_merge_in_lexical_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[services]\nlist = [\"logging\"]\n\n[thresholds.logging]\ncpu = 70.0\nmemory = 60.0\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("config.d")).unwrap();
+        fs::write(
+            dir.path().join("config.d/20-ota.toml"),
+            "[services]\nlist = [\"ota\"]\n\n[thresholds.ota]\ncpu = 80.0\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("config.d/10-logging.toml"),
+            "[services]\nlist = [\"logging\"]\n\n[thresholds.logging]\ncpu = 50.0\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("config.d/30-ota.toml"),
+            "[thresholds.ota]\ncpu = 90.0\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("config.d/README"), "not a drop-in").unwrap();
+
+        let parser = ConfigParser::new(config_path.to_str().unwrap()).unwrap();
+        let config = parser.get_config();
+        assert_eq!(
+            parser.get_config_services(),
+            &Some(vec!["logging".to_string(), "ota".to_string()])
+        );
+        assert_eq!(config.thresholds["logging"].cpu, Some(50.0));
+        assert_eq!(config.thresholds["logging"].memory, Some(60.0));
+        assert_eq!(config.thresholds["ota"].cpu, Some(90.0));
+    }
+
+    #[test]
+    fn test_empty_list_resets_services() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[services]\nlist = [\"ota\", \"logging\"]\n").unwrap();
+        fs::create_dir(dir.path().join("config.d")).unwrap();
+        fs::write(
+            dir.path().join("config.d/50-reset.toml"),
+            "[services]\nlist = []\n",
+        )
+        .unwrap();
+
+        let parser = ConfigParser::new(config_path.to_str().unwrap()).unwrap();
+        assert_eq!(parser.get_config_services(), &Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_cli_overrides_and_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[monitor]\ninterval = 10\n\n[thresholds.mqtt-client]\ncpu = 60.0\nmemory = 50.0\n",
+        )
+        .unwrap();
+
+        let overrides = vec![
+            "monitor.output_dir=/tmp/doctor".to_string(),
+            "thresholds.mqtt-client.cpu = 75.5".to_string(),
+            "http.token=s3cret".to_string(),
+        ];
+        let parser =
+            ConfigParser::with_overrides(config_path.to_str().unwrap(), &overrides).unwrap();
+        let config = parser.get_config();
+        assert_eq!(config.monitor.interval, 10);
+        assert_eq!(
+            config.monitor.output_dir.as_deref(),
+            Some(std::path::Path::new("/tmp/doctor"))
+        );
+        assert_eq!(config.thresholds["mqtt-client"].cpu, Some(75.5));
+        assert_eq!(config.thresholds["mqtt-client"].memory, Some(50.0));
+
+        assert_eq!(
+            parser.get_source("thresholds.mqtt-client.cpu"),
+            ConfigSource::Cli
+        );
+        assert_eq!(
+            parser.get_source("thresholds.mqtt-client.memory"),
+            ConfigSource::File(config_path.clone())
+        );
+        let dump = parser.dump().unwrap();
+        assert!(dump.contains("output_dir = \"/tmp/doctor\" # --set"));
+        assert!(dump.contains("[thresholds.mqtt-client]"));
+        assert!(dump.contains("token = \"********\" # --set"));
+        assert!(!dump.contains("s3cret"));
+
+        let invalid = vec!["monitor.interval".to_string()];
+        assert!(ConfigParser::with_overrides(config_path.to_str().unwrap(), &invalid).is_err());
+        let invalid = vec!["mqtt.qos=3".to_string()];
+        assert!(ConfigParser::with_overrides(config_path.to_str().unwrap(), &invalid).is_err());
+        let invalid = vec!["thresholds.mqtt-client.intervals.disk=60".to_string()];
+        assert!(ConfigParser::with_overrides(config_path.to_str().unwrap(), &invalid).is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[services]\nlist = [\"mqtt-client\"]\n").unwrap();
+        let config_path = config_path.to_str().unwrap();
+        let env = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let parser = ConfigParser::load(
+            config_path,
+            env(&[
+                ("SYSTEMD_DOCTOR_MONITOR__INTERVAL", "5"),
+                ("SYSTEMD_DOCTOR_THRESHOLDS__MQTT_CLIENT__CPU", "75"),
+                ("SYSTEMD_DOCTOR_HTTP__TOKEN", "2024-01-01"),
+                ("PATH", "/usr/bin"),
+            ]),
+            &[],
+        )
+        .unwrap();
+        let config = parser.get_config();
+        assert_eq!(config.monitor.interval, 5);
+        assert_eq!(config.thresholds["mqtt-client"].cpu, Some(75.0));
+        // a string field keeps what TOML would read as a date
+        assert_eq!(config.http.token.as_deref(), Some("2024-01-01"));
+        assert_eq!(
+            parser.get_source("thresholds.mqtt-client.cpu"),
+            ConfigSource::Env("SYSTEMD_DOCTOR_THRESHOLDS__MQTT_CLIENT__CPU".to_string())
+        );
+
+        for unknown in [
+            ("SYSTEMD_DOCTOR_MONITOR__INTERVALL", "5"),
+            ("SYSTEMD_DOCTOR_DEBUG", "1"),
+            ("SYSTEMD_DOCTOR_THRESHOLDS__MQTT_CLIENTS__CPU", "75"),
+        ] {
+            assert!(ConfigParser::load(config_path, env(&[unknown]), &[]).is_err());
+        }
+    }
 }
@@ -0,0 +1,355 @@
+use chrono::{DateTime, Local};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::alert::AlertState;
+use crate::config::{Config, ConfigParser, ControlConfig};
+use crate::persist::StateFile;
+use crate::report::parse_age;
+use crate::shutdown::Shutdown;
+use crate::state_store::StateStore;
+
+// a client that neither sends nor reads for this long is dropped
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One line of JSON on the socket, e.g. `{"command": "mute", "args": ["ota", "30m"]}`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/*
+Answers the requests of the control socket from the state store and the state file. Commands
+that act on the daemon go through the shutdown flag: `trigger-check` wakes the collectors up,
+`reload` stops the monitor and sets `reload` so the main loop re-executes the doctor.
+*/
+pub struct Control {
+    pub config: Arc<Config>,
+    // what the doctor was started with, checked before a reload
+    pub config_path: String,
+    pub overrides: Vec<String>,
+    pub state: Arc<StateStore>,
+    pub state_file: Arc<StateFile>,
+    pub shutdown: Shutdown,
+    pub reload: Arc<AtomicBool>,
+    pub started_at: DateTime<Local>,
+}
+
+impl Control {
+    pub fn execute(&self, request: &Request) -> Result<serde_json::Value, String> {
+        let args: Vec<&str> = request.args.iter().map(String::as_str).collect();
+        match (request.command.as_str(), args.as_slice()) {
+            ("status", []) => Ok(self.status()),
+            ("snapshot", []) => Ok(self.state.snapshot().to_json()),
+            ("services", []) => Ok(self.services()),
+            ("mute", [service, duration]) => self.mute(service, duration),
+            ("trigger-check", []) => {
+                self.shutdown.request_check();
+                Ok(json!({ "requested": true }))
+            }
+            ("reload", []) => {
+                ConfigParser::with_overrides(&self.config_path, &self.overrides)
+                    .map_err(|e| format!("Config not reloaded: {}", e))?;
+                self.reload.store(true, Ordering::SeqCst);
+                self.shutdown.trigger();
+                Ok(json!({ "reloading": true }))
+            }
+            ("dump-state", []) => self
+                .state_file
+                .read(|state| serde_json::to_value(state))
+                .map_err(|e| e.to_string()),
+            ("mute", _) => Err("Usage: mute <service> <duration, e.g. 30m, 0 to unmute>".into()),
+            ("status" | "snapshot" | "services" | "trigger-check" | "reload" | "dump-state", _) => {
+                Err(format!("{} takes no arguments", request.command))
+            }
+            (command, _) => Err(format!("Unknown command '{}'", command)),
+        }
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let snapshot = self.state.snapshot();
+        let now = Local::now();
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": std::process::id(),
+            "started_at": self.started_at.to_rfc3339(),
+            "uptime": (now - self.started_at).num_seconds(),
+            "last_sample": snapshot.updated_at.map(|time| time.to_rfc3339()),
+            "services": self.config.services.list.as_ref().map_or(0, Vec::len),
+            "alerts_firing": snapshot
+                .alerts
+                .iter()
+                .filter(|alert| alert.state == AlertState::Firing)
+                .count(),
+            "muted": snapshot.muted.keys().collect::<Vec<_>>(),
+        })
+    }
+
+    // latest readings and alerts of each tracked service
+    fn services(&self) -> serde_json::Value {
+        let snapshot = self.state.snapshot();
+        self.config
+            .services
+            .list
+            .iter()
+            .flatten()
+            .map(|service| snapshot.service_json(service))
+            .collect()
+    }
+
+    fn mute(&self, service: &str, duration: &str) -> Result<serde_json::Value, String> {
+        let mut tracked = self.config.services.list.iter().flatten();
+        if !tracked.any(|tracked| tracked == service) {
+            return Err(format!("{} is not a tracked service", service));
+        }
+        let duration = match duration {
+            "0" => chrono::Duration::zero(),
+            _ => parse_age(duration)
+                .ok_or_else(|| format!("Invalid duration '{}', expected e.g. 30m", duration))?,
+        };
+        let now = Local::now();
+        let until = now + duration;
+        self.state.mute(service, until);
+        // kept across restarts and reloads
+        self.state_file
+            .update(|state| {
+                state.mutes.retain(|_, until| *until > now);
+                state.mutes.remove(service);
+                if until > now {
+                    state.mutes.insert(service.to_string(), until);
+                }
+            })
+            .map_err(|e| format!("Failed to save the mute: {}", e))?;
+        Ok(json!({
+            "service": service,
+            "muted_until": Some(until.to_rfc3339()).filter(|_| duration > chrono::Duration::zero()),
+        }))
+    }
+
+    // one response line per request line until the client hangs up
+    fn serve_client(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let result = serde_json::from_str::<Request>(&line)
+                .map_err(|e| format!("Invalid request: {}", e))
+                .and_then(|request| self.execute(&request));
+            let response = match result {
+                Ok(result) => json!({ "ok": true, "result": result }),
+                Err(error) => json!({ "ok": false, "error": error }),
+            };
+            writeln!(writer, "{}", response)?;
+        }
+        Ok(())
+    }
+}
+
+// uid and gid of the process at the other end of `stream`
+fn peer_credentials(stream: &UnixStream) -> io::Result<(u32, u32)> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: SO_PEERCRED fills a ucred of the given length on a valid socket
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((credentials.uid, credentials.gid))
+}
+
+// root and the doctor's own user, then the configured uids and gids
+fn allowed(config: &ControlConfig, uid: u32, gid: u32) -> bool {
+    // SAFETY: geteuid cannot fail
+    let own = unsafe { libc::geteuid() };
+    uid == 0
+        || uid == own
+        || config.allowed_uids.contains(&uid)
+        || config.allowed_gids.contains(&gid)
+}
+
+/// The socket this process bound, removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/*
+Binds the control socket and answers it on a thread of its own, one more thread per client.
+A socket file left by a previous run is replaced, unless a doctor still answers on it. Like the
+unit watcher, the thread blocks outside of the shutdown handling and is not joined.
+*/
+pub fn serve(control: Control, config: &ControlConfig) -> io::Result<ControlSocket> {
+    let path = &config.socket;
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another doctor", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let socket = ControlSocket { path: path.clone() };
+    fs::set_permissions(path, Permissions::from_mode(config.mode))?;
+
+    let control = Arc::new(control);
+    let config = config.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept a control connection: {}", e);
+                    continue;
+                }
+            };
+            match peer_credentials(&stream) {
+                Ok((uid, gid)) if allowed(&config, uid, gid) => {}
+                Ok((uid, _)) => {
+                    warn!("Refused control connection from uid {}", uid);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to read control peer credentials: {}", e);
+                    continue;
+                }
+            }
+            let control = control.clone();
+            thread::spawn(move || {
+                if let Err(e) = control.serve_client(stream) {
+                    warn!("Control connection failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(socket)
+}
+
+/// Sends one request to the doctor listening on `socket` and returns its result.
+pub fn request(socket: &Path, command: &str, args: &[String]) -> io::Result<serde_json::Value> {
+    let stream = UnixStream::connect(socket).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to connect to {}: {}", socket.display(), e),
+        )
+    })?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    let request = Request {
+        command: command.to_string(),
+        args: args.to_vec(),
+    };
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{}", serde_json::to_string(&request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let mut response: serde_json::Value = serde_json::from_str(&line)?;
+    if response["ok"] == true {
+        Ok(response["result"].take())
+    } else {
+        Err(io::Error::other(
+            response["error"]
+                .as_str()
+                .unwrap_or("no response")
+                .to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ControlConfig};
+    use crate::control::{request, serve, Control};
+    use crate::persist::StateFile;
+    use crate::shutdown::Shutdown;
+    use crate::state_store::StateStore;
+    use chrono::Local;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_mute_through_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.services.list = Some(vec!["ota".to_string()]);
+        let control_config = ControlConfig {
+            socket: dir.path().join("run/control.sock"),
+            ..ControlConfig::default()
+        };
+        let state = Arc::new(StateStore::new());
+        let shutdown = Shutdown::new();
+        let config = Arc::new(config);
+        let control = || Control {
+            config: config.clone(),
+            config_path: "config.toml".to_string(),
+            overrides: Vec::new(),
+            state: state.clone(),
+            state_file: Arc::new(StateFile::default()),
+            shutdown: shutdown.clone(),
+            reload: Arc::new(AtomicBool::new(false)),
+            started_at: Local::now(),
+        };
+        let bound = serve(control(), &control_config).unwrap();
+        let socket = &control_config.socket;
+
+        let args = ["ota".to_string(), "30m".to_string()];
+        let result = request(socket, "mute", &args).unwrap();
+        assert!(result["muted_until"].is_string());
+        assert!(state.is_muted("ota", Local::now()));
+        let status = request(socket, "status", &[]).unwrap();
+        assert_eq!(status["muted"], serde_json::json!(["ota"]));
+
+        request(socket, "mute", &["ota".to_string(), "0".to_string()]).unwrap();
+        assert!(!state.is_muted("ota", Local::now()));
+        let error = request(socket, "mute", &["can".to_string(), "1h".to_string()]).unwrap_err();
+        assert_eq!(error.to_string(), "can is not a tracked service");
+
+        request(socket, "trigger-check", &[]).unwrap();
+        assert_eq!(shutdown.checks(), 1);
+
+        // a second doctor neither takes over nor removes the socket
+        assert!(serve(control(), &control_config).is_err());
+        request(socket, "status", &[]).unwrap();
+        drop(bound);
+        assert!(!socket.exists());
+    }
+}
@@ -0,0 +1,116 @@
+use chrono::{DateTime, Local};
+use serde_json::json;
+use std::fmt;
+
+use crate::sample::Metric;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    // unit not in the `status` configured for it
+    StatusMismatch,
+    // NRestarts rising too fast
+    Flapping,
+    // unit failed, or failed and is waiting to be restarted
+    UnitFailed,
+    // a process of the unit was killed by the kernel OOM killer
+    OomKill,
+    // a step of the remediation ladder was taken
+    Remediation,
+    // unit restarted, by systemd (NRestarts) or by a remediation step
+    Restarted,
+    // a value crossed its threshold
+    Breach,
+    // a value went back below its threshold
+    Recovered,
+    // memory growing steadily, see trend.rs
+    LeakSuspected,
+    // a value far from the service's learned baseline, see anomaly.rs
+    Anomaly,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::StatusMismatch => "status_mismatch",
+            EventKind::Flapping => "flapping",
+            EventKind::UnitFailed => "unit_failed",
+            EventKind::OomKill => "oom_kill",
+            EventKind::Remediation => "remediation",
+            EventKind::Restarted => "restarted",
+            EventKind::Breach => "breach",
+            EventKind::Recovered => "recovered",
+            EventKind::LeakSuspected => "leak_suspected",
+            EventKind::Anomaly => "anomaly",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Something worth reacting to, raised as it is detected rather than on the sampling grid.
+#[derive(Clone, Debug)]
+pub struct HealthEvent {
+    pub time: DateTime<Local>,
+    // None for device-wide events
+    pub service: Option<String>,
+    pub kind: EventKind,
+    pub message: String,
+    // metric, value and threshold of breach and recovery events
+    pub metric: Option<Metric>,
+    pub value: Option<f64>,
+    pub threshold: Option<f64>,
+    // last journal lines of the unit, for failures
+    pub journal: Vec<String>,
+    // the service is muted through the control socket, hooks and remediation skip the event
+    pub muted: bool,
+}
+
+impl HealthEvent {
+    pub fn new(service: Option<&str>, kind: EventKind, message: String) -> Self {
+        Self {
+            time: Local::now(),
+            service: service.map(str::to_string),
+            kind,
+            message,
+            metric: None,
+            value: None,
+            threshold: None,
+            journal: Vec::new(),
+            muted: false,
+        }
+    }
+
+    pub fn with_reading(mut self, metric: Metric, value: f64, threshold: f64) -> Self {
+        self.metric = Some(metric);
+        self.value = Some(value);
+        self.threshold = Some(threshold);
+        self
+    }
+
+    // failures are reported with what the unit logged just before
+    pub fn wants_journal(&self) -> bool {
+        matches!(self.kind, EventKind::UnitFailed | EventKind::OomKill)
+    }
+
+    pub fn timestamp(&self) -> String {
+        self.time.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "time": self.time.to_rfc3339(),
+            "service": self.service,
+            "event": self.kind.name(),
+            "message": self.message,
+            "metric": self.metric.map(|metric| metric.name()),
+            "value": self.value,
+            "threshold": self.threshold,
+            "journal": self.journal,
+            "muted": self.muted,
+        })
+    }
+}
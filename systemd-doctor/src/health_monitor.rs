@@ -1,145 +1,275 @@
-use chrono::Local;
-
-use crate::cmd_health_check::CmdHealCheck;
-use crate::config::ConfigParser;
-use crate::log::LogWriter;
-use std::fmt::format;
-use std::fs::metadata;
+use crate::anomaly::AnomalyDetector;
+use crate::collector::{unit_readings, Collector};
+use crate::config::Config;
+use crate::hooks::HookSink;
+use crate::log::CsvSink;
+use crate::mqtt::MqttSink;
+use crate::oom::spawn_oom_watcher;
+use crate::persist::{state_dir, StateFile, STATE_FILE};
+use crate::processes::ProcessSink;
+use crate::remediation::RemediationSink;
+use crate::sample::{Metric, Sample};
+use crate::scheduler::Scheduler;
+use crate::shutdown::Shutdown;
+use crate::sink::{spawn_sink, Publisher};
+use crate::state_store::StateStore;
+use crate::syslog::SyslogSink;
+use crate::systemd_dbus::SystemdClient;
+use crate::tsdb::StoreSink;
+use crate::unit_state::{unit_name, UnitWatch};
+use log::{error, info, warn};
 use std::io;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+/*
+Each metric is collected on its own thread, so a slow `pgrep` only delays its own metric.
+Collectors publish their samples to the state store (latest values, readable at any time)
+and to the sinks, which consume them from a channel on their own threads.
+*/
 pub struct HealthMonitor {
-    services: Option<Vec<String>>,
-    check_interval: Duration,
-    memory_log: LogWriter,
-    cpu_log: LogWriter,
-    cmd_checker: CmdHealCheck,
+    config: Arc<Config>,
+    state: Arc<StateStore>,
+    // dropped on shutdown so the sinks see their channels close
+    publisher: Option<Publisher>,
+    shutdown: Shutdown,
+    unit_watch: Arc<Mutex<UnitWatch>>,
+    anomaly: Arc<Mutex<AnomalyDetector>>,
+    // what survives a restart, see persist.rs
+    state_file: Arc<StateFile>,
+    collectors: Vec<JoinHandle<()>>,
+    sinks: Vec<JoinHandle<()>>,
 }
 
 impl HealthMonitor {
-    pub fn new(
-        config_path: &str,
-        check_interval: Duration,
-        _log_file: Option<&str>,
-    ) -> Result<Self, io::Error> {
-        let (memory_log, cpu_log) = LogWriter::new()?;
-        let cmd_checker = CmdHealCheck::new();
-        let config = ConfigParser::new(config_path);
-        let tracking_services = config.get_config_services().clone();
+    pub fn new(config: Config, shutdown: Shutdown) -> Result<Self, io::Error> {
+        let config = Arc::new(config);
+        let state = Arc::new(StateStore::new());
+        let state_file = Arc::new(StateFile::load(state_dir(&config).join(STATE_FILE)));
+        for (service, until) in state_file.read(|state| state.mutes.clone()) {
+            state.mute(&service, until);
+        }
+
+        let csv_sink = CsvSink::new(&config)?;
+        let (csv_sender, csv_handle) = spawn_sink(Box::new(csv_sink));
+        // remediation reports its actions through the publisher it is fed by
+        let outbox = Arc::new(OnceLock::new());
+        let remediation_sink =
+            RemediationSink::new(config.clone(), outbox.clone(), state_file.clone());
+        let (remediation_sender, remediation_handle) = spawn_sink(Box::new(remediation_sink));
+        let (hook_sender, hook_handle) = spawn_sink(Box::new(HookSink::new(config.clone())));
+        let mut senders = vec![csv_sender, remediation_sender, hook_sender];
+        let mut sinks = vec![csv_handle, remediation_handle, hook_handle];
+        if config.store.enabled {
+            let (store_sender, store_handle) = spawn_sink(Box::new(StoreSink::new(&config)?));
+            senders.push(store_sender);
+            sinks.push(store_handle);
+        }
+        if config.mqtt.enabled {
+            let (mqtt_sender, mqtt_handle) =
+                spawn_sink(Box::new(MqttSink::new(&config, state.clone())?));
+            senders.push(mqtt_sender);
+            sinks.push(mqtt_handle);
+        }
+        if config.syslog.enabled {
+            let (syslog_sender, syslog_handle) = spawn_sink(Box::new(SyslogSink::new(&config)));
+            senders.push(syslog_sender);
+            sinks.push(syslog_handle);
+        }
+        if config.processes.enabled {
+            let (process_sender, process_handle) = spawn_sink(Box::new(ProcessSink::new(&config)?));
+            senders.push(process_sender);
+            sinks.push(process_handle);
+        }
+        let publisher = Publisher::with_journal(
+            state.clone(),
+            senders,
+            config.monitor.journal_lines,
+            state_file.clone(),
+        );
+        let _ = outbox.set(publisher.downgrade());
+        let anomaly = AnomalyDetector::new(state_file.clone());
+
         Ok(Self {
-            services: tracking_services,
-            check_interval,
-            memory_log,
-            cpu_log,
-            cmd_checker,
+            config,
+            state,
+            publisher: Some(publisher),
+            shutdown,
+            unit_watch: Arc::new(Mutex::new(UnitWatch::new())),
+            anomaly: Arc::new(Mutex::new(anomaly)),
+            state_file,
+            collectors: Vec::new(),
+            sinks,
         })
     }
 
-    pub fn start_tracking(monitor: Arc<Mutex<Self>>, interval: Duration) {
-        thread::spawn(move || {
-            loop {
-                let mut monitor = monitor.lock().unwrap(); // Lock the mutex to get mutable access
-                if let Err(e) = monitor.start_monitor_memory() {
-                    eprintln!("Failed to monitor memory: {}", e);
-                }
-                if let Err(e) = monitor.start_monitor_cpuload() {
-                    eprintln!("Failed to monitor CPU: {}", e);
-                }
-                thread::sleep(interval);
-            }
-        });
+    pub fn state(&self) -> Arc<StateStore> {
+        self.state.clone()
     }
 
-    pub fn start_monitor_memory(&mut self) -> Result<(), io::Error> {
-        if metadata(self.memory_log.get_log_file_path())?.len() == 0 {
-            let mut header = vec![
-                "Timestamp".to_string(),
-                "Total Memory(MB)".to_string(),
-                "Free Memory(MB)".to_string(),
-                "Available Memory(MB)".to_string(),
-                "Buffers Memory(MB)".to_string(),
-                "Cached Memory(MB)".to_string(),
-            ];
-
-            // Extend header with service names
-            if let Some(services) = &self.services {
-                for service in services {
-                    header.push(format!("{}(MB)", service));
-                }
-            }
+    pub fn state_file(&self) -> Arc<StateFile> {
+        self.state_file.clone()
+    }
 
-            let header_refs: Vec<&str> = header.iter().map(String::as_str).collect();
-            self.memory_log.write_record(&header_refs)?;
-        }
+    pub fn start_tracking(&mut self) {
+        let Some(publisher) = &self.publisher else {
+            return;
+        };
+        for metric in Metric::ALL {
+            let jobs = Collector::jobs(&self.config, metric);
+            if jobs.is_empty() {
+                continue;
+            }
 
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        match self.cmd_checker.parse_meminfo() {
-            Ok(meminfo) => {
-                let mut record = vec![
-                    timestamp,
-                    meminfo.total_memory.to_string(),
-                    meminfo.free_memory.to_string(),
-                    meminfo.available_memory.to_string(),
-                    meminfo.buffers_memory.to_string(),
-                    meminfo.cached_memory.to_string(),
-                ];
-
-                if let Some(services) = &self.services {
-                    for service in services {
-                        match self.cmd_checker.cmd_check_memory_usage_mb(service, None) {
-                            Ok(memory_usage) => record.push(memory_usage.to_string()),
-                            Err(e) => {
-                                eprintln!("Failed to get memory usage for {}: {}", service, e);
-                                record.push("N/A".to_string()); // Or use a default value
-                            }
+            let mut collector = Collector::new(
+                self.config.clone(),
+                self.unit_watch.clone(),
+                self.anomaly.clone(),
+                self.state_file.clone(),
+            );
+            let publisher = publisher.clone();
+            let shutdown = self.shutdown.clone();
+            self.collectors.push(thread::spawn(move || {
+                let mut scheduler = Scheduler::new();
+                for (interval, job) in jobs {
+                    scheduler.add(job, interval);
+                }
+                let mut checks = shutdown.checks();
+                // a job already started is finished and published before leaving
+                loop {
+                    let seen = checks;
+                    if shutdown.wait_timeout_or_check(scheduler.time_until_due(), &mut checks) {
+                        break;
+                    }
+                    let now = Instant::now();
+                    if checks != seen {
+                        scheduler.make_due(now);
+                    }
+                    for job in scheduler.due(now) {
+                        let collection = collector.collect(&job);
+                        publisher.publish(collection.sample);
+                        publisher.publish_alerts(metric, collection.alerts);
+                        for event in collection.events {
+                            publisher.publish_event(event);
+                        }
+                        if let Some(interval) = collection.next_interval {
+                            scheduler.set_interval(&job, interval);
                         }
                     }
                 }
-                let record_refs: Vec<&str> = record.iter().map(String::as_str).collect();
-                self.memory_log.write_record(&record_refs)?;
-            }
-            Err(e) => {
-                eprintln!("Failed to retrieve memory information: {}", e);
+            }));
+        }
+        if self.config.services.list.is_some() {
+            match spawn_oom_watcher(
+                self.config.clone(),
+                publisher.clone(),
+                self.shutdown.clone(),
+                self.unit_watch.clone(),
+            ) {
+                Ok(handle) => self.collectors.push(handle),
+                Err(e) => warn!("Failed to watch for OOM kills: {}", e),
             }
         }
-
-        Ok(())
+        self.start_unit_watcher();
     }
 
-    pub fn start_monitor_cpuload(&mut self) -> Result<(), io::Error> {
-        if metadata(self.cpu_log.get_log_file_path())?.len() == 0 {
-            let mut header = vec!["Timestamp".to_string()];
+    /*
+    Unit state is also polled, this reacts to changes as systemd signals them. The thread
+    blocks on D-Bus, so it is not joined on shutdown and only holds a weak publisher.
+    */
+    fn start_unit_watcher(&self) {
+        let (Some(publisher), Some(services)) = (&self.publisher, &self.config.services.list)
+        else {
+            return;
+        };
+        let publisher = publisher.downgrade();
+        let services = services.clone();
+        let config = self.config.clone();
+        let unit_watch = self.unit_watch.clone();
 
-            if let Some(services) = &self.services {
-                for service in services {
-                    header.push(format!("{}(%)", service));
+        thread::spawn(move || {
+            let units: Vec<String> = services.iter().map(|service| unit_name(service)).collect();
+            let client = match SystemdClient::system() {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("D-Bus unavailable, unit state is only polled: {}", e);
+                    return;
+                }
+            };
+            for unit in &units {
+                match client.load_state(unit) {
+                    Ok(state) if state == "loaded" => {}
+                    Ok(state) => info!("Tracked unit {} is {}", unit, state),
+                    Err(e) => warn!("Failed to load unit {}: {}", unit, e),
                 }
             }
+            let changes = match client.watch_units(&units) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    warn!("Failed to subscribe to unit changes: {}", e);
+                    return;
+                }
+            };
 
-            let header_refs: Vec<&str> = header.iter().map(String::as_str).collect();
-            self.cpu_log.write_record(&header_refs)?;
-        }
-
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        let mut record = vec![timestamp];
-        if let Some(services) = &self.services {
-            for service in services {
-                match self.cmd_checker.cmd_check_cpu_load(service, None) {
-                    Ok(cpu_load) => record.push(cpu_load.to_string()),
-                    Err(e) => {
-                        eprintln!("Failed to get cpu usage for {}: {}", service, e);
-                        record.push("N/A".to_string()); // Or use a default value
+            for unit in changes {
+                let Some(publisher) = publisher.upgrade() else {
+                    break;
+                };
+                let Some(index) = units.iter().position(|name| *name == unit) else {
+                    continue;
+                };
+                let service = &services[index];
+                match client.unit_state(&unit) {
+                    Ok(state) => {
+                        let mut sample = Sample::new(Metric::Unit);
+                        sample.readings = unit_readings(service, Some(&state));
+                        let events = unit_watch.lock().unwrap().update(
+                            &config,
+                            service,
+                            &state,
+                            Instant::now(),
+                        );
+                        publisher.publish(sample);
+                        for event in events {
+                            publisher.publish_event(event);
+                        }
                     }
+                    Err(e) => warn!("Failed to get unit state for {}: {}", service, e),
                 }
             }
+        });
+    }
 
-            let record_refs: Vec<&str> = record.iter().map(String::as_str).collect();
-            self.cpu_log.write_record(&record_refs)?;
-        }
+    /*
+    Stops the collectors once their in-flight sample is published, then lets every sink drain
+    its channel and flush. Returns false if that did not complete within `timeout`.
+    */
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        self.shutdown.trigger();
+        let collectors = mem::take(&mut self.collectors);
+        let sinks = mem::take(&mut self.sinks);
+        // the collectors hold the last publishers, sinks stop once those are gone
+        self.publisher = None;
+
+        let anomaly = self.anomaly.clone();
 
-        Ok(())
+        let (done_sender, done_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for handle in collectors {
+                let _ = handle.join();
+            }
+            // baselines are complete once the last sample is in
+            if let Err(e) = anomaly.lock().unwrap().save() {
+                error!("Failed to save baselines: {}", e);
+            }
+            for handle in sinks {
+                let _ = handle.join();
+            }
+            let _ = done_sender.send(());
+        });
+        done_receiver.recv_timeout(timeout).is_ok()
     }
 
     // pub fn enable_journal_service_log(&self, service_name: &str) -> Result<(), String> {
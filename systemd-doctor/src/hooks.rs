@@ -0,0 +1,291 @@
+use log::{error, info, warn};
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, Hooks};
+use crate::event::{EventKind, HealthEvent};
+use crate::sample::Message;
+use crate::sink::Sink;
+
+// how often a running hook is checked for exit
+const WAIT_STEP: Duration = Duration::from_millis(50);
+// time left to the output readers once the process group is killed
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+fn hook_list(hooks: &Hooks, kind: EventKind) -> &[String] {
+    match kind {
+        EventKind::Breach | EventKind::Anomaly => &hooks.on_breach,
+        EventKind::Recovered => &hooks.on_recover,
+        EventKind::Restarted => &hooks.on_restart,
+        EventKind::UnitFailed | EventKind::OomKill => &hooks.on_unit_failed,
+        _ => &[],
+    }
+}
+
+/// DOCTOR_* variables describing `event`, the fields that are not set are left out.
+pub fn event_env(event: &HealthEvent) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("DOCTOR_EVENT", event.kind.name().to_string()),
+        ("DOCTOR_TIME", event.time.to_rfc3339()),
+        ("DOCTOR_MESSAGE", event.message.clone()),
+    ];
+    if let Some(service) = &event.service {
+        env.push(("DOCTOR_SERVICE", service.clone()));
+    }
+    if let Some(metric) = event.metric {
+        env.push(("DOCTOR_METRIC", metric.name().to_string()));
+    }
+    if let Some(value) = event.value {
+        env.push(("DOCTOR_VALUE", value.to_string()));
+    }
+    if let Some(threshold) = event.threshold {
+        env.push(("DOCTOR_THRESHOLD", threshold.to_string()));
+    }
+    env
+}
+
+pub struct HookOutput {
+    // None when the hook was killed after the timeout
+    pub status: Option<ExitStatus>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output);
+        }
+        let _ = sender.send(output);
+    });
+    receiver
+}
+
+// the hook's process group, its id is the hook's pid
+fn kill_group(hook: &str, pgid: u32) {
+    // SAFETY: kill only takes plain integers, a negative pid addresses the process group
+    let result = unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        // ESRCH: every process of the group already exited
+        if error.raw_os_error() != Some(libc::ESRCH) {
+            warn!("Failed to kill the processes of hook {}: {}", hook, error);
+        }
+    }
+}
+
+/// Runs `hook` with the event as JSON on stdin, killing it after `timeout`.
+pub fn run_hook(hook: &str, event: &HealthEvent, timeout: Duration) -> io::Result<HookOutput> {
+    let mut child = Command::new(hook)
+        .envs(event_env(event))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // own process group, so a timeout also kills what the hook started
+        .process_group(0)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // a hook that does not read its input closes the pipe early
+        let _ = writeln!(stdin, "{}", event.to_json());
+    }
+    // read while the hook runs so it never blocks on a full pipe
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            kill_group(hook, child.id());
+            let _ = child.kill();
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(WAIT_STEP);
+    };
+
+    /*
+    A process the hook left in the background keeps the pipes open after the hook exited, the
+    output is only waited for until the same deadline. Killing the group then closes them, unless
+    the process left the group too: its reader is abandoned and ends whenever the pipe closes.
+    */
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    let mut outputs = [stdout, stderr].map(|pipe| (pipe.recv_timeout(remaining()).ok(), pipe));
+    if outputs.iter().any(|(output, _)| output.is_none()) {
+        if status.is_some() {
+            warn!(
+                "Hook {} left processes holding its output, killing them",
+                hook
+            );
+        }
+        kill_group(hook, child.id());
+        for (output, pipe) in &mut outputs {
+            if output.is_none() {
+                *output = pipe.recv_timeout(KILL_GRACE).ok();
+            }
+        }
+    }
+    let [(stdout, _), (stderr, _)] = outputs;
+    Ok(HookOutput {
+        status,
+        stdout: stdout.unwrap_or_default(),
+        stderr: stderr.unwrap_or_default(),
+    })
+}
+
+fn log_hook(hook: &str, event: &HealthEvent, result: io::Result<HookOutput>) {
+    let output = match result {
+        Ok(output) => output,
+        Err(e) => {
+            error!("Failed to run hook {} on {}: {}", hook, event.kind, e);
+            return;
+        }
+    };
+    for line in output.stdout.lines() {
+        info!("[hook {}] {}", hook, line);
+    }
+    for line in output.stderr.lines() {
+        info!("[hook {}] {}", hook, line);
+    }
+    match output.status {
+        Some(status) if status.success() => {}
+        Some(status) => warn!("Hook {} on {} failed: {}", hook, event.kind, status),
+        None => warn!("Hook {} on {} timed out and was killed", hook, event.kind),
+    }
+}
+
+/*
+Runs the hooks configured for an event, the global ones and those of the event's service.
+Each hook runs on its own thread, at most `hook_concurrency` at a time: when all slots are
+taken the sink waits, so later events queue up in its channel.
+*/
+pub struct HookSink {
+    config: Arc<Config>,
+    // number of hooks running
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl HookSink {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    fn hooks_for(&self, event: &HealthEvent) -> Vec<String> {
+        let mut hooks = hook_list(&self.config.hooks, event.kind).to_vec();
+        if let Some(service_hooks) = event
+            .service
+            .as_ref()
+            .and_then(|service| self.config.thresholds.get(service))
+            .and_then(|threshold| threshold.hooks.as_ref())
+        {
+            hooks.extend_from_slice(hook_list(service_hooks, event.kind));
+        }
+        hooks
+    }
+
+    fn spawn(&self, hook: String, event: HealthEvent) {
+        let limit = self.config.monitor.hook_concurrency.max(1);
+        let (running, condvar) = &*self.running;
+        let mut count = condvar
+            .wait_while(running.lock().unwrap(), |count| *count >= limit)
+            .unwrap();
+        *count += 1;
+        drop(count);
+
+        let timeout = Duration::from_secs(self.config.monitor.hook_timeout.max(1));
+        let slots = self.running.clone();
+        thread::spawn(move || {
+            log_hook(&hook, &event, run_hook(&hook, &event, timeout));
+            let (running, condvar) = &*slots;
+            *running.lock().unwrap() -= 1;
+            condvar.notify_all();
+        });
+    }
+}
+
+impl Sink for HookSink {
+    fn name(&self) -> &str {
+        "hooks"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        if let Message::Event(event) = message {
+            if event.muted {
+                return Ok(());
+            }
+            for hook in self.hooks_for(event) {
+                self.spawn(hook, event.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // hooks still running are waited for as long as their timeout lets them run
+    fn flush(&mut self) -> io::Result<()> {
+        let timeout = Duration::from_secs(self.config.monitor.hook_timeout.max(1)) + KILL_GRACE;
+        let (running, condvar) = &*self.running;
+        let (count, _) = condvar
+            .wait_timeout_while(running.lock().unwrap(), timeout, |count| *count > 0)
+            .unwrap();
+        if *count > 0 {
+            warn!("{} hooks still running, not waiting for them", *count);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::{EventKind, HealthEvent};
+    use crate::hooks::run_hook;
+    use crate::sample::Metric;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_run_hook_with_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("hook.sh");
+        fs::write(
+            &hook,
+            "#!/bin/sh\nread -r event\necho \"$DOCTOR_SERVICE $DOCTOR_EVENT $DOCTOR_VALUE\"\necho \"$event\" >&2\n",
+        )
+        .unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let event = HealthEvent::new(Some("ota"), EventKind::Breach, "cpu high".to_string())
+            .with_reading(Metric::Cpu, 92.5, 80.0);
+
+        let output = run_hook(hook.to_str().unwrap(), &event, Duration::from_secs(5)).unwrap();
+        assert!(output.status.unwrap().success());
+        assert_eq!(output.stdout, "ota breach 92.5\n");
+        let json: serde_json::Value = serde_json::from_str(&output.stderr).unwrap();
+        assert_eq!(json["event"], "breach");
+        assert_eq!(json["threshold"], 80.0);
+
+        fs::write(&hook, "#!/bin/sh\nsleep 5\n").unwrap();
+        let output = run_hook(hook.to_str().unwrap(), &event, Duration::from_millis(200)).unwrap();
+        assert!(output.status.is_none());
+
+        // a background process holding stdout does not keep the hook running
+        fs::write(&hook, "#!/bin/sh\necho started\nsleep 30 &\n").unwrap();
+        let start = Instant::now();
+        let output = run_hook(hook.to_str().unwrap(), &event, Duration::from_millis(500)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(output.status.unwrap().success());
+        assert_eq!(output.stdout, "started\n");
+    }
+}
@@ -0,0 +1,385 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local};
+use std::fmt::Write;
+
+use crate::collector::MEMINFO_COLUMNS;
+use crate::report::{gap_limit, Columns, EventRecord, History, Report};
+use crate::sample::Metric;
+use crate::tsdb::Point;
+
+const WIDTH: f64 = 960.0;
+const HEIGHT: f64 = 280.0;
+const LEFT: f64 = 64.0;
+const RIGHT: f64 = 16.0;
+const TOP: f64 = 16.0;
+const BOTTOM: f64 = 36.0;
+// points drawn per series, longer series are averaged down to this
+const MAX_POINTS: usize = 800;
+const PALETTE: [&str; 10] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+    "#bcbd22", "#17becf",
+];
+// hue step for series past the palette, the golden angle keeps neighbours apart
+const HUE_STEP: f64 = 137.5;
+// events drawn as markers on every chart, with their colour
+const MARKERS: [(&str, &str); 2] = [("breach", "#d62728"), ("restarted", "#1f77b4")];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;font-size:14px}\
+th,td{border:1px solid #ccc;padding:3px 8px;text-align:right}\
+th:first-child,td:first-child{text-align:left}\
+.legend span{display:inline-block;margin-right:1.2em;font-size:13px}\
+.legend i{display:inline-block;width:12px;height:12px;margin-right:4px;vertical-align:middle}\
+svg{background:#fafafa;border:1px solid #ddd}\
+svg text{font-size:11px;fill:#555}";
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// averages runs of points so a series has at most `max` of them
+fn thin(points: &[Point], max: usize) -> Vec<Point> {
+    if points.len() <= max {
+        return points.to_vec();
+    }
+    points
+        .chunks(points.len().div_ceil(max))
+        .map(|chunk| {
+            let count: u32 = chunk.iter().map(|point| point.count).sum();
+            Point {
+                time: chunk[0].time,
+                min: chunk
+                    .iter()
+                    .map(|point| point.min)
+                    .fold(f64::INFINITY, f64::min),
+                avg: chunk
+                    .iter()
+                    .map(|point| point.avg * f64::from(point.count))
+                    .sum::<f64>()
+                    / f64::from(count.max(1)),
+                max: chunk
+                    .iter()
+                    .map(|point| point.max)
+                    .fold(f64::NEG_INFINITY, f64::max),
+                count,
+            }
+        })
+        .collect()
+}
+
+// 1, 2 or 5 times a power of ten, at least `raw`
+fn nice_step(raw: f64) -> f64 {
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .into_iter()
+        .map(|factor| factor * magnitude)
+        .find(|step| *step >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+/// Time span shown on every chart, from the first to the last sample or event.
+fn span(history: &History) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    let times = history
+        .metrics
+        .values()
+        .flat_map(|columns| columns.values())
+        .flat_map(|points| points.first().into_iter().chain(points.last()))
+        .map(|point| point.time)
+        .chain(history.events.iter().map(|event| event.time));
+    let (start, end) = times.fold((None, None), |(start, end), time| {
+        (
+            Some(start.map_or(time, |start: DateTime<Local>| start.min(time))),
+            Some(end.map_or(time, |end: DateTime<Local>| end.max(time))),
+        )
+    });
+    let (start, end) = (start?, end?);
+    // a single instant still gets a visible axis
+    Some((start, end.max(start + ChronoDuration::minutes(1))))
+}
+
+fn chart(
+    series: &Columns,
+    events: &[EventRecord],
+    (start, end): (DateTime<Local>, DateTime<Local>),
+) -> String {
+    let plot_width = WIDTH - LEFT - RIGHT;
+    let plot_height = HEIGHT - TOP - BOTTOM;
+    let seconds = (end - start).num_seconds().max(1) as f64;
+    let x =
+        |time: DateTime<Local>| LEFT + (time - start).num_seconds() as f64 / seconds * plot_width;
+
+    let top = series
+        .values()
+        .flatten()
+        .map(|point| point.max)
+        .fold(0.0, f64::max);
+    let step = nice_step((if top > 0.0 { top } else { 1.0 }) / 4.0);
+    let y_max = (top / step).ceil().max(1.0) * step;
+    let y = |value: f64| TOP + plot_height - value / y_max * plot_height;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        WIDTH, HEIGHT, WIDTH, HEIGHT
+    );
+    // horizontal grid with the value axis
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    for tick in 0..=(y_max / step).round() as usize {
+        let value = tick as f64 * step;
+        let _ = writeln!(
+            svg,
+            "<line x1=\"{LEFT}\" x2=\"{:.1}\" y1=\"{:.1}\" y2=\"{:.1}\" stroke=\"#e0e0e0\"/>\
+             <text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{:.*}</text>",
+            WIDTH - RIGHT,
+            y(value),
+            y(value),
+            LEFT - 6.0,
+            y(value) + 4.0,
+            decimals,
+            value
+        );
+    }
+    // time axis
+    let format = if end - start > ChronoDuration::days(1) {
+        "%m-%d %H:%M"
+    } else {
+        "%H:%M"
+    };
+    for tick in 0..=5 {
+        let time = start + ChronoDuration::seconds((seconds * tick as f64 / 5.0) as i64);
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>",
+            x(time),
+            HEIGHT - BOTTOM + 16.0,
+            time.format(format)
+        );
+    }
+
+    for (index, points) in series.values().enumerate() {
+        let color = color(index);
+        let points = thin(points, MAX_POINTS);
+        // gaps are left blank rather than bridged
+        let limit = gap_limit(&points);
+        let mut line: Vec<String> = Vec::new();
+        let mut previous: Option<DateTime<Local>> = None;
+        for point in &points {
+            if previous
+                .zip(limit)
+                .is_some_and(|(previous, limit)| (point.time - previous).num_seconds() > limit)
+            {
+                let _ = writeln!(svg, "{}", polyline(&line, &color));
+                line.clear();
+            }
+            line.push(format!("{:.1},{:.1}", x(point.time), y(point.avg)));
+            previous = Some(point.time);
+        }
+        let _ = writeln!(svg, "{}", polyline(&line, &color));
+    }
+
+    for event in events {
+        let Some((_, color)) = MARKERS.iter().find(|(kind, _)| *kind == event.kind) else {
+            continue;
+        };
+        let _ = writeln!(
+            svg,
+            "<line x1=\"{0:.1}\" x2=\"{0:.1}\" y1=\"{TOP}\" y2=\"{1:.1}\" stroke=\"{2}\" \
+             stroke-dasharray=\"4 3\"><title>{3} {4} {5}: {6}</title></line>",
+            x(event.time),
+            TOP + plot_height,
+            color,
+            event.time.format("%Y-%m-%d %H:%M:%S"),
+            escape(&event.service),
+            escape(&event.kind),
+            escape(&event.message)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn color(index: usize) -> String {
+    match PALETTE.get(index) {
+        Some(color) => color.to_string(),
+        None => format!("hsl({:.0},60%,40%)", (index as f64 * HUE_STEP) % 360.0),
+    }
+}
+
+fn polyline(points: &[String], color: &str) -> String {
+    match points.len() {
+        0 => String::new(),
+        // a lone point would be invisible as a line
+        1 => {
+            let (x, y) = points[0].split_once(',').unwrap_or_default();
+            format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"2\" fill=\"{}\"/>",
+                x, y, color
+            )
+        }
+        _ => format!(
+            "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>",
+            color,
+            points.join(" ")
+        ),
+    }
+}
+
+fn legend(series: &Columns) -> String {
+    let mut html = String::from("<p class=\"legend\">");
+    for (index, name) in series.keys().enumerate() {
+        let _ = write!(
+            html,
+            "<span><i style=\"background:{}\"></i>{}</span>",
+            color(index),
+            escape(name)
+        );
+    }
+    for (kind, color) in MARKERS {
+        let _ = write!(
+            html,
+            "<span><i style=\"background:{}\"></i>{} event</span>",
+            color, kind
+        );
+    }
+    html.push_str("</p>\n");
+    html
+}
+
+fn summary(report: &Report) -> String {
+    let mut html = String::from(
+        "<table>\n<tr><th>Service</th><th>CPU min</th><th>CPU avg</th><th>CPU p95</th>\
+         <th>CPU max</th><th>RSS min (MB)</th><th>RSS avg</th><th>RSS p95</th><th>RSS max</th>\
+         <th>Breaches</th><th>Restarts</th><th>Gaps</th></tr>\n",
+    );
+    for service in &report.services {
+        let cells: Vec<String> = Report::stats_cells(service.cpu.as_ref())
+            .into_iter()
+            .chain(Report::stats_cells(service.rss_mb.as_ref()))
+            .chain([
+                service.breaches.to_string(),
+                service.restarts.to_string(),
+                Report::gap_cell(&service.gaps),
+            ])
+            .collect();
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            escape(&service.service),
+            cells.join("</td><td>")
+        );
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+/*
+One self-contained page: the summary table, then meminfo, the RSS of each service, CPU,
+temperature and disk charts as inline SVG, breaches and restarts marked on each. No script
+and nothing fetched, so it opens offline from a USB stick.
+*/
+pub fn render(history: &History, report: &Report) -> String {
+    // the services get a chart of their own, on the meminfo scale they would be flat lines
+    let meminfo: Columns = history
+        .columns(Metric::Memory)
+        .iter()
+        .filter(|(column, _)| MEMINFO_COLUMNS.contains(&column.as_str()))
+        .map(|(column, points)| (column.clone(), points.clone()))
+        .collect();
+    let charts = [
+        ("Memory (MB)", meminfo),
+        ("Service RSS (MB)", history.services(Metric::Memory)),
+        ("CPU (%)", history.services(Metric::Cpu)),
+        (
+            "Temperature (C)",
+            history.columns(Metric::Temperature).clone(),
+        ),
+        ("Disk (MB)", history.columns(Metric::Disk).clone()),
+    ];
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>systemd-doctor report {0}</title><style>{1}</style></head>\n<body>\n\
+         <h1>systemd-doctor report</h1>\n<p>{0}</p>\n",
+        escape(&report.range()),
+        STYLE
+    );
+    html.push_str(&summary(report));
+    let Some(span) = span(history) else {
+        html.push_str("<p>No samples in this range.</p>\n</body></html>\n");
+        return html;
+    };
+    for (title, series) in charts {
+        let _ = writeln!(html, "<h2>{}</h2>", title);
+        if series.is_empty() {
+            html.push_str("<p>No samples.</p>\n");
+            continue;
+        }
+        html.push_str(&chart(&series, &history.events, span));
+        html.push_str(&legend(&series));
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::html::{color, nice_step, render, PALETTE};
+    use crate::report::{EventRecord, History, Report};
+    use crate::sample::Metric;
+    use crate::tsdb::Point;
+    use chrono::{Duration, Local, TimeZone};
+
+    #[test]
+    fn test_render_charts_and_markers() {
+        let start = Local.with_ymd_and_hms(2024, 6, 3, 0, 0, 0).unwrap();
+        let points = |value: f64| -> Vec<Point> {
+            (0..2000)
+                .map(|i| Point {
+                    time: start + Duration::seconds(i * 10),
+                    min: value,
+                    avg: value,
+                    max: value,
+                    count: 1,
+                })
+                .collect()
+        };
+        let history = History {
+            from: None,
+            to: start + Duration::hours(6),
+            metrics: [
+                (
+                    Metric::Memory,
+                    [
+                        ("Total Memory(MB)".to_string(), points(3800.0)),
+                        ("<ota>(MB)".to_string(), points(120.0)),
+                        ("<ota> PSS(MB)".to_string(), points(90.0)),
+                    ]
+                    .into(),
+                ),
+                (Metric::Cpu, [("<ota>(%)".to_string(), points(5.0))].into()),
+            ]
+            .into(),
+            events: vec![EventRecord {
+                time: start + Duration::hours(1),
+                service: "<ota>".to_string(),
+                kind: "breach".to_string(),
+                message: "memory 60.0 above threshold 50 for 0s".to_string(),
+            }],
+        };
+
+        let html = render(&history, &Report::new(&history));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<ota>"));
+        assert_eq!(html.matches("<svg").count(), 5 - 2);
+        // one line per series, thinned, the smaps column is not plotted
+        assert_eq!(html.matches("<polyline").count(), 3);
+        // the breach marker is drawn on every chart
+        assert_eq!(html.matches("stroke-dasharray").count(), 3);
+        assert!(!PALETTE.contains(&color(PALETTE.len()).as_str()));
+        assert_ne!(color(PALETTE.len()), color(PALETTE.len() + 1));
+        assert_eq!(nice_step(950.0), 1000.0);
+        assert_eq!(nice_step(0.3), 0.5);
+    }
+}
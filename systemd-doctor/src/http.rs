@@ -0,0 +1,423 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local};
+use log::{error, warn};
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{Config, HttpConfig};
+use crate::report::{csv_columns, csv_events, parse_time, store_columns};
+use crate::sample::Metric;
+use crate::state_store::StateStore;
+use crate::tsdb::{store_dir, TimeSeriesStore};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+// requests answered at the same time, further connections are closed right away
+const MAX_CLIENTS: usize = 8;
+// request line and headers, there is no body to read
+const MAX_HEAD: usize = 8192;
+const DEFAULT_EVENT_LIMIT: usize = 100;
+
+pub struct Response {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.into() }),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/*
+Read-only JSON API over the state store and the history on disk, for the web UI and the fleet
+agent. Requests are answered from the latest snapshot, except /api/v1/history and
+/api/v1/events which read the store or the csv files like `report` does.
+*/
+pub struct Api {
+    pub config: Arc<Config>,
+    pub state: Arc<StateStore>,
+    pub started_at: DateTime<Local>,
+}
+
+impl Api {
+    /// Answers `GET <target>`, `authorization` is the value of its Authorization header.
+    pub fn get(&self, target: &str, authorization: Option<&str>) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query: Vec<(String, String)> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+            .collect();
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+                .filter(|value| !value.is_empty())
+        };
+
+        // liveness probes do not need the token, nothing is disclosed
+        if path == "/healthz" {
+            return self.health();
+        }
+        if let Some(token) = &self.config.http.token {
+            let presented = authorization.and_then(|value| value.strip_prefix("Bearer "));
+            if !presented.is_some_and(|presented| same(presented.as_bytes(), token.as_bytes())) {
+                return Response::error(401, "Missing or wrong bearer token");
+            }
+        }
+
+        let result = match path.trim_end_matches('/') {
+            "/api/v1/services" => Ok(self.services()),
+            "/api/v1/history" => self.history(param("metric"), param("from"), param("to")),
+            "/api/v1/events" => {
+                self.events(param("service"), param("from"), param("to"), param("limit"))
+            }
+            path => match path.strip_prefix("/api/v1/services/") {
+                Some(service) => self.service(&percent_decode(service)),
+                None => Err(Response::error(404, format!("No such endpoint {}", path))),
+            },
+        };
+        result.unwrap_or_else(|response| response)
+    }
+
+    // ok while samples keep coming, three of the shortest intervals are allowed to pass
+    fn health(&self) -> Response {
+        let now = Local::now();
+        let limit = ChronoDuration::from_std(self.config.stale_after()).unwrap_or_default();
+        let updated_at = self.state.snapshot().updated_at;
+        let status = match updated_at {
+            Some(updated_at) if now - updated_at <= limit => "ok",
+            None if now - self.started_at <= limit => "starting",
+            _ => "stale",
+        };
+        let body = json!({
+            "status": status,
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime": (now - self.started_at).num_seconds(),
+            "last_sample": updated_at.map(|time| time.to_rfc3339()),
+        });
+        match status {
+            "stale" => Response { status: 503, body },
+            _ => Response::ok(body),
+        }
+    }
+
+    fn tracked(&self) -> impl Iterator<Item = &String> {
+        self.config.services.list.iter().flatten()
+    }
+
+    fn services(&self) -> Response {
+        let snapshot = self.state.snapshot();
+        Response::ok(
+            self.tracked()
+                .map(|service| snapshot.service_json(service))
+                .collect(),
+        )
+    }
+
+    fn service(&self, service: &str) -> Result<Response, Response> {
+        if !self.tracked().any(|tracked| tracked == service) {
+            return Err(Response::error(
+                404,
+                format!("{} is not a tracked service", service),
+            ));
+        }
+        Ok(Response::ok(self.state.snapshot().service_json(service)))
+    }
+
+    // `from` defaults to `default_age` ago, `to` to now
+    fn range(
+        from: Option<&str>,
+        to: Option<&str>,
+        default_age: ChronoDuration,
+    ) -> Result<(DateTime<Local>, DateTime<Local>), Response> {
+        let now = Local::now();
+        let parse =
+            |text: &str| parse_time(text, now).map_err(|message| Response::error(400, message));
+        let from = from.map(parse).transpose()?.unwrap_or(now - default_age);
+        let to = to.map(parse).transpose()?.unwrap_or(now);
+        Ok((from, to))
+    }
+
+    fn history(
+        &self,
+        metric: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Response, Response> {
+        let names: Vec<&str> = Metric::ALL.iter().map(Metric::name).collect();
+        let metric = metric
+            .and_then(|name| Metric::ALL.into_iter().find(|metric| metric.name() == name))
+            .ok_or_else(|| {
+                Response::error(400, format!("metric must be one of {}", names.join(", ")))
+            })?;
+        let (from, to) = Self::range(from, to, ChronoDuration::hours(1))?;
+        let columns = if self.config.store.enabled {
+            TimeSeriesStore::open(&store_dir(&self.config), self.config.store.clone())
+                .and_then(|store| store_columns(&store, metric, from, to))
+        } else {
+            let dir = self.config.monitor.output_dir.clone().unwrap_or_default();
+            csv_columns(&dir, metric, from, to)
+        }
+        .map_err(|e| Response::error(500, e.to_string()))?;
+
+        let series: serde_json::Map<String, serde_json::Value> = columns
+            .into_iter()
+            .map(|(column, points)| {
+                let points: Vec<serde_json::Value> = points
+                    .iter()
+                    .map(|point| {
+                        json!({
+                            "time": point.time.to_rfc3339(),
+                            "min": point.min,
+                            "avg": point.avg,
+                            "max": point.max,
+                            "count": point.count,
+                        })
+                    })
+                    .collect();
+                (column, points.into())
+            })
+            .collect();
+        Ok(Response::ok(json!({
+            "metric": metric.name(),
+            "from": from.to_rfc3339(),
+            "to": to.to_rfc3339(),
+            "series": series,
+        })))
+    }
+
+    // the latest `limit` events of the range, oldest first
+    fn events(
+        &self,
+        service: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>,
+        limit: Option<&str>,
+    ) -> Result<Response, Response> {
+        let (from, to) = Self::range(from, to, ChronoDuration::hours(24))?;
+        let limit = match limit {
+            Some(limit) => limit
+                .parse::<usize>()
+                .map_err(|_| Response::error(400, format!("Invalid limit '{}'", limit)))?,
+            None => DEFAULT_EVENT_LIMIT,
+        };
+        let dir = self.config.monitor.output_dir.clone().unwrap_or_default();
+        let mut events =
+            csv_events(&dir, from, to).map_err(|e| Response::error(500, e.to_string()))?;
+        if let Some(service) = service {
+            events.retain(|event| event.service == service);
+        }
+        let events: Vec<serde_json::Value> = events
+            .iter()
+            .skip(events.len().saturating_sub(limit))
+            .map(|event| {
+                json!({
+                    "time": event.time.to_rfc3339(),
+                    "service": Some(&event.service).filter(|service| !service.is_empty()),
+                    "event": event.kind,
+                    "message": event.message,
+                })
+            })
+            .collect();
+        Ok(Response::ok(events.into()))
+    }
+
+    fn serve_client(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream).take(MAX_HEAD as u64);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut authorization = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let response = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => self.get(target, authorization.as_deref()),
+            (Some(_), Some(_)) => Response::error(405, "Only GET is supported"),
+            _ => Response::error(400, "Malformed request"),
+        };
+        let body = response.body.to_string();
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status,
+            response.reason(),
+            body.len()
+        );
+        if response.status == 401 {
+            head.push_str("WWW-Authenticate: Bearer\r\n");
+        }
+        write!(writer, "{}\r\n{}", head, body)?;
+        writer.flush()
+    }
+}
+
+// %XX escapes and + of a query or path segment
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok());
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// compares every byte, so the time taken does not tell how much of the token was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/*
+Listens on `[http] listen` and answers each connection on a thread of its own, at most
+MAX_CLIENTS at a time. Like the control socket, the listener is not joined on shutdown.
+*/
+pub fn serve(api: Api, config: &HttpConfig) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(&config.listen)?;
+    let address = listener.local_addr()?;
+    let api = Arc::new(api);
+    let clients = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept an HTTP connection: {}", e);
+                    continue;
+                }
+            };
+            if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                clients.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            let api = api.clone();
+            let clients = clients.clone();
+            thread::spawn(move || {
+                if let Err(e) = api.serve_client(stream) {
+                    warn!("HTTP request failed: {}", e);
+                }
+                clients.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::http::{serve, Api};
+    use crate::sample::{Metric, Reading, Sample, Value};
+    use crate::state_store::StateStore;
+    use chrono::Local;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+
+    fn get(address: SocketAddr, target: &str, token: Option<&str>) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", target);
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        write!(stream, "{}\r\n", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_services_endpoint() {
+        let mut config = Config::default();
+        config.services.list = Some(vec!["ota".to_string(), "can@0".to_string()]);
+        config.http.listen = "127.0.0.1:0".to_string();
+        config.http.token = Some("secret".to_string());
+        let state = Arc::new(StateStore::new());
+        let mut sample = Sample::new(Metric::Cpu);
+        sample
+            .readings
+            .push(Reading::service("ota", Metric::Cpu, Value::Number(12.5)));
+        state.apply(&sample);
+        let http = config.http.clone();
+        let address = serve(
+            Api {
+                config: Arc::new(config),
+                state,
+                started_at: Local::now(),
+            },
+            &http,
+        )
+        .unwrap();
+
+        assert_eq!(get(address, "/healthz", None).0, 200);
+        assert_eq!(get(address, "/api/v1/services", None).0, 401);
+        assert_eq!(get(address, "/api/v1/services", Some("wrong")).0, 401);
+
+        let (status, body) = get(address, "/api/v1/services", Some("secret"));
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["service"], "ota");
+        assert_eq!(body[0]["readings"]["cpu"], 12.5);
+        let (status, body) = get(address, "/api/v1/services/can%400", Some("secret"));
+        assert_eq!((status, body["service"].as_str()), (200, Some("can@0")));
+        assert_eq!(get(address, "/api/v1/services/nope", Some("secret")).0, 404);
+        assert_eq!(
+            get(address, "/api/v1/history?metric=bogus", Some("secret")).0,
+            400
+        );
+    }
+}
@@ -1,30 +1,43 @@
 use csv::Writer;
-use log::info;
+use log::{error, info, warn};
+use std::collections::HashMap;
 use std::env;
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
 use std::io;
 use std::io::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::thread;
 use std::time::{Duration, SystemTime};
 
+use crate::collector::{columns, unit_columns};
+use crate::config::Config;
+use crate::event::HealthEvent;
+use crate::persist::StateFile;
+use crate::sample::{Message, Metric, Sample, Value};
+use crate::sink::Sink;
+use crate::unit_state::unit_name;
+
 pub struct LogWriter {
     log_file_path: PathBuf,
     writer: Writer<std::fs::File>,
 }
 
 impl LogWriter {
-    pub fn new() -> io::Result<(Self, Self)> {
-        let memory_log_writer = LogWriter::create_log_writer(None, "DrViet_memory.csv")?;
-        let cpu_log_writer = LogWriter::create_log_writer(None, "DrViet_cpu.csv")?;
-
-        Ok((memory_log_writer, cpu_log_writer))
+    /// Opens `file_name` in `output_dir`, or in the current directory when unset.
+    pub fn open(output_dir: Option<&Path>, file_name: &str) -> io::Result<Self> {
+        match output_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                LogWriter::create_log_writer(Some(&dir.join(file_name)), file_name)
+            }
+            None => LogWriter::create_log_writer(None, file_name),
+        }
     }
 
-    pub fn create_log_writer(log_file: Option<&str>, default_name: &str) -> io::Result<Self> {
+    pub fn create_log_writer(log_file: Option<&Path>, default_name: &str) -> io::Result<Self> {
         let log_file_path = match log_file {
-            Some(path) => PathBuf::from(path),
+            Some(path) => path.to_path_buf(),
             None => {
                 let mut current_dir = env::current_dir().expect("Failed to get currect directory");
                 current_dir.push(default_name);
@@ -34,7 +47,6 @@ impl LogWriter {
 
         // Open or create the csv file
         let file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(&log_file_path)?;
@@ -50,6 +62,15 @@ impl LogWriter {
         &self.log_file_path
     }
 
+    // the header is only written to a new (empty) file
+    pub fn write_header_if_empty(&mut self, header: &[String]) -> io::Result<()> {
+        if fs::metadata(self.get_log_file_path())?.len() == 0 {
+            let header_refs: Vec<&str> = header.iter().map(String::as_str).collect();
+            self.write_record(&header_refs)?;
+        }
+        Ok(())
+    }
+
     //methods to write to the csv file
     pub fn write_record(&mut self, record: &[&str]) -> io::Result<()> {
         self.writer.write_record(record)?;
@@ -57,10 +78,16 @@ impl LogWriter {
         Ok(())
     }
 
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    #[allow(dead_code)]
     pub fn log_info(&self, message: &str) {
         info!("{}", message);
     }
 
+    #[allow(dead_code)]
     pub fn extract_service_logs(service: &str, since: &str) -> Result<String> {
         let output = Command::new("journalctl")
             .arg("-u")
@@ -71,12 +98,45 @@ impl LogWriter {
             .output()?;
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, stderr));
+            return Err(std::io::Error::other(stderr));
         }
 
         Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 
+    /*
+    Last `lines` journal lines of `unit`, only those after the `after` cursor when set, and the
+    cursor of the last entry (None when there was no new entry).
+    */
+    pub fn journal_tail(
+        unit: &str,
+        lines: usize,
+        after: Option<&str>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let mut command = Command::new("journalctl");
+        command.arg("-u").arg(unit);
+        match after {
+            // -n with a cursor would show the first lines after it, the last ones are kept below
+            Some(cursor) => command.arg("--after-cursor").arg(cursor),
+            None => command.arg("-n").arg(lines.to_string()),
+        };
+        let output = command
+            .arg("-o")
+            .arg("short-iso")
+            .arg("--show-cursor")
+            .arg("--no-pager")
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(std::io::Error::other(stderr));
+        }
+        Ok(parse_journal(
+            &String::from_utf8_lossy(&output.stdout),
+            lines,
+        ))
+    }
+
+    #[allow(dead_code)]
     pub fn extract_kernel_logs(since: &str) -> Result<String> {
         let output = Command::new("journalctl")
             .arg("-k")
@@ -86,11 +146,12 @@ impl LogWriter {
             .output()?;
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, stderr));
+            return Err(std::io::Error::other(stderr));
         }
         Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 
+    #[allow(dead_code)]
     pub fn spawn_service_log_writer(&self, service: &str) -> Result<()> {
         let service = service.to_string();
         thread::spawn(move || {
@@ -102,7 +163,7 @@ impl LogWriter {
                         info!("{}", logs);
                         last_fetch_time = SystemTime::now();
                     }
-                    Err(e) => eprintln!("Failed to fetch logs: {} {}", e, service),
+                    Err(e) => warn!("Failed to fetch logs: {} {}", e, service),
                 }
                 thread::sleep(Duration::from_secs(1));
             }
@@ -111,6 +172,7 @@ impl LogWriter {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn spawn_kernel_log_writer(&self) -> Result<()> {
         thread::spawn(move || {
             let mut last_fetch_time = SystemTime::now();
@@ -121,7 +183,7 @@ impl LogWriter {
                         info!("{}", logs);
                         last_fetch_time = SystemTime::now();
                     }
-                    Err(e) => eprintln!("Failed to fetch logs: {}", e),
+                    Err(e) => warn!("Failed to fetch logs: {}", e),
                 }
                 thread::sleep(Duration::from_secs(1));
             }
@@ -130,3 +192,214 @@ impl LogWriter {
         Ok(())
     }
 }
+
+// Splits journalctl --show-cursor output into its last `lines` lines and the cursor
+fn parse_journal(output: &str, lines: usize) -> (Vec<String>, Option<String>) {
+    let mut journal: Vec<String> = Vec::new();
+    let mut cursor = None;
+    for line in output.lines() {
+        match line.strip_prefix("-- cursor: ") {
+            Some(value) => cursor = Some(value.to_string()),
+            None if line == "-- No entries --" => {}
+            None => journal.push(line.to_string()),
+        }
+    }
+    let skip = journal.len().saturating_sub(lines);
+    (journal.split_off(skip), cursor)
+}
+
+/*
+Fills in the last `lines` journal lines of the unit of each failure event. Lines attached to an
+earlier event, also before a restart, are not attached again: the cursor of the last one is
+kept in the state file.
+*/
+pub fn attach_journal(events: &mut [HealthEvent], lines: usize, state_file: &StateFile) {
+    if lines == 0 {
+        return;
+    }
+    for event in events.iter_mut().filter(|event| event.wants_journal()) {
+        let Some(service) = &event.service else {
+            continue;
+        };
+        let unit = unit_name(service);
+        let after = state_file.read(|state| state.journal_cursors.get(&unit).cloned());
+        match LogWriter::journal_tail(&unit, lines, after.as_deref()) {
+            Ok((journal, cursor)) => {
+                event.journal = journal;
+                if let Some(cursor) = cursor {
+                    let result = state_file.update(|state| {
+                        state.journal_cursors.insert(unit, cursor);
+                    });
+                    if let Err(e) = result {
+                        error!("Failed to save the journal cursor of {}: {}", service, e);
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to read the journal of {}: {}", service, e),
+        }
+    }
+}
+
+pub const EVENTS_HEADER: [&str; 5] = ["Timestamp", "Service", "Event", "Message", "Journal"];
+
+/*
+Writes each metric to its DrViet_<metric>.csv file and events to DrViet_events.csv. The unit
+state of the services goes in the cpu and memory rows, after their own columns, as last read:
+a change between two rows shows in the next one, and as an event. Only when samples go to the
+time-series store alone, which keeps numbers, is it written to DrViet_unit.csv instead.
+*/
+pub struct CsvSink {
+    logs: HashMap<Metric, (LogWriter, Vec<String>)>,
+    events_log: LogWriter,
+    // column -> latest unit state reading, e.g. "ota(ActiveState)"
+    units: HashMap<String, Value>,
+}
+
+impl CsvSink {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let output_dir = config.monitor.output_dir.as_deref();
+        let services = config.services.list.clone().unwrap_or_default();
+        let mut logs = HashMap::new();
+        // numeric samples can go to the time-series store only
+        let store_only = config.store.enabled && !config.store.csv;
+        for metric in Metric::ALL {
+            if store_only != (metric == Metric::Unit) {
+                continue;
+            }
+            let mut header = vec!["Timestamp".to_string()];
+            header.extend(columns(metric, &services));
+            if matches!(metric, Metric::Cpu | Metric::Memory) {
+                header.extend(unit_columns(&services));
+            }
+
+            let file_name = format!("DrViet_{}.csv", metric.name());
+            let log = LogWriter::open(output_dir, &file_name)?;
+            logs.insert(metric, (log, header));
+        }
+        let events_log = LogWriter::open(output_dir, "DrViet_events.csv")?;
+        Ok(Self {
+            logs,
+            events_log,
+            units: HashMap::new(),
+        })
+    }
+
+    fn write_event(&mut self, event: &HealthEvent) -> io::Result<()> {
+        let header: Vec<String> = EVENTS_HEADER.iter().map(|c| c.to_string()).collect();
+        self.events_log.write_header_if_empty(&header)?;
+        let timestamp = event.timestamp();
+        let service = event.service.clone().unwrap_or_default();
+        let kind = event.kind.to_string();
+        let journal = event.journal.join("\n");
+        self.events_log
+            .write_record(&[&timestamp, &service, &kind, &event.message, &journal])
+    }
+
+    // Every row carries all columns, the ones not sampled are left empty
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        if sample.metric == Metric::Unit {
+            for reading in &sample.readings {
+                self.units
+                    .insert(reading.column.clone(), reading.value.clone());
+            }
+        }
+        let Some((log, header)) = self.logs.get_mut(&sample.metric) else {
+            return Ok(());
+        };
+        log.write_header_if_empty(header)?;
+
+        let mut record = vec![sample.timestamp()];
+        for column in &header[1..] {
+            let cell = sample
+                .readings
+                .iter()
+                .find(|reading| &reading.column == column)
+                .map(|reading| &reading.value)
+                .or_else(|| self.units.get(column))
+                .map(Value::to_string)
+                .unwrap_or_default();
+            record.push(cell);
+        }
+        let record_refs: Vec<&str> = record.iter().map(String::as_str).collect();
+        log.write_record(&record_refs)
+    }
+}
+
+impl Sink for CsvSink {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Sample(sample) => self.write_sample(sample),
+            Message::Event(event) => self.write_event(event),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (log, _) in self.logs.values_mut() {
+            log.flush()?;
+        }
+        self.events_log.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::event::{EventKind, HealthEvent};
+    use crate::log::CsvSink;
+    use crate::sample::{Message, Metric, Reading, Sample, Value};
+    use crate::sink::Sink;
+    use crate::unit_state::UNIT_FIELDS;
+    use std::fs;
+
+    #[test]
+    fn test_csv_rows_under_one_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.monitor.output_dir = Some(dir.path().to_path_buf());
+        config.services.list = Some(vec!["ota".to_string(), "logging".to_string()]);
+        let mut sink = CsvSink::new(&config).unwrap();
+
+        let mut units = Sample::new(Metric::Unit);
+        units.readings.push(Reading::service_field(
+            "ota",
+            "ActiveState",
+            Value::Text("active".to_string()),
+        ));
+        sink.handle(&Message::Sample(units)).unwrap();
+        for load in [12.5, 13.0] {
+            let mut sample = Sample::new(Metric::Cpu);
+            sample
+                .readings
+                .push(Reading::service("ota", Metric::Cpu, Value::Number(load)));
+            sample
+                .readings
+                .push(Reading::service("logging", Metric::Cpu, Value::Missing));
+            sink.handle(&Message::Sample(sample)).unwrap();
+        }
+        let mut event = HealthEvent::new(Some("ota"), EventKind::UnitFailed, "failed".to_string());
+        event.journal = vec!["line 1".to_string(), "line 2".to_string()];
+        sink.handle(&Message::Event(event)).unwrap();
+        sink.flush().unwrap();
+
+        let cpu = fs::read_to_string(dir.path().join("DrViet_cpu.csv")).unwrap();
+        let lines: Vec<&str> = cpu.lines().collect();
+        let header: Vec<&str> = lines[0].split(',').collect();
+        assert_eq!(
+            header[..4],
+            ["Timestamp", "ota(%)", "logging(%)", "ota(ActiveState)"]
+        );
+        assert_eq!(header.len(), 3 + 2 * UNIT_FIELDS.len());
+        assert!(lines[1].contains(",12.5,N/A,active,"));
+        assert!(lines[2].contains(",13,N/A,active,"));
+        assert_eq!(lines.len(), 3);
+        // the unit state has no file of its own
+        assert!(!dir.path().join("DrViet_unit.csv").exists());
+        let events = fs::read_to_string(dir.path().join("DrViet_events.csv")).unwrap();
+        assert!(events.starts_with("Timestamp,Service,Event,Message,Journal\n"));
+        assert!(events.ends_with(",ota,unit_failed,failed,\"line 1\nline 2\"\n"));
+    }
+}
@@ -0,0 +1,185 @@
+use log::{Level, LevelFilter, Record};
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+use crate::config::{Config, LogOutput, LoggingConfig};
+use crate::syslog::{journal_field, JOURNAL_SOCKET};
+
+const FILE_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} {l:<5} {t} - {m}{n}";
+const STDERR_PATTERN: &str = "{l:<5} {m}{n}";
+
+// syslog priority of a log level
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Native journal entries, with the level as PRIORITY and the module as CODE_MODULE.
+#[derive(Debug)]
+struct JournalAppender {
+    socket: UnixDatagram,
+}
+
+impl Append for JournalAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut entry = Vec::new();
+        journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+        journal_field(
+            &mut entry,
+            "PRIORITY",
+            &priority(record.level()).to_string(),
+        );
+        journal_field(&mut entry, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+        journal_field(&mut entry, "CODE_MODULE", record.target());
+        if let (Some(file), Some(line)) = (record.file(), record.line()) {
+            journal_field(&mut entry, "CODE_FILE", file);
+            journal_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        self.socket.send(&entry)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+fn parse_level(text: &str) -> io::Result<LevelFilter> {
+    text.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid log level '{}'", text),
+        )
+    })
+}
+
+// systemd sets JOURNAL_STREAM when stderr goes to the journal
+fn under_systemd() -> bool {
+    env::var_os("JOURNAL_STREAM").is_some()
+}
+
+fn appender(config: &Config) -> io::Result<Box<dyn Append>> {
+    let logging = &config.logging;
+    let output = match logging.output {
+        LogOutput::Auto if under_systemd() => LogOutput::Journal,
+        LogOutput::Auto => LogOutput::File,
+        output => output,
+    };
+    match output {
+        LogOutput::Journal => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNAL_SOCKET)?;
+            Ok(Box::new(JournalAppender { socket }))
+        }
+        LogOutput::File | LogOutput::Auto => {
+            let path = logging.file.clone().unwrap_or_else(|| {
+                let file = PathBuf::from("systemd-doctor.log");
+                match &config.monitor.output_dir {
+                    Some(dir) => dir.join(file),
+                    None => file,
+                }
+            });
+            let roller = FixedWindowRoller::builder()
+                .base(1)
+                .build(&format!("{}.{{}}", path.display()), logging.files.max(1))
+                .map_err(io::Error::other)?;
+            let policy = CompoundPolicy::new(
+                Box::new(SizeTrigger::new(logging.max_size.max(1) * 1024 * 1024)),
+                Box::new(roller),
+            );
+            let appender = RollingFileAppender::builder()
+                .encoder(Box::new(PatternEncoder::new(FILE_PATTERN)))
+                .build(path, Box::new(policy))?;
+            Ok(Box::new(appender))
+        }
+        LogOutput::Stderr => Ok(Box::new(
+            ConsoleAppender::builder()
+                .target(Target::Stderr)
+                .encoder(Box::new(PatternEncoder::new(STDERR_PATTERN)))
+                .build(),
+        )),
+    }
+}
+
+/// log4rs configuration of `[logging]`: one appender, the root level and a logger per module.
+pub fn build(config: &Config, appender: Box<dyn Append>) -> io::Result<log4rs::Config> {
+    let logging = &config.logging;
+    let mut builder =
+        log4rs::Config::builder().appender(Appender::builder().build("main", appender));
+    for (module, level) in &logging.modules {
+        builder = builder.logger(Logger::builder().build(module, parse_level(level)?));
+    }
+    builder
+        .build(
+            Root::builder()
+                .appender("main")
+                .build(parse_level(&logging.level)?),
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/*
+Installs the logger of the daemon. An unreachable journal falls back to stderr, which systemd
+also forwards to the journal, only without the priorities.
+*/
+pub fn init(config: &Config) -> io::Result<()> {
+    let appender = appender(config).or_else(|e| {
+        if config.logging.output == LogOutput::File {
+            return Err(e);
+        }
+        eprintln!("Failed to open the log, logging to stderr: {}", e);
+        let mut config = config.clone();
+        config.logging.output = LogOutput::Stderr;
+        appender(&config)
+    })?;
+    log4rs::init_config(build(config, appender)?).map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Warnings and errors on stderr, for the commands run from a terminal.
+pub fn init_stderr(config: &Config) -> io::Result<()> {
+    let mut config = config.clone();
+    config.logging.output = LogOutput::Stderr;
+    if config.logging.level == LoggingConfig::default().level {
+        config.logging.level = "warn".to_string();
+    }
+    log4rs::init_config(build(&config, appender(&config)?)?).map_err(io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::logging::build;
+    use log::LevelFilter;
+    use log4rs::append::console::ConsoleAppender;
+
+    #[test]
+    fn test_module_levels() {
+        let mut config = Config::default();
+        config
+            .logging
+            .modules
+            .insert("systemd_doctor::collector".to_string(), "debug".to_string());
+        let log_config = build(&config, Box::new(ConsoleAppender::builder().build())).unwrap();
+        assert_eq!(log_config.root().level(), LevelFilter::Info);
+        assert_eq!(log_config.loggers()[0].name(), "systemd_doctor::collector");
+        assert_eq!(log_config.loggers()[0].level(), LevelFilter::Debug);
+
+        config.logging.level = "loud".to_string();
+        let error = build(&config, Box::new(ConsoleAppender::builder().build())).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid log level 'loud'");
+    }
+}
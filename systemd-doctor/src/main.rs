@@ -1,25 +1,216 @@
+use ::log::{debug, error, info, warn};
+use std::env;
+use std::fs;
 use std::io;
-use std::thread;
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+mod alert;
+mod anomaly;
+mod cli;
 mod cmd_health_check;
+mod collector;
 mod config;
+mod control;
+mod event;
 mod health_monitor;
+mod hooks;
+mod html;
+mod http;
 mod log;
+mod logging;
+mod mqtt;
+mod notify;
+mod oom;
+mod persist;
+mod processes;
+mod remediation;
+mod report;
+mod sample;
+mod scheduler;
+mod shutdown;
+mod sink;
+mod state_store;
 mod sys_health_check;
+mod syslog;
+mod systemd_dbus;
+mod top;
+mod trend;
+mod tsdb;
+mod unit_state;
+use crate::cli::{Cli, Command, ConfigCommand, ReportFormat};
+use crate::config::ConfigParser;
+use crate::control::Control;
 use crate::health_monitor::HealthMonitor;
-use std::sync::{Arc, Mutex};
+use crate::http::Api;
+use crate::notify::{notify, watchdog_interval};
+use crate::report::{Format, History, Report};
+use crate::shutdown::Shutdown;
+use chrono::{Duration as ChronoDuration, Local};
+use clap::Parser;
 
 fn main() -> io::Result<()> {
-    println!("Starting health check...");
+    let cli = Cli::parse();
+    let config_parser = ConfigParser::with_overrides(&cli.config, &cli.overrides)?;
 
-    let health_monitor = Arc::new(Mutex::new(
-        HealthMonitor::new("config.toml", Duration::from_secs(10), None).unwrap(),
-    ));
+    let command = cli.command.unwrap_or(Command::Run);
+    match command {
+        Command::Run | Command::Top { .. } => logging::init(config_parser.get_config())?,
+        // one-shot commands only tell what went wrong
+        _ => logging::init_stderr(config_parser.get_config())?,
+    }
+
+    match command {
+        Command::Run => run(config_parser, cli.config, cli.overrides),
+        Command::Check => {
+            print!("{}", trend::check(config_parser.get_config())?);
+            Ok(())
+        }
+        Command::Report {
+            since,
+            until,
+            format,
+            html,
+            dir,
+        } => {
+            let now = Local::now();
+            let parse = |text: &str| {
+                report::parse_time(text, now)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            };
+            let from = since.as_deref().map(parse).transpose()?;
+            let to = until.as_deref().map(parse).transpose()?.unwrap_or(now);
+            let history = History::load(config_parser.get_config(), dir.as_deref(), from, to)?;
+            let report = Report::new(&history);
+            if let Some(path) = html {
+                fs::write(&path, html::render(&history, &report))?;
+                println!("Wrote {}", path.display());
+                return Ok(());
+            }
+            let format = match format {
+                ReportFormat::Table => Format::Table,
+                ReportFormat::Json => Format::Json,
+                ReportFormat::Markdown => Format::Markdown,
+            };
+            print!("{}", report.render(format));
+            Ok(())
+        }
+        Command::Top { interval } => top::run(
+            config_parser.get_config(),
+            Duration::from_secs(interval.max(1)),
+        ),
+        Command::Ctl { command, args } => {
+            let socket = &config_parser.get_config().control.socket;
+            let result = control::request(socket, &command, &args)?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+            Ok(())
+        }
+        Command::Config {
+            action: ConfigCommand::Dump,
+        } => {
+            print!("{}", config_parser.dump()?);
+            Ok(())
+        }
+    }
+}
 
-    HealthMonitor::start_tracking(health_monitor.clone(), Duration::from_secs(10));
+fn run(config_parser: ConfigParser, config_path: String, overrides: Vec<String>) -> io::Result<()> {
+    info!(
+        "Starting health check, version {}",
+        env!("CARGO_PKG_VERSION")
+    );
 
-    loop {
-        println!("Viet is working");
-        thread::sleep(Duration::from_secs(10));
+    let config = config_parser.get_config().clone();
+    let shutdown_timeout = Duration::from_secs(config.monitor.shutdown_timeout);
+    let shutdown = Shutdown::new();
+    shutdown.on_signals()?;
+
+    let mut health_monitor = HealthMonitor::new(config.clone(), shutdown.clone())?;
+    let state = health_monitor.state();
+    let reload = Arc::new(AtomicBool::new(false));
+
+    health_monitor.start_tracking();
+    let mut control_socket = None;
+    if config.control.enabled {
+        let control = Control {
+            config: Arc::new(config.clone()),
+            config_path,
+            overrides,
+            state: state.clone(),
+            state_file: health_monitor.state_file(),
+            shutdown: shutdown.clone(),
+            reload: reload.clone(),
+            started_at: Local::now(),
+        };
+        match control::serve(control, &config.control) {
+            Ok(socket) => control_socket = Some(socket),
+            Err(e) => error!("Failed to open the control socket: {}", e),
+        }
+    }
+    if config.http.enabled {
+        let api = Api {
+            config: Arc::new(config.clone()),
+            state: state.clone(),
+            started_at: Local::now(),
+        };
+        if let Err(e) = http::serve(api, &config.http) {
+            error!("Failed to listen on {}: {}", config.http.listen, e);
+        }
+    }
+    if let Err(e) = notify("READY=1") {
+        warn!("Failed to notify systemd: {}", e);
+    }
+
+    // systemd restarts a doctor whose collectors stopped publishing, see WatchdogSec=
+    let started_at = Local::now();
+    let stale_after = ChronoDuration::from_std(config.stale_after()).unwrap_or_default();
+    let keep_alive = watchdog_interval().unwrap_or(Duration::from_secs(10));
+    while !shutdown.wait_timeout(keep_alive) {
+        let snapshot = state.snapshot();
+        let last_sample = snapshot.updated_at.unwrap_or(started_at);
+        if Local::now() - last_sample <= stale_after {
+            let _ = notify("WATCHDOG=1");
+        } else {
+            warn!(
+                "No sample since {}, not notifying the watchdog",
+                last_sample.format("%H:%M:%S")
+            );
+        }
+        match snapshot.updated_at {
+            Some(updated_at) => debug!(
+                "Viet is working, {} services, last sample at {}",
+                snapshot.services.len(),
+                updated_at.format("%H:%M:%S")
+            ),
+            None => debug!("Viet is working"),
+        }
+    }
+
+    let reloading = reload.load(Ordering::SeqCst);
+    let _ = notify(if reloading {
+        "RELOADING=1"
+    } else {
+        "STOPPING=1"
+    });
+    if health_monitor.shutdown(shutdown_timeout) {
+        info!("Health check stopped");
+    } else {
+        warn!(
+            "Collectors did not stop within {}s, exiting anyway",
+            shutdown_timeout.as_secs()
+        );
+    }
+    // only a socket this doctor bound, exec below skips destructors
+    drop(control_socket);
+    if reloading {
+        // same pid, so systemd keeps tracking it, state is picked up from the state file
+        info!("Reloading...");
+        let error = process::Command::new(env::current_exe()?)
+            .args(env::args_os().skip(1))
+            .exec();
+        return Err(error);
     }
+    Ok(())
 }
@@ -0,0 +1,467 @@
+use chrono::Local;
+use log::warn;
+use rumqttc::v5::mqttbytes::v5::{LastWill as LastWill5, Packet as Packet5};
+use rumqttc::v5::mqttbytes::QoS as QoS5;
+use rumqttc::{Event, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, MqttConfig, MqttVersion};
+use crate::sample::Message;
+use crate::sink::Sink;
+use crate::state_store::StateStore;
+use crate::syslog::hostname;
+
+const DEFAULT_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+// requests rumqttc buffers between the sink and the network
+const CHANNEL_CAPACITY: usize = 64;
+// pause between two connection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// time given to the offline status to go out on shutdown
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `[mqtt] device`, else the hostname.
+pub fn device_name(config: &MqttConfig) -> String {
+    config.device.clone().unwrap_or_else(hostname)
+}
+
+enum Client {
+    V311(rumqttc::Client),
+    V5(rumqttc::v5::Client),
+}
+
+impl Client {
+    fn try_publish(
+        &self,
+        topic: &str,
+        qos: u8,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
+        match self {
+            Client::V311(client) => client
+                .try_publish(topic, qos_v311(qos), retain, payload)
+                .map_err(|e| e.to_string()),
+            Client::V5(client) => client
+                .try_publish(topic, qos_v5(qos), retain, payload)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    fn disconnect(&self) -> Result<(), String> {
+        match self {
+            Client::V311(client) => client.try_disconnect().map_err(|e| e.to_string()),
+            Client::V5(client) => client.try_disconnect().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// qos is 0, 1 or 2, checked when the config is loaded
+fn qos_v311(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+fn qos_v5(qos: u8) -> QoS5 {
+    match qos {
+        0 => QoS5::AtMostOnce,
+        1 => QoS5::AtLeastOnce,
+        _ => QoS5::ExactlyOnce,
+    }
+}
+
+// what the connection thread reports, the same for both protocol versions
+enum Notice {
+    Connected,
+    Disconnected,
+    Other,
+    Failed(String),
+}
+
+/*
+State shared with the connection thread, which drives rumqttc: it sends the retained `online`
+status on every (re)connection and waits RECONNECT_DELAY after a failure before rumqttc tries
+again.
+*/
+struct Link {
+    connected: AtomicBool,
+    stopping: AtomicBool,
+    status_topic: String,
+    qos: u8,
+}
+
+impl Link {
+    // false once the thread should stop
+    fn on(&self, notice: Notice, client: &Client) -> bool {
+        match notice {
+            Notice::Connected => {
+                self.connected.store(true, Ordering::SeqCst);
+                if let Err(e) =
+                    client.try_publish(&self.status_topic, self.qos, true, b"online".to_vec())
+                {
+                    warn!("Failed to publish the MQTT status: {}", e);
+                }
+            }
+            Notice::Disconnected => {
+                self.connected.store(false, Ordering::SeqCst);
+                return !self.stopping.load(Ordering::SeqCst);
+            }
+            Notice::Other => {}
+            Notice::Failed(error) => {
+                let was_connected = self.connected.swap(false, Ordering::SeqCst);
+                if self.stopping.load(Ordering::SeqCst) {
+                    return false;
+                }
+                if was_connected {
+                    warn!("MQTT connection lost: {}", error);
+                }
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+        true
+    }
+}
+
+/*
+Publishes a snapshot of the state store every `snapshot_interval` to <prefix>/<device>/metrics
+and every health event to <prefix>/<device>/events. <prefix>/<device>/status is a retained
+`online`, replaced by `offline` on shutdown or, through the Last Will, by the broker when the
+doctor disappears. Messages are queued while the broker is unreachable and sent as the next
+sample or event comes in after the reconnection.
+*/
+pub struct MqttSink {
+    config: MqttConfig,
+    device: String,
+    client: Client,
+    link: Arc<Link>,
+    state: Arc<StateStore>,
+    // (topic, payload) waiting for the connection
+    queue: VecDeque<(String, Vec<u8>)>,
+    dropping: bool,
+    last_snapshot: Option<Instant>,
+}
+
+impl MqttSink {
+    pub fn new(config: &Config, state: Arc<StateStore>) -> io::Result<Self> {
+        let config = config.mqtt.clone();
+        let device = device_name(&config);
+        let topic = |name: &str| format!("{}/{}/{}", config.prefix, device, name);
+        let link = Arc::new(Link {
+            connected: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            status_topic: topic("status"),
+            qos: config.qos,
+        });
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("systemd-doctor-{}", device));
+        let transport = match config.tls {
+            true => Transport::tls_with_config(tls_config(&config)?),
+            false => Transport::tcp(),
+        };
+        let keep_alive = Duration::from_secs(config.keep_alive.max(5));
+
+        let client = match config.version {
+            MqttVersion::V311 => {
+                let mut options = MqttOptions::new(client_id, &config.host, config.port);
+                options
+                    .set_keep_alive(keep_alive)
+                    .set_transport(transport)
+                    .set_last_will(LastWill::new(
+                        &link.status_topic,
+                        "offline",
+                        qos_v311(config.qos),
+                        true,
+                    ));
+                if let Some(username) = &config.username {
+                    options.set_credentials(username, config.password.clone().unwrap_or_default());
+                }
+                let (client, mut connection) = rumqttc::Client::new(options, CHANNEL_CAPACITY);
+                let thread_client = Client::V311(client.clone());
+                let thread_link = link.clone();
+                thread::spawn(move || {
+                    for notification in connection.iter() {
+                        let notice = match notification {
+                            Ok(Event::Incoming(Packet::ConnAck(_))) => Notice::Connected,
+                            Ok(Event::Outgoing(Outgoing::Disconnect)) => Notice::Disconnected,
+                            Ok(_) => Notice::Other,
+                            Err(e) => Notice::Failed(e.to_string()),
+                        };
+                        if !thread_link.on(notice, &thread_client) {
+                            break;
+                        }
+                    }
+                });
+                Client::V311(client)
+            }
+            MqttVersion::V5 => {
+                let mut options =
+                    rumqttc::v5::MqttOptions::new(client_id, &config.host, config.port);
+                options
+                    .set_keep_alive(keep_alive)
+                    .set_transport(transport)
+                    .set_last_will(LastWill5::new(
+                        &link.status_topic,
+                        "offline",
+                        qos_v5(config.qos),
+                        true,
+                        None,
+                    ));
+                if let Some(username) = &config.username {
+                    options.set_credentials(username, config.password.clone().unwrap_or_default());
+                }
+                let (client, mut connection) = rumqttc::v5::Client::new(options, CHANNEL_CAPACITY);
+                let thread_client = Client::V5(client.clone());
+                let thread_link = link.clone();
+                thread::spawn(move || {
+                    for notification in connection.iter() {
+                        let notice = match notification {
+                            Ok(rumqttc::v5::Event::Incoming(Packet5::ConnAck(_))) => {
+                                Notice::Connected
+                            }
+                            Ok(rumqttc::v5::Event::Outgoing(Outgoing::Disconnect)) => {
+                                Notice::Disconnected
+                            }
+                            Ok(_) => Notice::Other,
+                            Err(e) => Notice::Failed(e.to_string()),
+                        };
+                        if !thread_link.on(notice, &thread_client) {
+                            break;
+                        }
+                    }
+                });
+                Client::V5(client)
+            }
+        };
+
+        Ok(Self {
+            config,
+            device,
+            client,
+            link,
+            state,
+            queue: VecDeque::new(),
+            dropping: false,
+            last_snapshot: None,
+        })
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}/{}", self.config.prefix, self.device, name)
+    }
+
+    fn enqueue(&mut self, topic: String, mut payload: serde_json::Value) {
+        payload["device"] = self.device.clone().into();
+        if self.queue.len() >= self.config.queue_size.max(1) {
+            self.queue.pop_front();
+            if !self.dropping {
+                warn!("MQTT queue full, dropping the oldest messages");
+                self.dropping = true;
+            }
+        }
+        self.queue
+            .push_back((topic, payload.to_string().into_bytes()));
+    }
+
+    // hands the queue to rumqttc while connected, what it cannot take yet stays queued
+    fn send_queued(&mut self) {
+        while self.link.connected.load(Ordering::SeqCst) {
+            let Some((topic, payload)) = self.queue.front() else {
+                self.dropping = false;
+                break;
+            };
+            if self
+                .client
+                .try_publish(topic, self.config.qos, false, payload.clone())
+                .is_err()
+            {
+                break;
+            }
+            self.queue.pop_front();
+        }
+    }
+}
+
+// CA bundle and optional client certificate of the broker connection
+fn tls_config(config: &MqttConfig) -> io::Result<TlsConfiguration> {
+    let read = |path: &std::path::Path| {
+        fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    };
+    let ca = read(
+        config
+            .ca_file
+            .as_deref()
+            .unwrap_or(DEFAULT_CA_FILE.as_ref()),
+    )?;
+    let client_auth = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mqtt client_cert and client_key go together",
+            ))
+        }
+    };
+    Ok(TlsConfiguration::Simple {
+        ca,
+        alpn: None,
+        client_auth,
+    })
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Sample(_) => {
+                let interval = Duration::from_secs(self.config.snapshot_interval.max(1));
+                if self
+                    .last_snapshot
+                    .is_none_or(|last| last.elapsed() >= interval)
+                {
+                    self.last_snapshot = Some(Instant::now());
+                    let mut snapshot = self.state.snapshot().to_json();
+                    snapshot["time"] = Local::now().to_rfc3339().into();
+                    self.enqueue(self.topic("metrics"), snapshot);
+                }
+            }
+            // muted events are published too, muting only holds back hooks and remediation
+            Message::Event(event) => self.enqueue(self.topic("events"), event.to_json()),
+        }
+        self.send_queued();
+        Ok(())
+    }
+
+    // announces the doctor offline, the Last Will is only sent when it disappears
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_queued();
+        self.link.stopping.store(true, Ordering::SeqCst);
+        if self.link.connected.load(Ordering::SeqCst) {
+            let status = self.link.status_topic.clone();
+            let _ = self
+                .client
+                .try_publish(&status, self.config.qos, true, b"offline".to_vec());
+        }
+        self.client.disconnect().map_err(io::Error::other)?;
+        let start = Instant::now();
+        while self.link.connected.load(Ordering::SeqCst) && start.elapsed() < DISCONNECT_TIMEOUT {
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::event::{EventKind, HealthEvent};
+    use crate::mqtt::MqttSink;
+    use crate::sample::Message;
+    use crate::sink::Sink;
+    use crate::state_store::StateStore;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // minimal MQTT 3.1.1 broker: acknowledges CONNECT and PUBLISH, reports (topic, retain, payload)
+    fn broker(listener: TcpListener, published: Sender<(String, bool, String)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let read_packet = |stream: &mut TcpStream| -> Option<(u8, Vec<u8>)> {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).ok()?;
+            let header = byte[0];
+            let (mut length, mut shift) = (0usize, 0);
+            loop {
+                stream.read_exact(&mut byte).ok()?;
+                length |= ((byte[0] & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).ok()?;
+            Some((header, body))
+        };
+        while let Some((header, body)) = read_packet(&mut stream) {
+            match header >> 4 {
+                1 => stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap(),
+                3 => {
+                    let qos = (header >> 1) & 0x03;
+                    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).into_owned();
+                    let mut offset = 2 + topic_len;
+                    if qos > 0 {
+                        stream
+                            .write_all(&[0x40, 0x02, body[offset], body[offset + 1]])
+                            .unwrap();
+                        offset += 2;
+                    }
+                    let payload = String::from_utf8_lossy(&body[offset..]).into_owned();
+                    let _ = published.send((topic, header & 0x01 == 1, payload));
+                }
+                12 => stream.write_all(&[0xd0, 0x00]).unwrap(),
+                _ => break,
+            }
+        }
+    }
+
+    #[test]
+    fn test_publishes_status_and_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.mqtt.port = listener.local_addr().unwrap().port();
+        config.mqtt.host = "127.0.0.1".to_string();
+        config.mqtt.device = Some("dev1".to_string());
+        let (sender, published) = mpsc::channel();
+        thread::spawn(move || broker(listener, sender));
+
+        let mut sink = MqttSink::new(&config, Arc::new(StateStore::new())).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            published.recv_timeout(timeout).unwrap(),
+            (
+                "systemd-doctor/dev1/status".to_string(),
+                true,
+                "online".to_string()
+            )
+        );
+
+        let event = HealthEvent::new(Some("ota"), EventKind::Breach, "cpu 95.0".to_string());
+        sink.handle(&Message::Event(event)).unwrap();
+        let (topic, retain, payload) = published.recv_timeout(timeout).unwrap();
+        assert_eq!(
+            (topic.as_str(), retain),
+            ("systemd-doctor/dev1/events", false)
+        );
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["event"], "breach");
+        assert_eq!(payload["device"], "dev1");
+
+        sink.flush().unwrap();
+        assert_eq!(
+            published.recv_timeout(timeout).unwrap(),
+            (
+                "systemd-doctor/dev1/status".to_string(),
+                true,
+                "offline".to_string()
+            )
+        );
+    }
+}
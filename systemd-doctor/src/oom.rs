@@ -0,0 +1,184 @@
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::event::{EventKind, HealthEvent};
+use crate::sample::Metric;
+use crate::shutdown::Shutdown;
+use crate::sink::Publisher;
+use crate::unit_state::{unit_name, UnitReader, UnitWatch};
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// how often queued inotify events and the shutdown flag are looked at
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// `oom_kill` counter of a cgroup v2 memory.events file.
+pub fn parse_oom_kills(memory_events: &str) -> Option<u64> {
+    memory_events.lines().find_map(|line| {
+        let (key, value) = line.split_once(' ')?;
+        if key == "oom_kill" {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+// ControlGroup as reported by systemd, e.g. /system.slice/ota.service
+pub fn memory_events_path(control_group: &str) -> PathBuf {
+    Path::new(CGROUP_ROOT)
+        .join(control_group.trim_start_matches('/'))
+        .join("memory.events")
+}
+
+/// Turns oom_kill counters into events, the first count seen for a service is its baseline.
+#[derive(Default)]
+pub struct OomWatch {
+    kills: HashMap<String, u64>,
+}
+
+impl OomWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, service: &str, kills: u64) -> Option<HealthEvent> {
+        let last = self.kills.insert(service.to_string(), kills)?;
+        // a lower count is a new cgroup, i.e. the unit was restarted
+        if kills <= last {
+            return None;
+        }
+        Some(HealthEvent::new(
+            Some(service),
+            EventKind::OomKill,
+            format!(
+                "{}: {} process(es) killed by the OOM killer (oom_kill={})",
+                unit_name(service),
+                kills - last,
+                kills
+            ),
+        ))
+    }
+
+    fn check(&mut self, service: &str, path: &Path) -> Option<HealthEvent> {
+        let kills = parse_oom_kills(&fs::read_to_string(path).ok()?)?;
+        self.update(service, kills)
+    }
+}
+
+/*
+The kernel counts the OOM kills of a cgroup in its memory.events and signals every change of the
+file, so a kill is reported as it happens, also when only a child process of the unit was killed
+and the unit itself keeps running. A unit killed as a whole also ends with Result=oom-kill, the
+unit watch reports whichever of the two it sees first, by then the cgroup may already be gone.
+Cgroups are looked up again every `unit` interval since a restarted unit gets a new one.
+*/
+pub fn spawn_oom_watcher(
+    config: Arc<Config>,
+    publisher: Publisher,
+    shutdown: Shutdown,
+    unit_watch: Arc<Mutex<UnitWatch>>,
+) -> io::Result<JoinHandle<()>> {
+    let mut inotify = Inotify::init()?;
+    Ok(thread::spawn(move || {
+        let services = config.services.list.clone().unwrap_or_default();
+        let rescan_interval = config.metric_interval(Metric::Unit);
+        let mut reader = UnitReader::new();
+        let mut oom_watch = OomWatch::new();
+        let mut watches: HashMap<WatchDescriptor, (String, PathBuf)> = HashMap::new();
+        let mut next_rescan = Instant::now();
+        let mut buffer = [0; 4096];
+
+        while !shutdown.wait_timeout(POLL_INTERVAL) {
+            let mut events = Vec::new();
+
+            if Instant::now() >= next_rescan {
+                for service in &services {
+                    if watches.values().any(|(watched, _)| watched == service) {
+                        continue;
+                    }
+                    let Ok(state) = reader.unit_state(&unit_name(service)) else {
+                        continue;
+                    };
+                    // empty while the unit is not running
+                    if state.control_group.is_empty() {
+                        continue;
+                    }
+                    let path = memory_events_path(&state.control_group);
+                    // fails without the memory controller, retried on the next rescan
+                    if let Ok(wd) = inotify.watches().add(&path, WatchMask::MODIFY) {
+                        events.extend(oom_watch.check(service, &path));
+                        watches.insert(wd, (service.clone(), path));
+                    }
+                }
+                next_rescan = Instant::now() + rescan_interval;
+            }
+
+            match inotify.read_events(&mut buffer) {
+                Ok(changes) => {
+                    for change in changes {
+                        if change.mask.contains(EventMask::IGNORED) {
+                            // the cgroup was removed
+                            watches.remove(&change.wd);
+                        } else if let Some((service, path)) = watches.get(&change.wd) {
+                            events.extend(oom_watch.check(service, path));
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    warn!("Failed to read cgroup events: {}", e);
+                    break;
+                }
+            }
+
+            let now = Instant::now();
+            for event in events {
+                let service = event.service.clone().unwrap_or_default();
+                if unit_watch
+                    .lock()
+                    .unwrap()
+                    .cgroup_oom_kill(&config, &service, now)
+                {
+                    publisher.publish_event(event);
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::EventKind;
+    use crate::oom::{memory_events_path, parse_oom_kills, OomWatch};
+    use std::path::Path;
+
+    #[test]
+    fn test_oom_kill_counter() {
+        let events = "low 0\nhigh 12\nmax 3\noom 2\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kills(events), Some(1));
+        assert_eq!(
+            memory_events_path("/system.slice/can-parser.service"),
+            Path::new("/sys/fs/cgroup/system.slice/can-parser.service/memory.events")
+        );
+
+        let mut watch = OomWatch::new();
+        assert!(watch.update("can-parser", 1).is_none());
+        assert!(watch.update("can-parser", 1).is_none());
+        let event = watch.update("can-parser", 3).unwrap();
+        assert_eq!(event.kind, EventKind::OomKill);
+        assert_eq!(
+            event.message,
+            "can-parser.service: 2 process(es) killed by the OOM killer (oom_kill=3)"
+        );
+        // restarted unit, new cgroup
+        assert!(watch.update("can-parser", 0).is_none());
+    }
+}
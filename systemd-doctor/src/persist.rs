@@ -0,0 +1,188 @@
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::alert::SavedAlert;
+use crate::anomaly::Baseline;
+use crate::config::Config;
+use crate::remediation::SavedLadder;
+
+pub const STATE_FILE: &str = "state.json";
+pub const STATE_VERSION: u32 = 1;
+
+/*
+Directory of the doctor's own state: `[monitor] state_dir`, else the one systemd created for
+StateDirectory= (passed in $STATE_DIRECTORY), else the output directory.
+*/
+pub fn state_dir(config: &Config) -> PathBuf {
+    if let Some(dir) = &config.monitor.state_dir {
+        return dir.clone();
+    }
+    if let Some(dir) = env::var_os("STATE_DIRECTORY") {
+        // several directories are separated by ':', the first one is ours
+        if let Some(first) = dir
+            .to_string_lossy()
+            .split(':')
+            .next()
+            .filter(|d| !d.is_empty())
+        {
+            return PathBuf::from(first);
+        }
+    }
+    config.monitor.output_dir.clone().unwrap_or_default()
+}
+
+/// Replaces `path` with `contents` through a temporary file, so a crash never leaves half a file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Wall clock time of `instant`, as written to the state file.
+pub fn wall_clock(instant: Instant, now: Instant) -> DateTime<Local> {
+    let wall_now = Local::now();
+    match now.checked_duration_since(instant) {
+        Some(age) => wall_now - chrono::Duration::from_std(age).unwrap_or_default(),
+        None => wall_now + chrono::Duration::from_std(instant - now).unwrap_or_default(),
+    }
+}
+
+/// Instant of a time read back from the state file, None when it cannot be represented.
+pub fn instant_at(time: DateTime<Local>, now: Instant) -> Option<Instant> {
+    // a time in the future (the clock was set back) counts as now
+    let age = (Local::now() - time).to_std().unwrap_or_default();
+    now.checked_sub(age)
+}
+
+/*
+What survives a restart of the doctor. Fields this version does not know (written by a newer
+one) are kept as they are, and a field missing from an older file starts empty.
+*/
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PersistedState {
+    pub version: u32,
+    // remediation ladder of each service, see remediation.rs
+    pub remediation: BTreeMap<String, SavedLadder>,
+    // alerts that are not ok
+    pub alerts: Vec<SavedAlert>,
+    // cursor of the last journal entry attached to an event, per unit
+    pub journal_cursors: BTreeMap<String, String>,
+    // service -> metric -> learned baseline, see anomaly.rs
+    pub baselines: BTreeMap<String, BTreeMap<String, Baseline>>,
+    // service -> end of its mute, see control.rs
+    pub mutes: BTreeMap<String, DateTime<Local>>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, serde_json::Value>,
+}
+
+/// The state file, shared by the parts of the doctor that keep state. Every update rewrites it.
+#[derive(Default)]
+pub struct StateFile {
+    // None keeps the state in memory only
+    path: Option<PathBuf>,
+    state: Mutex<PersistedState>,
+}
+
+impl StateFile {
+    /// Reads `path`, an empty state if it does not exist or cannot be parsed.
+    pub fn load(path: PathBuf) -> Self {
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<PersistedState>(&contents) {
+                Ok(state) => {
+                    if state.version > STATE_VERSION {
+                        warn!(
+                            "{} was written by a newer version ({}), reading what is known",
+                            path.display(),
+                            state.version
+                        );
+                    }
+                    state
+                }
+                Err(e) => {
+                    warn!("Failed to parse {}, starting over: {}", path.display(), e);
+                    PersistedState::default()
+                }
+            },
+            Err(_) => PersistedState::default(),
+        };
+        Self {
+            path: Some(path),
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn read<T>(&self, f: impl FnOnce(&PersistedState) -> T) -> T {
+        f(&self.state.lock().unwrap())
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut PersistedState)) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        state.version = state.version.max(STATE_VERSION);
+        write_atomic(path, serde_json::to_string_pretty(&*state)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persist::{StateFile, STATE_VERSION};
+    use std::fs;
+
+    #[test]
+    fn test_state_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        // written by a newer version, with a field this one does not know
+        fs::write(
+            &path,
+            r#"{"version": 7, "journal_cursors": {"ota.service": "s=1"}, "future": [1, 2]}"#,
+        )
+        .unwrap();
+
+        let state_file = StateFile::load(path.clone());
+        assert_eq!(state_file.read(|state| state.journal_cursors.len()), 1);
+        state_file
+            .update(|state| {
+                state
+                    .journal_cursors
+                    .insert("can-parser.service".to_string(), "s=2".to_string());
+            })
+            .unwrap();
+
+        let state_file = StateFile::load(path.clone());
+        state_file.read(|state| {
+            assert_eq!(state.version, 7);
+            assert_eq!(state.journal_cursors["can-parser.service"], "s=2");
+            assert_eq!(state.unknown["future"], serde_json::json!([1, 2]));
+        });
+        assert!(!dir.path().join("state.json.tmp").exists());
+
+        fs::remove_file(&path).unwrap();
+        let state_file = StateFile::load(path.clone());
+        state_file.update(|_| {}).unwrap();
+        assert_eq!(
+            StateFile::load(path).read(|state| state.version),
+            STATE_VERSION
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use chrono::{DateTime, Local, TimeZone};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::cmd_health_check::CmdHealCheck;
+use crate::config::{Config, ProcessConfig};
+use crate::event::EventKind;
+use crate::log::LogWriter;
+use crate::oom::CGROUP_ROOT;
+use crate::sample::{Message, Value};
+use crate::sink::Sink;
+use crate::unit_state::{unit_name, UnitReader};
+
+pub const PROCESSES_HEADER: [&str; 13] = [
+    "Timestamp",
+    "Service",
+    "PID",
+    "Command",
+    "Threads",
+    "State",
+    "RSS(MB)",
+    "PSS(MB)",
+    "USS(MB)",
+    "Swap(MB)",
+    "CPU(%)",
+    "FDs",
+    "Started",
+];
+
+/// Memory of a process from /proc/<pid>/smaps_rollup, in kB, or of several added together.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SmapsRollup {
+    pub rss: u64,
+    pub pss: u64,
+    // private clean + private dirty
+    pub uss: u64,
+    pub swap: u64,
+    pub swap_pss: u64,
+}
+
+impl SmapsRollup {
+    // smaps_rollup holds one entry, smaps one per mapping: both are summed
+    pub fn parse(contents: &str) -> Self {
+        let mut rollup = SmapsRollup::default();
+        for line in contents.lines() {
+            let mut parts = line.split_whitespace();
+            let (Some(key), Some(Ok(kb))) = (parts.next(), parts.next().map(str::parse::<u64>))
+            else {
+                continue;
+            };
+            match key {
+                "Rss:" => rollup.rss += kb,
+                "Pss:" => rollup.pss += kb,
+                "Private_Clean:" | "Private_Dirty:" => rollup.uss += kb,
+                "Swap:" => rollup.swap += kb,
+                "SwapPss:" => rollup.swap_pss += kb,
+                _ => {}
+            }
+        }
+        rollup
+    }
+
+    // kernels before 4.14 have no smaps_rollup, the mappings of smaps are added up instead
+    pub fn read(pid: u32) -> io::Result<Self> {
+        let contents = match fs::read_to_string(format!("/proc/{}/smaps_rollup", pid)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::read_to_string(format!("/proc/{}/smaps", pid))?
+            }
+            contents => contents?,
+        };
+        Ok(Self::parse(&contents))
+    }
+
+    fn add(&mut self, other: &SmapsRollup) {
+        self.rss += other.rss;
+        self.pss += other.pss;
+        self.uss += other.uss;
+        self.swap += other.swap;
+        self.swap_pss += other.swap_pss;
+    }
+}
+
+/// Memory of the processes of a service in kB.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    // VmRSS, readable for every process
+    pub rss: u64,
+    // None when the smaps of one of the processes cannot be read
+    pub smaps: Option<SmapsRollup>,
+}
+
+// the process exited since it was listed
+fn exited(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(libc::ESRCH)
+}
+
+impl MemoryUsage {
+    // processes that exited in between are skipped, also when they go between the two reads
+    pub fn read(pids: &[u32]) -> Result<Self, String> {
+        let mut usage = MemoryUsage {
+            rss: 0,
+            smaps: Some(SmapsRollup::default()),
+        };
+        for pid in pids {
+            let status = match fs::read_to_string(format!("/proc/{}/status", pid)) {
+                Ok(status) => status,
+                Err(e) if exited(&e) => continue,
+                Err(e) => return Err(format!("Failed to read the status of {}: {}", pid, e)),
+            };
+            let smaps = match SmapsRollup::read(*pid) {
+                Err(e) if exited(&e) => continue,
+                smaps => smaps.ok(),
+            };
+            usage.rss += status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|value| value.split_whitespace().next())
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(0);
+            usage.smaps = match (usage.smaps.take(), smaps) {
+                (Some(mut total), Some(smaps)) => {
+                    total.add(&smaps);
+                    Some(total)
+                }
+                _ => None,
+            };
+        }
+        Ok(usage)
+    }
+}
+
+/// What /proc tells about one process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    // the command line, [name] for processes without one
+    pub command: String,
+    pub threads: u32,
+    // R running, S sleeping, D disk sleep, Z zombie, ...
+    pub state: String,
+    pub rss_kb: u64,
+    // None when smaps_rollup cannot be read (other user, old kernel)
+    pub smaps: Option<SmapsRollup>,
+    // user + system time in clock ticks
+    pub cpu_ticks: u64,
+    pub fds: Option<usize>,
+    pub started_at: Option<DateTime<Local>>,
+}
+
+// (state, utime + stime, starttime) from /proc/<pid>/stat, the name may hold spaces and parentheses
+fn parse_stat(stat: &str) -> Option<(String, u64, u64)> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let number = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    Some((
+        fields.first()?.to_string(),
+        number(11)? + number(12)?,
+        number(19)?,
+    ))
+}
+
+fn clock_ticks() -> u64 {
+    // SAFETY: sysconf has no preconditions
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 {
+        ticks as u64
+    } else {
+        100
+    }
+}
+
+// boot time in seconds since the epoch, the btime line of /proc/stat
+fn boot_time() -> Option<i64> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    stat.lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+impl ProcessInfo {
+    pub fn read(pid: u32) -> io::Result<Self> {
+        let dir = Path::new("/proc").join(pid.to_string());
+        let stat = fs::read_to_string(dir.join("stat"))?;
+        let (state, cpu_ticks, start_ticks) = parse_stat(&stat).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid stat of {}", pid),
+            )
+        })?;
+
+        let status = fs::read_to_string(dir.join("status"))?;
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|value| value.split_whitespace().next())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        let threads = field("Threads:").unwrap_or(1) as u32;
+        // kernel threads have no VmRSS
+        let rss_kb = field("VmRSS:").unwrap_or(0);
+
+        let cmdline = fs::read(dir.join("cmdline")).unwrap_or_default();
+        let command = match cmdline.is_empty() {
+            true => {
+                let name = stat
+                    .split_once('(')
+                    .and_then(|(_, rest)| rest.rsplit_once(')'))
+                    .map_or("?", |(name, _)| name);
+                format!("[{}]", name)
+            }
+            false => String::from_utf8_lossy(&cmdline)
+                .trim_end_matches('\0')
+                .replace('\0', " "),
+        };
+
+        let started_at = boot_time().and_then(|boot| {
+            let seconds = boot + (start_ticks / clock_ticks()) as i64;
+            Local.timestamp_opt(seconds, 0).single()
+        });
+
+        Ok(Self {
+            pid,
+            command,
+            threads,
+            state,
+            rss_kb,
+            smaps: SmapsRollup::read(pid).ok(),
+            cpu_ticks,
+            fds: fs::read_dir(dir.join("fd")).ok().map(Iterator::count),
+            started_at,
+        })
+    }
+}
+
+// kB to MB, truncated to one decimal
+pub fn mb(kb: u64) -> Value {
+    Value::Number((kb as f64 / 1024.0 * 10.0).trunc() / 10.0)
+}
+
+/*
+Processes of a service: those of its cgroup, so workers with a name of their own are found,
+else the ones `pgrep` matches like the memory and cpu collectors.
+*/
+pub fn service_pids(unit_reader: &mut UnitReader, service: &str) -> Result<Vec<u32>, String> {
+    let control_group = unit_reader
+        .unit_state(&unit_name(service))
+        .map(|state| state.control_group)
+        .unwrap_or_default();
+    if !control_group.is_empty() {
+        let procs = Path::new(CGROUP_ROOT)
+            .join(control_group.trim_start_matches('/'))
+            .join("cgroup.procs");
+        if let Ok(contents) = fs::read_to_string(procs) {
+            let pids: Vec<u32> = contents
+                .lines()
+                .filter_map(|pid| pid.trim().parse().ok())
+                .collect();
+            if !pids.is_empty() {
+                return Ok(pids);
+            }
+        }
+    }
+    CmdHealCheck::new().cmd_find_pids(service)
+}
+
+/*
+Writes the processes of every service to DrViet_processes.csv every `[processes] interval`,
+and those of a service right away when it breaches a threshold, leaks or turns anomalous, to
+tell which worker is growing. The sink only uses the samples and events to know when to look.
+*/
+pub struct ProcessSink {
+    config: ProcessConfig,
+    services: Vec<String>,
+    log: LogWriter,
+    unit_reader: UnitReader,
+    // cpu ticks of each pid at the previous table, for the cpu usage in between
+    ticks: HashMap<u32, (u64, Instant)>,
+    last_table: Option<Instant>,
+}
+
+impl ProcessSink {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let log = LogWriter::open(config.monitor.output_dir.as_deref(), "DrViet_processes.csv")?;
+        Ok(Self {
+            config: config.processes.clone(),
+            services: config.services.list.clone().unwrap_or_default(),
+            log,
+            unit_reader: UnitReader::new(),
+            ticks: HashMap::new(),
+            last_table: None,
+        })
+    }
+
+    // cpu usage since the previous table, since the start of the process the first time
+    fn cpu_percent(&mut self, process: &ProcessInfo, now: Instant) -> Value {
+        let ticks_per_second = clock_ticks() as f64;
+        let percent = match self.ticks.insert(process.pid, (process.cpu_ticks, now)) {
+            Some((ticks, at)) if process.cpu_ticks >= ticks => {
+                let elapsed = now.duration_since(at).as_secs_f64();
+                (elapsed > 0.0)
+                    .then(|| (process.cpu_ticks - ticks) as f64 / ticks_per_second / elapsed)
+            }
+            _ => process.started_at.and_then(|started_at| {
+                let elapsed = (Local::now() - started_at).num_milliseconds() as f64 / 1000.0;
+                (elapsed > 0.0).then(|| process.cpu_ticks as f64 / ticks_per_second / elapsed)
+            }),
+        };
+        match percent {
+            Some(percent) => Value::Number((percent * 1000.0).round() / 10.0),
+            None => Value::Missing,
+        }
+    }
+
+    fn write_service(&mut self, service: &str) -> io::Result<()> {
+        let pids = match service_pids(&mut self.unit_reader, service) {
+            Ok(pids) => pids,
+            // not running, nothing to detail
+            Err(_) => return Ok(()),
+        };
+        let header: Vec<String> = PROCESSES_HEADER.iter().map(|c| c.to_string()).collect();
+        self.log.write_header_if_empty(&header)?;
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let now = Instant::now();
+        for pid in pids {
+            // the process may have exited in between
+            let Ok(process) = ProcessInfo::read(pid) else {
+                continue;
+            };
+            let smaps = process.smaps.as_ref();
+            let cells = [
+                timestamp.clone(),
+                service.to_string(),
+                pid.to_string(),
+                process.command.clone(),
+                process.threads.to_string(),
+                process.state.clone(),
+                mb(process.rss_kb).to_string(),
+                smaps
+                    .map_or(Value::Missing, |smaps| mb(smaps.pss))
+                    .to_string(),
+                smaps
+                    .map_or(Value::Missing, |smaps| mb(smaps.uss))
+                    .to_string(),
+                smaps
+                    .map_or(Value::Missing, |smaps| mb(smaps.swap))
+                    .to_string(),
+                self.cpu_percent(&process, now).to_string(),
+                process
+                    .fds
+                    .map_or(Value::Missing, |fds| Value::Number(fds as f64))
+                    .to_string(),
+                process.started_at.map_or("N/A".to_string(), |started_at| {
+                    started_at.format("%Y-%m-%d %H:%M:%S").to_string()
+                }),
+            ];
+            let record: Vec<&str> = cells.iter().map(String::as_str).collect();
+            self.log.write_record(&record)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for ProcessSink {
+    fn name(&self) -> &str {
+        "processes"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Sample(_) => {
+                let interval = Duration::from_secs(self.config.interval);
+                let due = self
+                    .last_table
+                    .is_none_or(|last| last.elapsed() >= interval);
+                if self.config.interval == 0 || !due {
+                    return Ok(());
+                }
+                let start = Instant::now();
+                self.last_table = Some(start);
+                for service in self.services.clone() {
+                    self.write_service(&service)?;
+                }
+                // exited processes are forgotten
+                self.ticks.retain(|_, (_, at)| *at >= start);
+                Ok(())
+            }
+            Message::Event(event) => {
+                let breach = matches!(
+                    event.kind,
+                    EventKind::Breach | EventKind::LeakSuspected | EventKind::Anomaly
+                );
+                match &event.service {
+                    Some(service) if breach && self.config.on_breach => self.write_service(service),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.log.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processes::{parse_stat, MemoryUsage, ProcessInfo, SmapsRollup};
+
+    #[test]
+    fn test_parse_proc_files() {
+        let stat = "812 (mqtt (worker) 2) S 1 812 812 0 -1 4194560 1000 0 0 0 150 50 0 0 20 0 \
+                    3 0 4200 1000000 500 18446744073709551615";
+        assert_eq!(parse_stat(stat), Some(("S".to_string(), 200, 4200)));
+
+        let rollup = SmapsRollup::parse(
+            "55d0c0a00000-7ffd3e5f2000 ---p 00000000 00:00 0    [rollup]\n\
+             Rss:                5120 kB\n\
+             Pss:                2048 kB\n\
+             Shared_Clean:       3072 kB\n\
+             Private_Clean:       512 kB\n\
+             Private_Dirty:      1024 kB\n\
+             Swap:                 64 kB\n\
+             SwapPss:              32 kB\n",
+        );
+        assert_eq!(
+            rollup,
+            SmapsRollup {
+                rss: 5120,
+                pss: 2048,
+                uss: 1536,
+                swap: 64,
+                swap_pss: 32,
+            }
+        );
+
+        let own = ProcessInfo::read(std::process::id()).unwrap();
+        assert!(own.threads >= 1);
+        assert!(own.rss_kb > 0);
+        assert!(own.command.contains("systemd_doctor"));
+    }
+
+    #[test]
+    fn test_memory_measures() {
+        // smaps of kernels without smaps_rollup, one entry per mapping
+        let smaps = SmapsRollup::parse(
+            "5581a1c00000-5581a1c21000 r--p 00000000 fd:00 1050 /usr/bin/ota\n\
+             Rss:                 132 kB\n\
+             Pss:                  40 kB\n\
+             Private_Clean:        12 kB\n\
+             Private_Dirty:         0 kB\n\
+             7f2b4c000000-7f2b4c021000 rw-p 00000000 00:00 0\n\
+             Rss:                 100 kB\n\
+             Pss:                 100 kB\n\
+             Private_Dirty:       100 kB\n\
+             Swap:                  8 kB\n",
+        );
+        assert_eq!(
+            (smaps.rss, smaps.pss, smaps.uss, smaps.swap),
+            (232, 140, 112, 8)
+        );
+
+        // a pid that exited does not take the smaps of the others with it
+        let own = MemoryUsage::read(&[std::process::id(), 999_999_999]).unwrap();
+        assert!(own.rss > 0);
+        assert!(own.smaps.unwrap().pss > 0);
+    }
+}
@@ -0,0 +1,444 @@
+use chrono::{DateTime, Local};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, Remediation, RemediationAction, RemediationStep};
+use crate::event::{EventKind, HealthEvent};
+use crate::persist::{instant_at, wall_clock, StateFile};
+use crate::sample::Message;
+use crate::sink::{Sink, WeakPublisher};
+use crate::systemd_dbus::SystemdClient;
+use crate::unit_state::unit_name;
+
+pub const DEFAULT_HEALTHY_PERIOD: u64 = 3600;
+pub const DEFAULT_ISOLATE_TARGET: &str = "rescue.target";
+// failure events of one incident (e.g. unit_failed and oom_kill) only take one step
+const INCIDENT_WINDOW: Duration = Duration::from_secs(10);
+
+impl RemediationAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RemediationAction::Log => "log",
+            RemediationAction::Reload => "reload",
+            RemediationAction::Restart => "restart",
+            RemediationAction::RestartDependencies => "restart-dependencies",
+            RemediationAction::Isolate => "isolate",
+            RemediationAction::Reboot => "reboot",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Ladder {
+    rung: usize,
+    // times the current step was taken
+    attempts: VecDeque<Instant>,
+    last_failure: Option<Instant>,
+}
+
+/// A ladder as written to the state file, so restart budgets hold across restarts of the doctor.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SavedLadder {
+    pub rung: usize,
+    pub attempts: Vec<DateTime<Local>>,
+    pub last_failure: Option<DateTime<Local>>,
+}
+
+/// Step to take for a failure and where it is on the ladder.
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub step: RemediationStep,
+    pub rung: usize,
+    pub attempt: usize,
+}
+
+/*
+Tracks the rung each service is on. A failure takes the current step as long as it was taken
+fewer than `count` times within its `window`, otherwise the ladder moves up. Once the last step
+is used up failures are only reported. After `healthy_period` without a failure the ladder
+starts over.
+*/
+#[derive(Default)]
+pub struct Remediator {
+    ladders: HashMap<String, Ladder>,
+}
+
+impl Remediator {
+    /// Continues the ladders saved by a previous run.
+    pub fn restore(saved: &BTreeMap<String, SavedLadder>, now: Instant) -> Self {
+        let instant = |time| instant_at(time, now);
+        let ladders = saved
+            .iter()
+            .map(|(service, ladder)| {
+                let ladder = Ladder {
+                    rung: ladder.rung,
+                    attempts: ladder
+                        .attempts
+                        .iter()
+                        .copied()
+                        .filter_map(instant)
+                        .collect(),
+                    last_failure: ladder.last_failure.and_then(instant),
+                };
+                (service.clone(), ladder)
+            })
+            .collect();
+        Self { ladders }
+    }
+
+    pub fn export(&self, now: Instant) -> BTreeMap<String, SavedLadder> {
+        let wall = |time| wall_clock(time, now);
+        self.ladders
+            .iter()
+            .map(|(service, ladder)| {
+                let saved = SavedLadder {
+                    rung: ladder.rung,
+                    attempts: ladder.attempts.iter().copied().map(wall).collect(),
+                    last_failure: ladder.last_failure.map(wall),
+                };
+                (service.clone(), saved)
+            })
+            .collect()
+    }
+
+    /// None when the failure belongs to the previous incident or the ladder is used up.
+    pub fn on_failure(
+        &mut self,
+        service: &str,
+        policy: &Remediation,
+        now: Instant,
+    ) -> Option<Decision> {
+        let ladder = self.ladders.entry(service.to_string()).or_default();
+        let healthy_period =
+            Duration::from_secs(policy.healthy_period.unwrap_or(DEFAULT_HEALTHY_PERIOD));
+        if let Some(last_failure) = ladder.last_failure {
+            let since = now.duration_since(last_failure);
+            if since < INCIDENT_WINDOW {
+                return None;
+            }
+            if since >= healthy_period {
+                *ladder = Ladder::default();
+            }
+        }
+        ladder.last_failure = Some(now);
+
+        while let Some(step) = policy.steps.get(ladder.rung) {
+            if let Some(window) = step.window {
+                let window = Duration::from_secs(window);
+                while ladder
+                    .attempts
+                    .front()
+                    .is_some_and(|attempt| now.duration_since(*attempt) > window)
+                {
+                    ladder.attempts.pop_front();
+                }
+            }
+            if ladder.attempts.len() < step.count.unwrap_or(1) as usize {
+                ladder.attempts.push_back(now);
+                return Some(Decision {
+                    step: step.clone(),
+                    rung: ladder.rung,
+                    attempt: ladder.attempts.len(),
+                });
+            }
+            if ladder.rung + 1 == policy.steps.len() {
+                return None;
+            }
+            ladder.rung += 1;
+            ladder.attempts.clear();
+        }
+        None
+    }
+}
+
+// failures that call for remediation: unit failures, alerts firing and anomalies, status
+// mismatches and flapping are only reported, as are device-wide alerts (disk, temperature)
+fn is_failure(kind: EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::UnitFailed | EventKind::OomKill | EventKind::Breach | EventKind::Anomaly
+    )
+}
+
+pub fn systemctl(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("systemctl")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute command: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to execute command: {}", stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/*
+Runs the remediation policy of a service on its failure events. Actions go through D-Bus, or
+systemctl while the system bus is unavailable, and are reported as `remediation` events through
+`outbox`, which is set once the publisher exists. The ladders are saved in the state file after
+every failure.
+*/
+pub struct RemediationSink {
+    config: Arc<Config>,
+    remediator: Remediator,
+    systemd: Option<SystemdClient>,
+    outbox: Arc<OnceLock<WeakPublisher>>,
+    state_file: Arc<StateFile>,
+}
+
+impl RemediationSink {
+    pub fn new(
+        config: Arc<Config>,
+        outbox: Arc<OnceLock<WeakPublisher>>,
+        state_file: Arc<StateFile>,
+    ) -> Self {
+        let remediator =
+            state_file.read(|state| Remediator::restore(&state.remediation, Instant::now()));
+        Self {
+            config,
+            remediator,
+            systemd: None,
+            outbox,
+            state_file,
+        }
+    }
+
+    fn policy(&self, service: &str) -> Option<&Remediation> {
+        self.config
+            .thresholds
+            .get(service)
+            .and_then(|threshold| threshold.remediation.as_ref())
+            .filter(|policy| !policy.steps.is_empty())
+    }
+
+    fn execute(&mut self, unit: &str, step: &RemediationStep) -> Result<(), String> {
+        if step.action == RemediationAction::Log {
+            return Ok(());
+        }
+        if self.systemd.is_none() {
+            self.systemd = SystemdClient::system().ok();
+        }
+        let target = step.target.as_deref().unwrap_or(DEFAULT_ISOLATE_TARGET);
+        match &self.systemd {
+            Some(client) => {
+                Self::execute_dbus(client, unit, step.action, target).map_err(|e| e.to_string())
+            }
+            None => Self::execute_systemctl(unit, step.action, target),
+        }
+    }
+
+    fn execute_dbus(
+        client: &SystemdClient,
+        unit: &str,
+        action: RemediationAction,
+        target: &str,
+    ) -> zbus::Result<()> {
+        match action {
+            RemediationAction::Log => {}
+            RemediationAction::Reload => {
+                client.reload_unit(unit)?;
+            }
+            RemediationAction::Restart => {
+                client.restart_unit(unit)?;
+            }
+            RemediationAction::RestartDependencies => {
+                // only services, restarting the slice or sysinit.target would take everything down
+                for dependency in client.requires(unit)? {
+                    if dependency.ends_with(".service") {
+                        client.restart_unit(&dependency)?;
+                    }
+                }
+                client.restart_unit(unit)?;
+            }
+            RemediationAction::Isolate => {
+                client.isolate(target)?;
+            }
+            RemediationAction::Reboot => {
+                client.reboot()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_systemctl(
+        unit: &str,
+        action: RemediationAction,
+        target: &str,
+    ) -> Result<(), String> {
+        match action {
+            RemediationAction::Log => {}
+            RemediationAction::Reload => {
+                systemctl(&["reload", unit])?;
+            }
+            RemediationAction::Restart => {
+                systemctl(&["restart", unit])?;
+            }
+            RemediationAction::RestartDependencies => {
+                let requires = systemctl(&["show", "--property=Requires", "--value", unit])?;
+                for dependency in requires.split_whitespace() {
+                    if dependency.ends_with(".service") {
+                        systemctl(&["restart", dependency])?;
+                    }
+                }
+                systemctl(&["restart", unit])?;
+            }
+            RemediationAction::Isolate => {
+                systemctl(&["isolate", target])?;
+            }
+            RemediationAction::Reboot => {
+                systemctl(&["reboot"])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self, service: &str, kind: EventKind, message: String) {
+        info!("Remediation for {}: {}", service, message);
+        if let Some(publisher) = self.outbox.get().and_then(WeakPublisher::upgrade) {
+            publisher.publish_event(HealthEvent::new(Some(service), kind, message));
+        }
+    }
+}
+
+impl Sink for RemediationSink {
+    fn name(&self) -> &str {
+        "remediation"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        let Message::Event(event) = message else {
+            return Ok(());
+        };
+        let Some(service) = event
+            .service
+            .as_deref()
+            .filter(|_| is_failure(event.kind) && !event.muted)
+        else {
+            return Ok(());
+        };
+        let Some(policy) = self.policy(service).cloned() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let decision = self.remediator.on_failure(service, &policy, now);
+        let ladders = self.remediator.export(now);
+        if let Err(e) = self.state_file.update(|state| state.remediation = ladders) {
+            error!("Failed to save the remediation state: {}", e);
+        }
+        let Some(decision) = decision else {
+            return Ok(());
+        };
+
+        let unit = unit_name(service);
+        let action = decision.step.action;
+        let description = format!(
+            "{} {} (step {}/{}, attempt {}/{})",
+            action.name(),
+            if action == RemediationAction::Isolate {
+                decision
+                    .step
+                    .target
+                    .as_deref()
+                    .unwrap_or(DEFAULT_ISOLATE_TARGET)
+            } else {
+                &unit
+            },
+            decision.rung + 1,
+            policy.steps.len(),
+            decision.attempt,
+            decision.step.count.unwrap_or(1)
+        );
+        match self.execute(&unit, &decision.step) {
+            Ok(()) => {
+                self.report(service, EventKind::Remediation, description);
+                if matches!(
+                    action,
+                    RemediationAction::Restart | RemediationAction::RestartDependencies
+                ) {
+                    self.report(
+                        service,
+                        EventKind::Restarted,
+                        format!("restarted by remediation ({})", action.name()),
+                    );
+                }
+            }
+            Err(e) => self.report(
+                service,
+                EventKind::Remediation,
+                format!("{} failed: {}", description, e),
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Remediation, RemediationAction};
+    use crate::remediation::Remediator;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_escalation_ladder() {
+        // restart twice, then reboot, then go to safe mode
+        let policy: Remediation = toml::from_str(
+            r#"
+            healthy_period = 1800
+            steps = [
+                { action = "restart", count = 2, window = 600 },
+                { action = "reboot" },
+                { action = "isolate", target = "safe-mode.target" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let mut remediator = Remediator::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut action = |secs| {
+            remediator
+                .on_failure("can-parser", &policy, at(secs))
+                .map(|decision| decision.step.action)
+        };
+
+        assert_eq!(action(0), Some(RemediationAction::Restart));
+        // same incident
+        assert_eq!(action(2), None);
+        assert_eq!(action(60), Some(RemediationAction::Restart));
+        assert_eq!(action(120), Some(RemediationAction::Reboot));
+        assert_eq!(action(300), Some(RemediationAction::Isolate));
+        // ladder used up
+        assert_eq!(action(400), None);
+        // healthy for long enough, start over
+        assert_eq!(action(2400), Some(RemediationAction::Restart));
+        assert_eq!(action(2600), Some(RemediationAction::Restart));
+        // the first restart left the window, one more is allowed
+        assert_eq!(action(3050), Some(RemediationAction::Restart));
+        assert_eq!(action(3100), Some(RemediationAction::Reboot));
+    }
+
+    #[test]
+    fn test_ladder_survives_restart() {
+        let policy: Remediation =
+            toml::from_str(r#"steps = [{ action = "restart", count = 2, window = 3600 }]"#)
+                .unwrap();
+        let mut remediator = Remediator::default();
+        let now = Instant::now();
+        let early = now - Duration::from_secs(120);
+        assert!(remediator.on_failure("ota", &policy, early).is_some());
+        assert!(remediator.on_failure("ota", &policy, now).is_some());
+
+        // the restart budget is used up for the hour, also for the next run
+        let saved = remediator.export(now);
+        let mut remediator = Remediator::restore(&saved, now);
+        assert!(remediator
+            .on_failure("ota", &policy, now + Duration::from_secs(60))
+            .is_none());
+    }
+}
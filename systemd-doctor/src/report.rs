@@ -0,0 +1,648 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime, TimeZone};
+use flate2::read::MultiGzDecoder;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::collector::MEMINFO_COLUMNS;
+use crate::config::Config;
+use crate::sample::Metric;
+use crate::tsdb::{series_key, store_dir, Point, TimeSeriesStore};
+use crate::unit_state::UNIT_FIELDS;
+
+// metrics read from the history, unit state is text
+pub const HISTORY_METRICS: [Metric; 4] = [
+    Metric::Memory,
+    Metric::Cpu,
+    Metric::Disk,
+    Metric::Temperature,
+];
+
+// a pause of more than this many typical sampling intervals is a gap
+const GAP_FACTOR: i64 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+    Markdown,
+}
+
+/// Parses an age or duration such as `90s`, `10m`, `24h` or `7d`.
+pub fn parse_age(text: &str) -> Option<ChronoDuration> {
+    let text = text.trim();
+    let unit = text.chars().last().filter(|unit| "smhd".contains(*unit))?;
+    let amount = text[..text.len() - 1].parse::<i64>().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        _ => 86400,
+    };
+    Some(ChronoDuration::seconds(amount * seconds))
+}
+
+/// Parses a report bound: `2024-06-03 08:00[:00]`, `2024-06-03`, or an age such as `90m`, `24h`, `7d`.
+pub fn parse_time(text: &str, now: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    let text = text.trim();
+    if let Some(age) = parse_age(text) {
+        return Ok(now - age);
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| {
+            format!(
+                "Invalid time '{}', expected e.g. 2024-06-03 08:00 or 24h",
+                text
+            )
+        })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("Invalid local time '{}'", text))
+}
+
+/// `DrViet_<name>.csv` and its rotated copies (`.1`, `.2.gz`, `-20240603.gz`).
+pub fn csv_files(dir: &Path, name: &str) -> io::Result<Vec<PathBuf>> {
+    let prefix = format!("DrViet_{}.csv", name);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(&prefix))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn open_csv(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+fn parse_timestamp(cell: &str) -> Option<DateTime<Local>> {
+    NaiveDateTime::parse_from_str(cell, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).single())
+}
+
+pub type Row = (DateTime<Local>, Vec<(String, String)>);
+
+/*
+Rows of a csv file as (time, [(column, cell)]). A `Timestamp` row starts a new header, as in
+files concatenated or appended to after the services changed, short rows leave the missing
+columns out and cells past the header are dropped.
+*/
+pub fn read_rows(reader: impl Read) -> io::Result<Vec<Row>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(reader);
+    let mut header: Vec<String> = Vec::new();
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        if record.get(0) == Some("Timestamp") {
+            header = record.iter().map(str::to_string).collect();
+            continue;
+        }
+        let Some(time) = record.get(0).and_then(parse_timestamp) else {
+            continue;
+        };
+        let cells = header
+            .iter()
+            .zip(record.iter())
+            .skip(1)
+            .map(|(column, cell)| (column.clone(), cell.to_string()))
+            .collect();
+        rows.push((time, cells));
+    }
+    Ok(rows)
+}
+
+// points of each csv column, or of each service
+pub type Columns = BTreeMap<String, Vec<Point>>;
+
+fn point(time: DateTime<Local>, value: f64) -> Point {
+    Point {
+        time,
+        min: value,
+        avg: value,
+        max: value,
+        count: 1,
+    }
+}
+
+// service of a per-service csv column of `metric`, e.g. `ota(MB)`, but not `ota PSS(MB)`
+fn column_service(metric: Metric, column: &str) -> Option<&str> {
+    if MEMINFO_COLUMNS.contains(&column) {
+        return None;
+    }
+    column
+        .strip_suffix(&format!("({})", metric.unit()))
+        .filter(|service| !service.is_empty() && !service.contains(' '))
+}
+
+// unit state carried in the cpu and memory rows, e.g. `ota(NRestarts)`
+fn is_unit_column(column: &str) -> bool {
+    UNIT_FIELDS
+        .iter()
+        .any(|field| column.ends_with(&format!("({})", field)))
+}
+
+/*
+Every numeric column of `metric` within the range from its csv files, N/A cells and the unit
+state columns are skipped.
+*/
+pub fn csv_columns(
+    dir: &Path,
+    metric: Metric,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> io::Result<Columns> {
+    let mut columns = Columns::new();
+    for path in csv_files(dir, metric.name())? {
+        let rows = open_csv(&path).and_then(read_rows).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read {}: {}", path.display(), e),
+            )
+        })?;
+        for (time, cells) in rows {
+            if time < from || time > to {
+                continue;
+            }
+            for (column, cell) in cells {
+                if is_unit_column(&column) {
+                    continue;
+                }
+                if let Ok(value) = cell.parse::<f64>() {
+                    columns.entry(column).or_default().push(point(time, value));
+                }
+            }
+        }
+    }
+    for series in columns.values_mut() {
+        series.sort_by_key(|point| point.time);
+    }
+    Ok(columns)
+}
+
+/// Every column of `metric` in the time-series store, at the finest resolution left.
+pub fn store_columns(
+    store: &TimeSeriesStore,
+    metric: Metric,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> io::Result<Columns> {
+    let resolution = store.resolution_for(from, Local::now());
+    let prefix = series_key(metric, "");
+    let mut columns = Columns::new();
+    for key in store.series() {
+        let Some(column) = key.strip_prefix(&prefix) else {
+            continue;
+        };
+        let series = store.query(&key, from, to, resolution)?;
+        if !series.is_empty() {
+            columns.insert(column.to_string(), series);
+        }
+    }
+    Ok(columns)
+}
+
+/// One line of DrViet_events.csv.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventRecord {
+    pub time: DateTime<Local>,
+    pub service: String,
+    pub kind: String,
+    pub message: String,
+}
+
+pub fn csv_events(
+    dir: &Path,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> io::Result<Vec<EventRecord>> {
+    let mut events = Vec::new();
+    for path in csv_files(dir, "events")? {
+        for (time, cells) in open_csv(&path).and_then(read_rows)? {
+            if time < from || time > to {
+                continue;
+            }
+            let cell = |name: &str| {
+                cells
+                    .iter()
+                    .find(|(column, _)| column == name)
+                    .map(|(_, cell)| cell.clone())
+                    .unwrap_or_default()
+            };
+            events.push(EventRecord {
+                time,
+                service: cell("Service"),
+                kind: cell("Event"),
+                message: cell("Message"),
+            });
+        }
+    }
+    events.sort_by_key(|event| event.time);
+    Ok(events)
+}
+
+/// The samples and events of a time range, from the csv files or the store.
+pub struct History {
+    pub from: Option<DateTime<Local>>,
+    pub to: DateTime<Local>,
+    pub metrics: BTreeMap<Metric, Columns>,
+    pub events: Vec<EventRecord>,
+}
+
+impl History {
+    /// Reads the output directory (or `dir`), samples come from the store if it is enabled.
+    pub fn load(
+        config: &Config,
+        dir: Option<&Path>,
+        from: Option<DateTime<Local>>,
+        to: DateTime<Local>,
+    ) -> io::Result<Self> {
+        let dir = dir
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| config.monitor.output_dir.clone().unwrap_or_default());
+        let start = from.unwrap_or_else(|| Local.timestamp_opt(0, 0).unwrap());
+        let store = if config.store.enabled {
+            Some(TimeSeriesStore::open(
+                &store_dir(config),
+                config.store.clone(),
+            )?)
+        } else {
+            None
+        };
+        let mut metrics = BTreeMap::new();
+        for metric in HISTORY_METRICS {
+            let columns = match &store {
+                Some(store) => store_columns(store, metric, start, to)?,
+                None => csv_columns(&dir, metric, start, to)?,
+            };
+            metrics.insert(metric, columns);
+        }
+        Ok(Self {
+            from,
+            to,
+            metrics,
+            events: csv_events(&dir, start, to)?,
+        })
+    }
+
+    pub fn columns(&self, metric: Metric) -> &Columns {
+        static EMPTY: Columns = Columns::new();
+        self.metrics.get(&metric).unwrap_or(&EMPTY)
+    }
+
+    /// Per-service points of `metric`, keyed by service instead of column.
+    pub fn services(&self, metric: Metric) -> Columns {
+        self.columns(metric)
+            .iter()
+            .filter_map(|(column, points)| {
+                column_service(metric, column).map(|service| (service.to_string(), points.clone()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stats {
+    pub min: f64,
+    pub avg: f64,
+    pub p95: f64,
+    pub max: f64,
+    pub samples: u32,
+}
+
+impl Stats {
+    // the 95th percentile is taken over the points, the averages of rollups
+    pub fn of(points: &[Point]) -> Option<Self> {
+        let samples: u32 = points.iter().map(|point| point.count).sum();
+        if samples == 0 {
+            return None;
+        }
+        let mut averages: Vec<f64> = points.iter().map(|point| point.avg).collect();
+        averages.sort_by(f64::total_cmp);
+        let rank = (averages.len() * 95).div_ceil(100).max(1);
+        Some(Self {
+            min: points
+                .iter()
+                .map(|point| point.min)
+                .fold(f64::INFINITY, f64::min),
+            avg: points
+                .iter()
+                .map(|point| point.avg * f64::from(point.count))
+                .sum::<f64>()
+                / f64::from(samples),
+            p95: averages[rank - 1],
+            max: points
+                .iter()
+                .map(|point| point.max)
+                .fold(f64::NEG_INFINITY, f64::max),
+            samples,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Gap {
+    pub from: DateTime<Local>,
+    pub to: DateTime<Local>,
+}
+
+/// Seconds between two points that make a gap: GAP_FACTOR times the median interval.
+pub fn gap_limit(points: &[Point]) -> Option<i64> {
+    let mut deltas: Vec<i64> = points
+        .windows(2)
+        .map(|pair| (pair[1].time - pair[0].time).num_seconds())
+        .collect();
+    if deltas.is_empty() {
+        return None;
+    }
+    deltas.sort();
+    Some(deltas[deltas.len() / 2].max(1) * GAP_FACTOR)
+}
+
+pub fn gaps(points: &[Point]) -> Vec<Gap> {
+    let Some(limit) = gap_limit(points) else {
+        return Vec::new();
+    };
+    points
+        .windows(2)
+        .filter(|pair| (pair[1].time - pair[0].time).num_seconds() > limit)
+        .map(|pair| Gap {
+            from: pair[0].time,
+            to: pair[1].time,
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ServiceReport {
+    pub service: String,
+    pub cpu: Option<Stats>,
+    pub rss_mb: Option<Stats>,
+    pub breaches: usize,
+    pub restarts: usize,
+    // periods without a memory reading, the service was down or the doctor was not running
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub from: Option<DateTime<Local>>,
+    pub to: DateTime<Local>,
+    pub services: Vec<ServiceReport>,
+}
+
+impl Report {
+    pub fn build(cpu: &Columns, memory: &Columns, events: &[EventRecord]) -> Vec<ServiceReport> {
+        let mut services: BTreeSet<&String> = cpu.keys().chain(memory.keys()).collect();
+        services.extend(
+            events
+                .iter()
+                .map(|event| &event.service)
+                .filter(|service| !service.is_empty()),
+        );
+        let count = |service: &str, kind: &str| {
+            events
+                .iter()
+                .filter(|event| event.service == service && event.kind == kind)
+                .count()
+        };
+        services
+            .into_iter()
+            .map(|service| {
+                let memory = memory.get(service).map(Vec::as_slice).unwrap_or_default();
+                ServiceReport {
+                    service: service.clone(),
+                    cpu: cpu.get(service).and_then(|points| Stats::of(points)),
+                    rss_mb: Stats::of(memory),
+                    breaches: count(service, "breach"),
+                    restarts: count(service, "restarted"),
+                    gaps: gaps(memory),
+                }
+            })
+            .collect()
+    }
+
+    pub fn new(history: &History) -> Self {
+        Self {
+            from: history.from,
+            to: history.to,
+            services: Self::build(
+                &history.services(Metric::Cpu),
+                &history.services(Metric::Memory),
+                &history.events,
+            ),
+        }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Table => self.table(),
+            Format::Json => serde_json::to_string_pretty(self).unwrap_or_default() + "\n",
+            Format::Markdown => self.markdown(),
+        }
+    }
+
+    pub fn range(&self) -> String {
+        let from = match self.from {
+            Some(from) => from.format("%Y-%m-%d %H:%M").to_string(),
+            None => "start".to_string(),
+        };
+        format!("{} - {}", from, self.to.format("%Y-%m-%d %H:%M"))
+    }
+
+    // min/avg/p95/max columns, dashes without data
+    pub fn stats_cells(stats: Option<&Stats>) -> [String; 4] {
+        match stats {
+            Some(stats) => {
+                [stats.min, stats.avg, stats.p95, stats.max].map(|v| format!("{:.1}", v))
+            }
+            None => ["-", "-", "-", "-"].map(str::to_string),
+        }
+    }
+
+    pub fn gap_cell(gaps: &[Gap]) -> String {
+        let total: i64 = gaps
+            .iter()
+            .map(|gap| (gap.to - gap.from).num_seconds())
+            .sum();
+        match gaps.len() {
+            0 => "0".to_string(),
+            n => format!("{} ({}m)", n, total / 60),
+        }
+    }
+
+    fn table(&self) -> String {
+        let mut out = format!("Report {}\n", self.range());
+        let _ = writeln!(
+            out,
+            "{:<20} {:>31}  {:>31}  {:>8} {:>8}  GAPS",
+            "SERVICE", "CPU(%) MIN/AVG/P95/MAX", "RSS(MB) MIN/AVG/P95/MAX", "BREACHES", "RESTARTS"
+        );
+        for report in &self.services {
+            let cpu = Self::stats_cells(report.cpu.as_ref()).map(|cell| format!("{:>7}", cell));
+            let rss = Self::stats_cells(report.rss_mb.as_ref()).map(|cell| format!("{:>7}", cell));
+            let _ = writeln!(
+                out,
+                "{:<20} {}  {}  {:>8} {:>8}  {}",
+                report.service,
+                cpu.join(" "),
+                rss.join(" "),
+                report.breaches,
+                report.restarts,
+                Self::gap_cell(&report.gaps)
+            );
+        }
+        out
+    }
+
+    fn markdown(&self) -> String {
+        let mut out = format!("## Report {}\n\n", self.range());
+        out.push_str("| Service | CPU min | CPU avg | CPU p95 | CPU max | RSS min (MB) | RSS avg | RSS p95 | RSS max | Breaches | Restarts | Gaps |\n");
+        out.push_str("|---|---:|---:|---:|---:|---:|---:|---:|---:|---:|---:|---|\n");
+        for report in &self.services {
+            let cpu = Self::stats_cells(report.cpu.as_ref());
+            let rss = Self::stats_cells(report.rss_mb.as_ref());
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {} | {} | {} |",
+                report.service,
+                cpu.join(" | "),
+                rss.join(" | "),
+                report.breaches,
+                report.restarts,
+                Self::gap_cell(&report.gaps)
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::report::{csv_columns, csv_events, gaps, parse_time, History, Report, Stats};
+    use crate::sample::Metric;
+    use chrono::{Local, TimeZone};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_report_from_rotated_csvs() {
+        let dir = tempfile::tempdir().unwrap();
+        // rotated and compressed, written before can-parser was tracked
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(
+            b"Timestamp,Total Memory(MB),ota(MB)\n\
+              2024-06-03 00:00:00,3800,100\n\
+              2024-06-03 00:00:10,3800,110\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("DrViet_memory.csv.1.gz"),
+            gz.finish().unwrap(),
+        )
+        .unwrap();
+        // the current file kept its old header, a new one was appended
+        fs::write(
+            dir.path().join("DrViet_memory.csv"),
+            "Timestamp,Total Memory(MB),ota(MB)\n\
+             2024-06-03 00:00:20,3800,N/A\n\
+             Timestamp,Total Memory(MB),ota(MB),can-parser(MB),ota(NRestarts)\n\
+             2024-06-03 00:00:30,3800,120,50,1\n\
+             2024-06-03 00:00:40,3800,130\n\
+             2024-06-03 00:05:00,3800,140,60,2,999\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("DrViet_events.csv"),
+            "Timestamp,Service,Event,Message,Journal\n\
+             2024-06-03 00:00:35,ota,breach,memory 60.0 above threshold 50 for 0s,\n\
+             2024-06-03 00:04:00,ota,restarted,\"restarted by remediation (restart)\",\"line 1\nline 2\"\n",
+        )
+        .unwrap();
+
+        let from = Local.with_ymd_and_hms(2024, 6, 3, 0, 0, 0).unwrap();
+        let to = Local.with_ymd_and_hms(2024, 6, 3, 1, 0, 0).unwrap();
+        let history = History {
+            from: Some(from),
+            to,
+            metrics: [(
+                Metric::Memory,
+                csv_columns(dir.path(), Metric::Memory, from, to).unwrap(),
+            )]
+            .into(),
+            events: csv_events(dir.path(), from, to).unwrap(),
+        };
+        let services = Report::new(&history).services;
+
+        // unit state is not a series
+        assert!(!history
+            .columns(Metric::Memory)
+            .contains_key("ota(NRestarts)"));
+        assert_eq!(services.len(), 2);
+        let ota = &services[1];
+        assert_eq!(ota.service, "ota");
+        let rss = ota.rss_mb.as_ref().unwrap();
+        assert_eq!(
+            (rss.min, rss.avg, rss.max, rss.samples),
+            (100.0, 120.0, 140.0, 5)
+        );
+        assert_eq!((ota.breaches, ota.restarts, ota.gaps.len()), (1, 1, 1));
+        assert!(ota.cpu.is_none());
+        assert_eq!(services[0].rss_mb.as_ref().unwrap().samples, 2);
+    }
+
+    #[test]
+    fn test_stats_gaps_and_times() {
+        let start = Local.with_ymd_and_hms(2024, 6, 3, 0, 0, 0).unwrap();
+        let points: Vec<_> = (1..=20)
+            .map(|i| {
+                let secs = if i > 10 { i * 10 + 600 } else { i * 10 };
+                super::point(start + chrono::Duration::seconds(secs), i as f64)
+            })
+            .collect();
+        let stats = Stats::of(&points).unwrap();
+        assert_eq!((stats.p95, stats.avg), (19.0, 10.5));
+        assert_eq!(gaps(&points).len(), 1);
+
+        let now = Local.with_ymd_and_hms(2024, 6, 3, 12, 0, 0).unwrap();
+        assert_eq!(
+            parse_time("24h", now).unwrap(),
+            now - chrono::Duration::hours(24)
+        );
+        assert_eq!(parse_time("2024-06-03", now).unwrap(), start);
+        assert!(parse_time("yesterday", now).is_err());
+    }
+}
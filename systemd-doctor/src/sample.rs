@@ -0,0 +1,165 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::event::HealthEvent;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Memory,
+    Cpu,
+    Disk,
+    Temperature,
+    // systemd unit state of each service
+    Unit,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 5] = [
+        Metric::Memory,
+        Metric::Cpu,
+        Metric::Disk,
+        Metric::Temperature,
+        Metric::Unit,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Memory => "memory",
+            Metric::Cpu => "cpu",
+            Metric::Disk => "disk",
+            Metric::Temperature => "temperature",
+            Metric::Unit => "unit",
+        }
+    }
+
+    // collected for each tracked service rather than for the device
+    pub fn per_service(&self) -> bool {
+        matches!(self, Metric::Memory | Metric::Cpu | Metric::Unit)
+    }
+
+    // unit of the per-service columns, e.g. `ota(MB)`
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Metric::Memory | Metric::Disk => "MB",
+            Metric::Cpu => "%",
+            Metric::Temperature => "C",
+            Metric::Unit => "",
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+    // the collection failed, written as N/A
+    Missing,
+}
+
+impl Value {
+    // N/A is null
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Number(value) => (*value).into(),
+            Value::Text(text) => text.as_str().into(),
+            Value::Missing => serde_json::Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Missing => write!(f, "N/A"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    // None for device-wide readings such as meminfo
+    pub service: Option<String>,
+    // metric name for service readings, column name for global ones
+    pub key: String,
+    // column of the reading in the metric's csv file
+    pub column: String,
+    pub value: Value,
+}
+
+impl Reading {
+    pub fn global(column: &str, value: Value) -> Self {
+        Self {
+            service: None,
+            key: column.to_string(),
+            column: column.to_string(),
+            value,
+        }
+    }
+
+    pub fn service(service: &str, metric: Metric, value: Value) -> Self {
+        Self {
+            service: Some(service.to_string()),
+            key: metric.name().to_string(),
+            column: format!("{}({})", service, metric.unit()),
+            value,
+        }
+    }
+
+    // one of several values of a service, e.g. `ota(ActiveState)`
+    pub fn service_field(service: &str, field: &str, value: Value) -> Self {
+        Self {
+            service: Some(service.to_string()),
+            key: field.to_string(),
+            column: format!("{}({})", service, field),
+            value,
+        }
+    }
+}
+
+/// Readings of one collection, stamped with the time they were taken.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub metric: Metric,
+    pub taken_at: DateTime<Local>,
+    pub readings: Vec<Reading>,
+}
+
+impl Sample {
+    pub fn new(metric: Metric) -> Self {
+        Self {
+            metric,
+            taken_at: Local::now(),
+            readings: Vec::new(),
+        }
+    }
+
+    pub fn timestamp(&self) -> String {
+        self.taken_at.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+// cpu load of `ota`, the sample the tests of alerts, anomalies and the store feed in
+#[cfg(test)]
+pub fn cpu_sample(load: f64) -> Sample {
+    let mut sample = Sample::new(Metric::Cpu);
+    sample
+        .readings
+        .push(Reading::service("ota", Metric::Cpu, Value::Number(load)));
+    sample
+}
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    Sample(Sample),
+    Event(HealthEvent),
+}
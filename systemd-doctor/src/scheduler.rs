@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+struct Task<J> {
+    job: J,
+    interval: Duration,
+    next_due: Instant,
+}
+
+/*
+Runs each job on its own cadence using the monotonic clock.
+Deadlines are advanced by whole intervals from the previous deadline (not from the time the
+job finished), so a slow collector does not make the schedule drift. Ticks missed while a job
+overran are skipped instead of being run back to back.
+*/
+pub struct Scheduler<J> {
+    tasks: Vec<Task<J>>,
+}
+
+impl<J: Clone + PartialEq> Scheduler<J> {
+    pub fn new() -> Self {
+        Self { tasks: Vec::new() }
+    }
+
+    /// Adds a job, first due right away.
+    pub fn add(&mut self, job: J, interval: Duration) {
+        self.add_at(job, interval, Instant::now());
+    }
+
+    pub fn add_at(&mut self, job: J, interval: Duration, first_due: Instant) {
+        self.tasks.push(Task {
+            job,
+            interval,
+            next_due: first_due,
+        });
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.tasks.iter().map(|task| task.next_due).min()
+    }
+
+    /// Time left until the earliest job is due, zero when one is already late.
+    pub fn time_until_due(&self) -> Duration {
+        self.next_due()
+            .map(|next_due| next_due.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::MAX)
+    }
+
+    /// Returns the jobs due at `now` and moves their deadlines to the next tick.
+    pub fn due(&mut self, now: Instant) -> Vec<J> {
+        let mut due = Vec::new();
+        for task in self.tasks.iter_mut().filter(|task| task.next_due <= now) {
+            due.push(task.job.clone());
+            while task.next_due <= now {
+                task.next_due += task.interval;
+            }
+        }
+        due
+    }
+
+    /// Makes every job due at `now`, their cadence starts over from there.
+    pub fn make_due(&mut self, now: Instant) {
+        for task in &mut self.tasks {
+            task.next_due = now;
+        }
+    }
+
+    /// Changes the cadence of `job`, the next run is at most one new interval away.
+    pub fn set_interval(&mut self, job: &J, interval: Duration) {
+        if let Some(task) = self.tasks.iter_mut().find(|task| &task.job == job) {
+            if task.interval == interval {
+                return;
+            }
+            let previous_due = task.next_due - task.interval;
+            task.interval = interval;
+            task.next_due = previous_due + interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduler::Scheduler;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_jobs_run_on_their_own_cadence() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.add_at("cpu", Duration::from_secs(2), start);
+        scheduler.add_at("memory", Duration::from_secs(10), start);
+
+        assert_eq!(scheduler.due(start), vec!["cpu", "memory"]);
+        assert!(scheduler.due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(scheduler.due(start + Duration::from_secs(2)), vec!["cpu"]);
+        // a late wake-up neither drifts the cadence nor replays the missed ticks
+        assert_eq!(
+            scheduler.due(start + Duration::from_millis(6500)),
+            vec!["cpu"]
+        );
+        assert_eq!(scheduler.next_due(), Some(start + Duration::from_secs(8)));
+        assert_eq!(
+            scheduler.due(start + Duration::from_secs(10)),
+            vec!["cpu", "memory"]
+        );
+    }
+
+    #[test]
+    fn test_set_interval_reschedules_from_previous_run() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.add_at("temperature", Duration::from_secs(10), start);
+        scheduler.due(start);
+
+        scheduler.set_interval(&"temperature", Duration::from_secs(1));
+        assert_eq!(
+            scheduler.due(start + Duration::from_secs(1)),
+            vec!["temperature"]
+        );
+        assert_eq!(scheduler.next_due(), Some(start + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,82 @@
+use log::info;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Flags {
+    stopped: bool,
+    // bumped by every check requested through the control socket
+    checks: u64,
+}
+
+/*
+Shared stop flag, threads wait on it instead of sleeping so a stop request wakes them up.
+The collectors wait on it between samples, so it also carries requests for an early check.
+*/
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<Flags>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        let (flags, condvar) = &*self.inner;
+        flags.lock().unwrap().stopped = true;
+        condvar.notify_all();
+    }
+
+    /// Wakes the collectors up to sample every metric right away.
+    pub fn request_check(&self) {
+        let (flags, condvar) = &*self.inner;
+        flags.lock().unwrap().checks += 1;
+        condvar.notify_all();
+    }
+
+    pub fn checks(&self) -> u64 {
+        self.inner.0.lock().unwrap().checks
+    }
+
+    /// Waits up to `timeout`, returns true as soon as a shutdown was requested.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (flags, condvar) = &*self.inner;
+        let guard = flags.lock().unwrap();
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |flags| !flags.stopped)
+            .unwrap();
+        guard.stopped
+    }
+
+    /// Like `wait_timeout`, also returns early once a check was requested after `*seen`.
+    pub fn wait_timeout_or_check(&self, timeout: Duration, seen: &mut u64) -> bool {
+        let (flags, condvar) = &*self.inner;
+        let guard = flags.lock().unwrap();
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |flags| {
+                !flags.stopped && flags.checks == *seen
+            })
+            .unwrap();
+        *seen = guard.checks;
+        guard.stopped
+    }
+
+    /// Triggers the shutdown on SIGTERM (systemctl stop) or SIGINT (Ctrl-C).
+    pub fn on_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Received signal {}, shutting down...", signal);
+                shutdown.trigger();
+            }
+        });
+        Ok(())
+    }
+}
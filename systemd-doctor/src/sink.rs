@@ -0,0 +1,223 @@
+use log::error;
+use std::io;
+use std::slice;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+use crate::alert::Alert;
+use crate::event::HealthEvent;
+use crate::log::attach_journal;
+use crate::persist::StateFile;
+use crate::sample::{Message, Metric, Sample};
+use crate::state_store::StateStore;
+
+/// Consumer of published samples and events, each sink runs on its own thread.
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+
+    fn handle(&mut self, message: &Message) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `sink` until every sender of its channel is dropped, then flushes it.
+pub fn spawn_sink(mut sink: Box<dyn Sink>) -> (Sender<Message>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel::<Message>();
+    let handle = thread::spawn(move || {
+        for message in receiver {
+            if let Err(e) = sink.handle(&message) {
+                error!("Sink {} failed: {}", sink.name(), e);
+            }
+        }
+        if let Err(e) = sink.flush() {
+            error!("Failed to flush sink {}: {}", sink.name(), e);
+        }
+    });
+    (sender, handle)
+}
+
+/// Handed to the collectors: updates the state store and fans samples out to the sinks.
+#[derive(Clone)]
+pub struct Publisher {
+    inner: Arc<PublisherInner>,
+}
+
+struct PublisherInner {
+    state: Arc<StateStore>,
+    senders: Vec<Sender<Message>>,
+    // failure events wait for their journal lines here, see Publisher::with_journal
+    journal: Option<Sender<HealthEvent>>,
+}
+
+fn send_all(senders: &[Sender<Message>], message: Message) {
+    for sender in senders {
+        // a sink that already stopped simply misses the message
+        let _ = sender.send(message.clone());
+    }
+}
+
+/*
+Publisher that does not keep the sinks alive, for threads that block outside of the
+shutdown handling (e.g. waiting on D-Bus signals). It stops working once the last
+Publisher is dropped.
+*/
+#[derive(Clone)]
+pub struct WeakPublisher {
+    inner: Weak<PublisherInner>,
+}
+
+impl WeakPublisher {
+    pub fn upgrade(&self) -> Option<Publisher> {
+        self.inner.upgrade().map(|inner| Publisher { inner })
+    }
+}
+
+impl Publisher {
+    pub fn new(state: Arc<StateStore>, senders: Vec<Sender<Message>>) -> Self {
+        Self {
+            inner: Arc::new(PublisherInner {
+                state,
+                senders,
+                journal: None,
+            }),
+        }
+    }
+
+    /*
+    Failure events get the last `lines` journal lines of their unit on a thread of their own
+    before going to the sinks, so the collectors and the D-Bus and OOM watchers never wait on
+    journalctl. The thread holds the sinks open until the last Publisher is dropped and it has
+    sent on what it was given.
+    */
+    pub fn with_journal(
+        state: Arc<StateStore>,
+        senders: Vec<Sender<Message>>,
+        lines: usize,
+        state_file: Arc<StateFile>,
+    ) -> Self {
+        if lines == 0 {
+            return Self::new(state, senders);
+        }
+        let (journal, receiver) = mpsc::channel::<HealthEvent>();
+        let journal_senders = senders.clone();
+        thread::spawn(move || {
+            for mut event in receiver {
+                attach_journal(slice::from_mut(&mut event), lines, &state_file);
+                send_all(&journal_senders, Message::Event(event));
+            }
+        });
+        Self {
+            inner: Arc::new(PublisherInner {
+                state,
+                senders,
+                journal: Some(journal),
+            }),
+        }
+    }
+
+    pub fn downgrade(&self) -> WeakPublisher {
+        WeakPublisher {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    pub fn publish(&self, sample: Sample) {
+        self.inner.state.apply(&sample);
+        self.send(Message::Sample(sample));
+    }
+
+    pub fn publish_alerts(&self, metric: Metric, alerts: Vec<Alert>) {
+        self.inner.state.set_alerts(metric, alerts);
+    }
+
+    pub fn publish_event(&self, mut event: HealthEvent) {
+        if let Some(service) = &event.service {
+            event.muted = self.inner.state.is_muted(service, event.time);
+        }
+        match &self.inner.journal {
+            Some(journal) if event.wants_journal() => {
+                let _ = journal.send(event);
+            }
+            _ => self.send(Message::Event(event)),
+        }
+    }
+
+    fn send(&self, message: Message) {
+        send_all(&self.inner.senders, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::{EventKind, HealthEvent};
+    use crate::sample::{Message, Metric, Sample};
+    use crate::sink::{spawn_sink, Publisher, Sink};
+    use crate::state_store::StateStore;
+    use chrono::{Duration, Local};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // records what it handled, "flush" last
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Sink for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn handle(&mut self, message: &Message) -> io::Result<()> {
+            let entry = match message {
+                Message::Sample(sample) => sample.metric.to_string(),
+                Message::Event(event) => format!("{} muted={}", event.kind, event.muted),
+            };
+            self.0.lock().unwrap().push(entry);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push("flush".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fan_out_and_drain_on_last_drop() {
+        let records = [(); 2].map(|_| Arc::new(Mutex::new(Vec::new())));
+        let (senders, handles): (Vec<_>, Vec<_>) = records
+            .iter()
+            .map(|record| spawn_sink(Box::new(Recorder(record.clone()))))
+            .unzip();
+        let state = Arc::new(StateStore::new());
+        state.mute("ota", Local::now() + Duration::minutes(5));
+        let publisher = Publisher::new(state, senders);
+        let weak = publisher.downgrade();
+        let clone = publisher.clone();
+
+        publisher.publish(Sample::new(Metric::Cpu));
+        drop(publisher);
+        // a clone keeps the sinks running
+        let upgraded = weak.upgrade().unwrap();
+        upgraded.publish_event(HealthEvent::new(
+            Some("ota"),
+            EventKind::Breach,
+            "cpu high".to_string(),
+        ));
+        clone.publish(Sample::new(Metric::Memory));
+        drop(upgraded);
+        drop(clone);
+        assert!(weak.upgrade().is_none());
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        for record in &records {
+            assert_eq!(
+                *record.lock().unwrap(),
+                ["cpu", "breach muted=true", "memory", "flush"]
+            );
+        }
+    }
+}
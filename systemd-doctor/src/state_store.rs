@@ -0,0 +1,222 @@
+use chrono::{DateTime, Local};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::alert::Alert;
+use crate::sample::{Metric, Sample, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Point {
+    pub value: Value,
+    pub taken_at: DateTime<Local>,
+}
+
+/// Latest value of every reading, as published by the collectors.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub updated_at: Option<DateTime<Local>>,
+    // column -> point, e.g. "Total Memory(MB)"
+    pub global: BTreeMap<String, Point>,
+    // service -> metric -> point
+    pub services: BTreeMap<String, BTreeMap<String, Point>>,
+    // state of every threshold alert
+    pub alerts: Vec<Alert>,
+    // service -> end of its mute, set through the control socket
+    pub muted: BTreeMap<String, DateTime<Local>>,
+}
+
+impl Point {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "value": self.value.to_json(),
+            "time": self.taken_at.to_rfc3339(),
+        })
+    }
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> serde_json::Value {
+        let points =
+            |points: &BTreeMap<String, Point>| -> serde_json::Map<String, serde_json::Value> {
+                points
+                    .iter()
+                    .map(|(key, point)| (key.clone(), point.to_json()))
+                    .collect()
+            };
+        json!({
+            "updated_at": self.updated_at.map(|time| time.to_rfc3339()),
+            "global": points(&self.global),
+            "services": self
+                .services
+                .iter()
+                .map(|(service, readings)| (service.clone(), points(readings).into()))
+                .collect::<serde_json::Map<_, _>>(),
+            "alerts": self.alerts.iter().map(Alert::to_json).collect::<Vec<_>>(),
+            "muted": self
+                .muted
+                .iter()
+                .map(|(service, until)| (service.clone(), until.to_rfc3339().into()))
+                .collect::<serde_json::Map<_, _>>(),
+        })
+    }
+
+    /// Latest readings, alerts and mute of one service.
+    pub fn service_json(&self, service: &str) -> serde_json::Value {
+        let readings: serde_json::Map<String, serde_json::Value> = self
+            .services
+            .get(service)
+            .into_iter()
+            .flatten()
+            .map(|(key, point)| (key.clone(), point.value.to_json()))
+            .collect();
+        let alerts: Vec<serde_json::Value> = self
+            .alerts
+            .iter()
+            .filter(|alert| alert.service.as_deref() == Some(service))
+            .map(Alert::to_json)
+            .collect();
+        json!({
+            "service": service,
+            "readings": readings,
+            "alerts": alerts,
+            "muted_until": self.muted.get(service).map(|until| until.to_rfc3339()),
+        })
+    }
+}
+
+/*
+Shared, read-mostly view of the current state. Writers only hold the lock while copying a
+sample in, so readers (status queries, endpoints) never wait on a collection.
+*/
+#[derive(Default)]
+pub struct StateStore {
+    snapshot: RwLock<Snapshot>,
+}
+
+impl StateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&self, sample: &Sample) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        snapshot.updated_at = Some(sample.taken_at);
+        for reading in &sample.readings {
+            let point = Point {
+                value: reading.value.clone(),
+                taken_at: sample.taken_at,
+            };
+            match &reading.service {
+                Some(service) => {
+                    snapshot
+                        .services
+                        .entry(service.clone())
+                        .or_default()
+                        .insert(reading.key.clone(), point);
+                }
+                None => {
+                    snapshot.global.insert(reading.key.clone(), point);
+                }
+            }
+        }
+    }
+
+    // replaces the alerts of `metric`, each metric is evaluated by its own collector
+    pub fn set_alerts(&self, metric: Metric, alerts: Vec<Alert>) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        snapshot.alerts.retain(|alert| alert.metric != metric);
+        snapshot.alerts.extend(alerts);
+    }
+
+    /// Mutes the events of `service` until `until`, a time in the past unmutes it.
+    pub fn mute(&self, service: &str, until: DateTime<Local>) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        if until > Local::now() {
+            snapshot.muted.insert(service.to_string(), until);
+        } else {
+            snapshot.muted.remove(service);
+        }
+    }
+
+    pub fn is_muted(&self, service: &str, now: DateTime<Local>) -> bool {
+        self.snapshot
+            .read()
+            .unwrap()
+            .muted
+            .get(service)
+            .is_some_and(|until| *until > now)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alert::{Alert, AlertState};
+    use crate::sample::{Metric, Reading, Sample, Value};
+    use crate::state_store::StateStore;
+    use chrono::{Duration, Local};
+
+    fn alert(service: &str, metric: Metric) -> Alert {
+        Alert {
+            service: Some(service.to_string()),
+            metric,
+            state: AlertState::Firing,
+            value: 90.0,
+            threshold: 80.0,
+            since: Local::now(),
+        }
+    }
+
+    #[test]
+    fn test_apply_and_snapshot() {
+        let state = StateStore::new();
+        let mut sample = Sample::new(Metric::Memory);
+        sample
+            .readings
+            .push(Reading::global("Total Memory(MB)", Value::Number(1024.0)));
+        sample
+            .readings
+            .push(Reading::service("ota", Metric::Memory, Value::Number(12.0)));
+        state.apply(&sample);
+        let mut sample = Sample::new(Metric::Cpu);
+        sample
+            .readings
+            .push(Reading::service("ota", Metric::Cpu, Value::Missing));
+        state.apply(&sample);
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.updated_at, Some(sample.taken_at));
+        assert_eq!(
+            snapshot.global["Total Memory(MB)"].value,
+            Value::Number(1024.0)
+        );
+        assert_eq!(
+            snapshot.services["ota"]["memory"].value,
+            Value::Number(12.0)
+        );
+        assert_eq!(snapshot.services["ota"]["cpu"].value, Value::Missing);
+        assert_eq!(
+            snapshot.service_json("ota")["readings"]["cpu"],
+            serde_json::Value::Null
+        );
+
+        // the alerts of one metric are replaced, the others kept
+        state.set_alerts(Metric::Cpu, vec![alert("ota", Metric::Cpu)]);
+        state.set_alerts(Metric::Memory, vec![alert("ota", Metric::Memory)]);
+        state.set_alerts(Metric::Cpu, Vec::new());
+        let alerts = state.snapshot().alerts;
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].metric, Metric::Memory);
+
+        let now = Local::now();
+        state.mute("ota", now + Duration::minutes(30));
+        assert!(state.is_muted("ota", now));
+        assert!(!state.is_muted("ota", now + Duration::hours(1)));
+        state.mute("ota", now - Duration::seconds(1));
+        assert!(state.snapshot().muted.is_empty());
+    }
+}
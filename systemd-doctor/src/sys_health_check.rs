@@ -1,10 +1,13 @@
+use log::debug;
 use std::{thread, time::Duration};
 use sysinfo::System;
 
+#[allow(dead_code)]
 pub struct HealthCheck {
     system: System,
 }
 
+#[allow(dead_code)]
 impl HealthCheck {
     pub fn new() -> Self {
         let mut system = System::new_all();
@@ -20,8 +23,8 @@ impl HealthCheck {
         self.system.refresh_all();
         thread::sleep(Duration::from_millis(500));
         self.system.refresh_all();
-        for process in self.system.processes_by_exact_name(service_name) {
-            println!("{}: cpu_load: {}", process.name(), process.cpu_usage());
+        if let Some(process) = self.system.processes_by_exact_name(service_name).next() {
+            debug!("{}: cpu_load: {}", process.name(), process.cpu_usage());
             return Ok(process.cpu_usage());
         }
         Err(format!("Service {} not found or CPU usage", service_name))
@@ -35,8 +38,8 @@ impl HealthCheck {
         self.system.refresh_all();
         thread::sleep(Duration::from_millis(500));
         self.system.refresh_all();
-        for process in self.system.processes_by_exact_name(service_name) {
-            println!("{}: memory: {}", process.name(), process.memory());
+        if let Some(process) = self.system.processes_by_exact_name(service_name).next() {
+            debug!("{}: memory: {}", process.name(), process.memory());
             return Ok(process.memory());
         }
         Err(format!("Service {} not found or CPU usage", service_name))
@@ -0,0 +1,271 @@
+use chrono::SecondsFormat;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+use crate::config::{Config, SyslogConfig, SyslogTransport};
+use crate::event::{EventKind, HealthEvent};
+use crate::sample::Message;
+use crate::sink::Sink;
+
+const DEV_LOG: &str = "/dev/log";
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const DEFAULT_REMOTE: &str = "127.0.0.1:514";
+// private enterprise number reserved for documentation by RFC 5612
+const SD_ID: &str = "doctor@32473";
+
+/// Name of the machine, "unknown" if the kernel does not tell.
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// syslog severity, failures first, recoveries last
+fn severity(kind: EventKind) -> u8 {
+    match kind {
+        EventKind::UnitFailed | EventKind::OomKill => 3,
+        EventKind::Breach
+        | EventKind::Flapping
+        | EventKind::StatusMismatch
+        | EventKind::LeakSuspected
+        | EventKind::Anomaly => 4,
+        EventKind::Remediation | EventKind::Restarted => 5,
+        EventKind::Recovered => 6,
+    }
+}
+
+// service, metric, value and threshold of the event, those it has
+fn fields(event: &HealthEvent) -> Vec<(&'static str, String)> {
+    let mut fields = Vec::new();
+    if let Some(service) = &event.service {
+        fields.push(("service", service.clone()));
+    }
+    if let Some(metric) = event.metric {
+        fields.push(("metric", metric.name().to_string()));
+    }
+    if let Some(value) = event.value {
+        fields.push(("value", value.to_string()));
+    }
+    if let Some(threshold) = event.threshold {
+        fields.push(("threshold", threshold.to_string()));
+    }
+    if event.muted {
+        fields.push(("muted", "1".to_string()));
+    }
+    fields
+}
+
+/// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [doctor@32473 ...] MSG`, the event kind as MSGID.
+pub fn rfc5424(config: &SyslogConfig, hostname: &str, event: &HealthEvent) -> String {
+    let priority = config.facility.code() * 8 + severity(event.kind);
+    let fields = fields(event);
+    let data = match fields.is_empty() {
+        true => "-".to_string(),
+        false => {
+            let params: Vec<String> = fields
+                .iter()
+                .map(|(name, value)| format!(" {}=\"{}\"", name, escape_param(value)))
+                .collect();
+            format!("[{}{}]", SD_ID, params.concat())
+        }
+    };
+    format!(
+        "<{}>1 {} {} {} {} {} {} {}",
+        priority,
+        event.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+        hostname,
+        config.app_name,
+        process::id(),
+        event.kind.name(),
+        data,
+        event.message
+    )
+}
+
+// `"`, `\` and `]` are escaped in structured data values
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/*
+Native journal protocol: KEY=value lines, and for values spanning several lines the key, a
+newline, the little endian 64 bit length and the value. See systemd.journal-fields(7) for the
+standard fields.
+*/
+pub fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+pub fn journal_entry(config: &SyslogConfig, event: &HealthEvent) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut field = |key: &str, value: &str| journal_field(&mut entry, key, value);
+    let mut message = event.message.clone();
+    for line in &event.journal {
+        message.push('\n');
+        message.push_str(line);
+    }
+    field("MESSAGE", &message);
+    field("PRIORITY", &severity(event.kind).to_string());
+    field("SYSLOG_FACILITY", &config.facility.code().to_string());
+    field("SYSLOG_IDENTIFIER", &config.app_name);
+    field("DOCTOR_EVENT", event.kind.name());
+    for (name, value) in fields(event) {
+        field(&format!("DOCTOR_{}", name.to_uppercase()), &value);
+    }
+    entry
+}
+
+enum Connection {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+/*
+Forwards every event, remediation steps included, to syslog or to the journal. The socket is
+opened on the first event and again after a failure, so a syslog daemon restarted under the
+doctor only costs the events sent while it was away.
+*/
+pub struct SyslogSink {
+    config: SyslogConfig,
+    address: String,
+    hostname: String,
+    connection: Option<Connection>,
+}
+
+impl SyslogSink {
+    pub fn new(config: &Config) -> Self {
+        let config = config.syslog.clone();
+        let address = config
+            .address
+            .clone()
+            .unwrap_or_else(|| match config.transport {
+                SyslogTransport::Unix => DEV_LOG.to_string(),
+                SyslogTransport::Journal => JOURNAL_SOCKET.to_string(),
+                SyslogTransport::Udp | SyslogTransport::Tcp => DEFAULT_REMOTE.to_string(),
+            });
+        Self {
+            config,
+            address,
+            hostname: hostname(),
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        Ok(match self.config.transport {
+            SyslogTransport::Unix | SyslogTransport::Journal => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.address)?;
+                Connection::Unix(socket)
+            }
+            SyslogTransport::Udp => {
+                let socket = UdpSocket::bind(if self.address.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })?;
+                socket.connect(&self.address)?;
+                Connection::Udp(socket)
+            }
+            SyslogTransport::Tcp => Connection::Tcp(TcpStream::connect(&self.address)?),
+        })
+    }
+
+    fn send(&mut self, event: &HealthEvent) -> io::Result<()> {
+        let payload = match self.config.transport {
+            SyslogTransport::Journal => journal_entry(&self.config, event),
+            _ => rfc5424(&self.config, &self.hostname, event).into_bytes(),
+        };
+        let connection = match self.connection.take() {
+            Some(connection) => Ok(connection),
+            None => self.connect(),
+        };
+        let result = connection.and_then(|mut connection| {
+            match &mut connection {
+                Connection::Unix(socket) => socket.send(&payload).map(|_| ()),
+                Connection::Udp(socket) => socket.send(&payload).map(|_| ()),
+                Connection::Tcp(stream) => {
+                    write!(stream, "{} ", payload.len()).and_then(|_| stream.write_all(&payload))
+                }
+            }?;
+            self.connection = Some(connection);
+            Ok(())
+        });
+        result.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.address, e)))
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Event(event) => self.send(event),
+            Message::Sample(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, SyslogTransport};
+    use crate::event::{EventKind, HealthEvent};
+    use crate::sample::{Message, Metric};
+    use crate::sink::Sink;
+    use crate::syslog::{journal_entry, SyslogSink};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[test]
+    fn test_forwards_events() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut config = Config::default();
+        config.syslog.transport = SyslogTransport::Udp;
+        config.syslog.address = Some(receiver.local_addr().unwrap().to_string());
+        let mut sink = SyslogSink::new(&config);
+
+        let event = HealthEvent::new(Some("ota"), EventKind::Breach, "cpu 95.0".to_string())
+            .with_reading(Metric::Cpu, 95.0, 90.0);
+        sink.handle(&Message::Event(event.clone())).unwrap();
+        let mut buffer = [0u8; 1024];
+        let length = receiver.recv(&mut buffer).unwrap();
+        let line = String::from_utf8_lossy(&buffer[..length]).into_owned();
+        // daemon.warning
+        assert!(line.starts_with("<28>1 "));
+        let pid = std::process::id();
+        assert!(line.ends_with(&format!(
+            " systemd-doctor {} breach [doctor@32473 service=\"ota\" metric=\"cpu\" value=\"95\" threshold=\"90\"] cpu 95.0",
+            pid
+        )), "{}", line);
+
+        let entry = String::from_utf8(journal_entry(&config.syslog, &event)).unwrap();
+        assert!(entry.contains("PRIORITY=4\n"));
+        assert!(entry.contains("DOCTOR_SERVICE=ota\nDOCTOR_METRIC=cpu\n"));
+    }
+}
@@ -0,0 +1,592 @@
+use chrono::{Local, TimeZone};
+use log::warn;
+use std::collections::HashMap;
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+use crate::unit_state::UnitState;
+
+const UNIT_PATH_PREFIX: &str = "/org/freedesktop/systemd1/unit";
+pub const UNIT_INTERFACE: &str = "org.freedesktop.systemd1.Unit";
+pub const SERVICE_INTERFACE: &str = "org.freedesktop.systemd1.Service";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait Manager {
+    #[allow(clippy::type_complexity)]
+    fn list_units(
+        &self,
+    ) -> zbus::Result<
+        Vec<(
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            OwnedObjectPath,
+            u32,
+            String,
+            OwnedObjectPath,
+        )>,
+    >;
+    fn load_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+    fn start_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn reload_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn kill_unit(&self, name: &str, whom: &str, signal: i32) -> zbus::Result<()>;
+    fn subscribe(&self) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.DBus.Properties",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait UnitProperties {
+    fn get_all(&self, interface_name: &str) -> zbus::Result<HashMap<String, OwnedValue>>;
+    fn get(&self, interface_name: &str, property_name: &str) -> zbus::Result<OwnedValue>;
+}
+
+/// One entry of `ListUnits`.
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitInfo {
+    pub name: String,
+    pub description: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+}
+
+/*
+Thin client for org.freedesktop.systemd1, the D-Bus API systemctl itself uses.
+Talking to systemd directly avoids a fork per query and lets the doctor subscribe to unit
+changes instead of polling them.
+*/
+pub struct SystemdClient {
+    connection: Connection,
+}
+
+impl SystemdClient {
+    pub fn system() -> zbus::Result<Self> {
+        Ok(Self::new(Connection::system()?))
+    }
+
+    pub fn new(connection: Connection) -> Self {
+        Self { connection }
+    }
+
+    fn manager(&self) -> zbus::Result<ManagerProxyBlocking<'_>> {
+        ManagerProxyBlocking::builder(&self.connection)
+            .cache_properties(CacheProperties::No)
+            .build()
+    }
+
+    fn properties(&self, path: OwnedObjectPath) -> zbus::Result<UnitPropertiesProxyBlocking<'_>> {
+        UnitPropertiesProxyBlocking::builder(&self.connection)
+            .path(path)?
+            .cache_properties(CacheProperties::No)
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn list_units(&self) -> zbus::Result<Vec<UnitInfo>> {
+        Ok(self
+            .manager()?
+            .list_units()?
+            .into_iter()
+            .map(
+                |(name, description, load_state, active_state, sub_state, ..)| UnitInfo {
+                    name,
+                    description,
+                    load_state,
+                    active_state,
+                    sub_state,
+                },
+            )
+            .collect())
+    }
+
+    pub fn unit_path(&self, unit: &str) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?.load_unit(unit)
+    }
+
+    /// All properties of `interface` (e.g. org.freedesktop.systemd1.Service) of a unit.
+    pub fn unit_properties(
+        &self,
+        unit: &str,
+        interface: &str,
+    ) -> zbus::Result<HashMap<String, OwnedValue>> {
+        self.properties(self.unit_path(unit)?)?.get_all(interface)
+    }
+
+    pub fn unit_property(
+        &self,
+        unit: &str,
+        interface: &str,
+        property: &str,
+    ) -> zbus::Result<OwnedValue> {
+        self.properties(self.unit_path(unit)?)?
+            .get(interface, property)
+    }
+
+    // "loaded", or "not-found" for a unit without a unit file
+    pub fn load_state(&self, unit: &str) -> zbus::Result<String> {
+        Ok(String::try_from(self.unit_property(
+            unit,
+            UNIT_INTERFACE,
+            "LoadState",
+        )?)?)
+    }
+
+    pub fn unit_state(&self, unit: &str) -> zbus::Result<UnitState> {
+        let unit_properties = self.unit_properties(unit, UNIT_INTERFACE)?;
+        // only service units have the Service interface
+        let service_properties = self
+            .unit_properties(unit, SERVICE_INTERFACE)
+            .unwrap_or_default();
+
+        let text = |map: &HashMap<String, OwnedValue>, key: &str| -> String {
+            map.get(key)
+                .and_then(|value| String::try_from(value.try_clone().ok()?).ok())
+                .unwrap_or_default()
+        };
+        let number = |map: &HashMap<String, OwnedValue>, key: &str| -> i64 {
+            map.get(key)
+                .and_then(|value| {
+                    u32::try_from(value)
+                        .map(i64::from)
+                        .or_else(|_| i32::try_from(value).map(i64::from))
+                        .or_else(|_| u64::try_from(value).map(|v| v as i64))
+                        .ok()
+                })
+                .unwrap_or(0)
+        };
+
+        let active_enter_usec = number(&unit_properties, "ActiveEnterTimestamp");
+        let active_enter_timestamp = if active_enter_usec > 0 {
+            Local
+                .timestamp_micros(active_enter_usec)
+                .single()
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        Ok(UnitState {
+            active_state: text(&unit_properties, "ActiveState"),
+            sub_state: text(&unit_properties, "SubState"),
+            n_restarts: number(&service_properties, "NRestarts") as u32,
+            exec_main_status: number(&service_properties, "ExecMainStatus") as i32,
+            exec_main_pid: number(&service_properties, "ExecMainPID") as u32,
+            active_enter_timestamp,
+            exec_main_code: number(&service_properties, "ExecMainCode") as i32,
+            result: text(&service_properties, "Result"),
+            control_group: text(&service_properties, "ControlGroup"),
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn start_unit(&self, unit: &str) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?.start_unit(unit, "replace")
+    }
+
+    #[allow(dead_code)]
+    pub fn stop_unit(&self, unit: &str) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?.stop_unit(unit, "replace")
+    }
+
+    pub fn reload_unit(&self, unit: &str) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?.reload_unit(unit, "replace")
+    }
+
+    pub fn restart_unit(&self, unit: &str) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?.restart_unit(unit, "replace")
+    }
+
+    // starts `target` and stops everything it does not pull in, like `systemctl isolate`
+    pub fn isolate(&self, target: &str) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?.start_unit(target, "isolate")
+    }
+
+    // like `systemctl reboot`
+    pub fn reboot(&self) -> zbus::Result<OwnedObjectPath> {
+        self.manager()?
+            .start_unit("reboot.target", "replace-irreversibly")
+    }
+
+    /// Units listed in the Requires= of `unit`.
+    pub fn requires(&self, unit: &str) -> zbus::Result<Vec<String>> {
+        Ok(Vec::<String>::try_from(self.unit_property(
+            unit,
+            UNIT_INTERFACE,
+            "Requires",
+        )?)?)
+    }
+
+    #[allow(dead_code)]
+    pub fn kill_unit(&self, unit: &str, signal: i32) -> zbus::Result<()> {
+        self.manager()?.kill_unit(unit, "all", signal)
+    }
+
+    /*
+    Asks systemd to emit change signals (it stays quiet for clients that did not Subscribe)
+    and returns an iterator over the names of `units` whose properties changed.
+    */
+    pub fn watch_units(&self, units: &[String]) -> zbus::Result<UnitChanges> {
+        self.manager()?.subscribe()?;
+
+        let mut paths = HashMap::new();
+        for unit in units {
+            // a unit systemd cannot load is left to the polling
+            match self.unit_path(unit) {
+                Ok(path) => {
+                    paths.insert(path.to_string(), unit.clone());
+                }
+                Err(e) => warn!("Not watching {} for changes: {}", unit, e),
+            }
+        }
+        let rule = zbus::MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .path_namespace(UNIT_PATH_PREFIX)?
+            .build();
+        let messages = MessageIterator::for_match_rule(rule, &self.connection, Some(256))?;
+        Ok(UnitChanges { messages, paths })
+    }
+}
+
+pub struct UnitChanges {
+    messages: MessageIterator,
+    // object path -> unit name
+    paths: HashMap<String, String>,
+}
+
+impl Iterator for UnitChanges {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let message = match self.messages.next()? {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Failed to read a unit change signal: {}", e);
+                    continue;
+                }
+            };
+            let header = message.header();
+            let Some(path) = header.path() else {
+                continue;
+            };
+            if let Some(unit) = self.paths.get(path.as_str()) {
+                return Some(unit.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::systemd_dbus::SystemdClient;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use zbus::blocking::connection::Builder;
+    use zbus::blocking::Connection;
+    use zbus::zvariant::{ObjectPath, OwnedObjectPath, Value};
+    use zbus::{fdo, interface, Guid};
+
+    pub const SYSTEMD_PATH: &str = "/org/freedesktop/systemd1";
+    pub const OTA_PATH: &str = "/org/freedesktop/systemd1/unit/ota_2eservice";
+
+    // Stand-in for systemd: a manager object with a single ota.service unit
+    pub struct MockManager {
+        pub calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Manager")]
+    impl MockManager {
+        #[allow(clippy::type_complexity)]
+        fn list_units(
+            &self,
+        ) -> Vec<(
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            OwnedObjectPath,
+            u32,
+            String,
+            OwnedObjectPath,
+        )> {
+            let root = OwnedObjectPath::try_from("/").unwrap();
+            vec![(
+                "ota.service".into(),
+                "OTA updater".into(),
+                "loaded".into(),
+                "active".into(),
+                "running".into(),
+                String::new(),
+                OwnedObjectPath::try_from(OTA_PATH).unwrap(),
+                0,
+                String::new(),
+                root,
+            )]
+        }
+
+        fn load_unit(&self, name: &str) -> fdo::Result<OwnedObjectPath> {
+            match name {
+                "ota.service" => Ok(OwnedObjectPath::try_from(OTA_PATH).unwrap()),
+                _ => Err(fdo::Error::FileNotFound(name.to_string())),
+            }
+        }
+
+        fn restart_unit(&self, name: &str, mode: &str) -> OwnedObjectPath {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("restart {} {}", name, mode));
+            OwnedObjectPath::try_from("/org/freedesktop/systemd1/job/1").unwrap()
+        }
+
+        fn kill_unit(&self, name: &str, whom: &str, signal: i32) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("kill {} {} {}", name, whom, signal));
+        }
+
+        fn subscribe(&self) {
+            self.calls.lock().unwrap().push("subscribe".to_string());
+        }
+    }
+
+    pub struct MockUnit {
+        pub active_state: String,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Unit")]
+    impl MockUnit {
+        #[zbus(property)]
+        fn active_state(&self) -> String {
+            self.active_state.clone()
+        }
+
+        #[zbus(property)]
+        fn load_state(&self) -> String {
+            "loaded".to_string()
+        }
+
+        #[zbus(property)]
+        fn sub_state(&self) -> String {
+            "running".to_string()
+        }
+
+        #[zbus(property)]
+        fn active_enter_timestamp(&self) -> u64 {
+            0
+        }
+    }
+
+    pub struct MockService {
+        pub n_restarts: u32,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Service")]
+    impl MockService {
+        #[zbus(property, name = "NRestarts")]
+        fn n_restarts(&self) -> u32 {
+            self.n_restarts
+        }
+
+        #[zbus(property)]
+        fn exec_main_status(&self) -> i32 {
+            0
+        }
+
+        #[zbus(property, name = "ExecMainPID")]
+        fn exec_main_pid(&self) -> u32 {
+            4242
+        }
+    }
+
+    /// A private session bus, stopped when dropped.
+    pub struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        // None without dbus-daemon
+        pub fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+            let address = address.trim().to_string();
+            Some(Self { daemon, address })
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    pub struct MockSystemd {
+        pub client: SystemdClient,
+        pub server: Connection,
+        pub calls: Arc<Mutex<Vec<String>>>,
+        // the bus both connections are on, if any
+        _bus: Option<TestBus>,
+    }
+
+    /*
+    Connects a client to a mock systemd owning org.freedesktop.systemd1 on a private session
+    bus, so Subscribe and the match rules go through a bus daemon as on a device. Without
+    dbus-daemon both ends talk over a socket pair instead.
+    */
+    pub fn mock_systemd() -> MockSystemd {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let manager = MockManager {
+            calls: calls.clone(),
+        };
+        let unit = MockUnit {
+            active_state: "active".to_string(),
+        };
+        let service = MockService { n_restarts: 1 };
+
+        if let Some(bus) = TestBus::start() {
+            let server = Builder::address(bus.address.as_str())
+                .unwrap()
+                .name("org.freedesktop.systemd1")
+                .unwrap()
+                .serve_at(SYSTEMD_PATH, manager)
+                .unwrap()
+                .serve_at(OTA_PATH, unit)
+                .unwrap()
+                .serve_at(OTA_PATH, service)
+                .unwrap()
+                .build()
+                .unwrap();
+            let client = Builder::address(bus.address.as_str())
+                .unwrap()
+                .build()
+                .unwrap();
+            return MockSystemd {
+                client: SystemdClient::new(client),
+                server,
+                calls,
+                _bus: Some(bus),
+            };
+        }
+
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            Builder::async_io_unix_stream(server_stream)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at(SYSTEMD_PATH, manager)
+                .unwrap()
+                .serve_at(OTA_PATH, unit)
+                .unwrap()
+                .serve_at(OTA_PATH, service)
+                .unwrap()
+                .build()
+                .unwrap()
+        });
+        let client = Builder::async_io_unix_stream(client_stream)
+            .p2p()
+            .build()
+            .unwrap();
+        MockSystemd {
+            client: SystemdClient::new(client),
+            server: server.join().unwrap(),
+            calls,
+            _bus: None,
+        }
+    }
+
+    #[test]
+    fn test_list_units_and_unit_state() {
+        let mock = mock_systemd();
+        let client = &mock.client;
+
+        let units = client.list_units().unwrap();
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].name, "ota.service");
+        assert_eq!(units[0].sub_state, "running");
+
+        assert_eq!(client.load_state("ota.service").unwrap(), "loaded");
+        let state = client.unit_state("ota.service").unwrap();
+        assert_eq!(state.active_state, "active");
+        assert_eq!(state.n_restarts, 1);
+        assert_eq!(state.exec_main_pid, 4242);
+        assert!(client.unit_state("missing.service").is_err());
+    }
+
+    #[test]
+    fn test_unit_commands() {
+        let mock = mock_systemd();
+        let (client, calls) = (&mock.client, &mock.calls);
+
+        client.restart_unit("ota.service").unwrap();
+        client.kill_unit("ota.service", 9).unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["restart ota.service replace", "kill ota.service all 9"]
+        );
+    }
+
+    #[test]
+    fn test_watch_units_reports_property_changes() {
+        let mock = mock_systemd();
+        let (client, server, calls) = (&mock.client, &mock.server, &mock.calls);
+        let mut changes = client.watch_units(&["ota.service".to_string()]).unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["subscribe"]);
+
+        let changed: HashMap<&str, Value> = HashMap::from([("ActiveState", Value::from("failed"))]);
+        let invalidated: Vec<&str> = Vec::new();
+        // a change of an unrelated object first, it must be skipped
+        server
+            .emit_signal(
+                None::<()>,
+                ObjectPath::try_from("/org/freedesktop/systemd1/unit/other_2eservice").unwrap(),
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &("org.freedesktop.systemd1.Unit", &changed, &invalidated),
+            )
+            .unwrap();
+        server
+            .emit_signal(
+                None::<()>,
+                ObjectPath::try_from(super::tests::OTA_PATH).unwrap(),
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &("org.freedesktop.systemd1.Unit", &changed, &invalidated),
+            )
+            .unwrap();
+        assert_eq!(changes.next(), Some("ota.service".to_string()));
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ratatui::backend::TermionBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Clear, Paragraph, Row, Sparkline, Table, TableState};
+use ratatui::{Frame, Terminal};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::IntoAlternateScreen;
+
+use crate::alert::AlertState;
+use crate::anomaly::AnomalyDetector;
+use crate::collector::{Collection, Collector, DISK_COLUMNS, MEMINFO_COLUMNS, TEMPERATURE_COLUMNS};
+use crate::config::Config;
+use crate::control;
+use crate::log::LogWriter;
+use crate::persist::StateFile;
+use crate::remediation::systemctl;
+use crate::sample::{Metric, Value};
+use crate::systemd_dbus::SystemdClient;
+use crate::unit_state::{unit_name, UnitWatch};
+
+// samples kept for the sparklines
+const HISTORY: usize = 60;
+const JOURNAL_LINES: usize = 30;
+// how long `m` mutes a service in the running doctor
+const MUTE_DURATION: &str = "1h";
+const SPARK: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Default)]
+struct ServiceView {
+    cpu: Option<f64>,
+    memory: Option<f64>,
+    active_state: Option<String>,
+    sub_state: Option<String>,
+    cpu_history: VecDeque<f64>,
+    memory_history: VecDeque<f64>,
+    // (state, threshold - value) of the cpu and memory alerts, in the unit of the thresholds
+    alerts: BTreeMap<Metric, (AlertState, f64)>,
+}
+
+enum Mode {
+    Normal,
+    ConfirmRestart(String),
+    Journal(String, Vec<String>),
+}
+
+/*
+What `systemd-doctor top` shows: the latest collections of every metric and a short history
+for the sparklines. Kept apart from the terminal so it can be fed collections directly.
+*/
+pub struct Dashboard {
+    services: Vec<String>,
+    views: HashMap<String, ServiceView>,
+    globals: HashMap<&'static str, f64>,
+    available_history: VecDeque<f64>,
+    // alerts are still tracked but not highlighted
+    muted: HashSet<String>,
+    // control socket of the running doctor, mutes are sent there too
+    socket: PathBuf,
+    selected: TableState,
+    mode: Mode,
+    status: String,
+}
+
+impl Dashboard {
+    pub fn new(config: &Config) -> Self {
+        let services = config.services.list.clone().unwrap_or_default();
+        let mut selected = TableState::default();
+        if !services.is_empty() {
+            selected.select(Some(0));
+        }
+        Self {
+            services,
+            views: HashMap::new(),
+            globals: HashMap::new(),
+            available_history: VecDeque::new(),
+            muted: HashSet::new(),
+            socket: config.control.socket.clone(),
+            selected,
+            mode: Mode::Normal,
+            status: String::new(),
+        }
+    }
+
+    pub fn apply(&mut self, collection: &Collection) {
+        let metric = collection.sample.metric;
+        for reading in &collection.sample.readings {
+            let number = match &reading.value {
+                Value::Number(value) => Some(*value),
+                _ => None,
+            };
+            let Some(service) = &reading.service else {
+                let column = MEMINFO_COLUMNS
+                    .iter()
+                    .chain(&DISK_COLUMNS)
+                    .chain(&TEMPERATURE_COLUMNS)
+                    .find(|column| **column == reading.column);
+                match (column, number) {
+                    (Some(column), Some(value)) => {
+                        self.globals.insert(column, value);
+                    }
+                    (Some(column), None) => {
+                        self.globals.remove(column);
+                    }
+                    _ => {}
+                }
+                if reading.column == MEMINFO_COLUMNS[2] {
+                    push(&mut self.available_history, number.unwrap_or(0.0));
+                }
+                continue;
+            };
+            let view = self.views.entry(service.clone()).or_default();
+            match metric {
+                Metric::Cpu => {
+                    view.cpu = number;
+                    push(&mut view.cpu_history, number.unwrap_or(0.0));
+                }
+                Metric::Memory if reading.key == metric.name() => {
+                    view.memory = number;
+                    push(&mut view.memory_history, number.unwrap_or(0.0));
+                }
+                Metric::Unit => {
+                    let text =
+                        Some(reading.value.to_string()).filter(|_| reading.value != Value::Missing);
+                    match reading.key.as_str() {
+                        "ActiveState" => view.active_state = text,
+                        "SubState" => view.sub_state = text,
+                        _ => {}
+                    }
+                }
+                Metric::Memory | Metric::Disk | Metric::Temperature => {}
+            }
+        }
+        for alert in &collection.alerts {
+            if let Some(service) = &alert.service {
+                self.views
+                    .entry(service.clone())
+                    .or_default()
+                    .alerts
+                    .insert(alert.metric, (alert.state, alert.threshold - alert.value));
+            }
+        }
+    }
+
+    fn selected_service(&self) -> Option<String> {
+        self.selected
+            .selected()
+            .and_then(|index| self.services.get(index))
+            .cloned()
+    }
+
+    // false once the dashboard should close
+    fn key(&mut self, key: Key) -> bool {
+        match std::mem::replace(&mut self.mode, Mode::Normal) {
+            Mode::ConfirmRestart(service) => {
+                if key == Key::Char('y') {
+                    self.status = match restart(&unit_name(&service)) {
+                        Ok(()) => format!("Restarted {}", service),
+                        Err(e) => format!("Failed to restart {}: {}", service, e),
+                    };
+                } else {
+                    self.status = "Restart cancelled".to_string();
+                }
+                return true;
+            }
+            // q still quits, any other key closes the journal
+            Mode::Journal(..) => return !matches!(key, Key::Char('q') | Key::Ctrl('c')),
+            Mode::Normal => {}
+        }
+        let count = self.services.len();
+        match key {
+            Key::Char('q') | Key::Esc | Key::Ctrl('c') => return false,
+            Key::Down | Key::Char('J') if count > 0 => {
+                let index = self
+                    .selected
+                    .selected()
+                    .map_or(0, |index| (index + 1) % count);
+                self.selected.select(Some(index));
+            }
+            Key::Up | Key::Char('K') if count > 0 => {
+                let index = self
+                    .selected
+                    .selected()
+                    .map_or(0, |index| (index + count - 1) % count);
+                self.selected.select(Some(index));
+            }
+            Key::Char('r') => {
+                if let Some(service) = self.selected_service() {
+                    self.mode = Mode::ConfirmRestart(service);
+                }
+            }
+            Key::Char('j') => {
+                if let Some(service) = self.selected_service() {
+                    let lines =
+                        match LogWriter::journal_tail(&unit_name(&service), JOURNAL_LINES, None) {
+                            Ok((lines, _)) => lines,
+                            Err(e) => vec![format!("Failed to read the journal: {}", e)],
+                        };
+                    self.mode = Mode::Journal(service, lines);
+                }
+            }
+            Key::Char('m') => {
+                if let Some(service) = self.selected_service() {
+                    let unmute = self.muted.remove(&service);
+                    if !unmute {
+                        self.muted.insert(service.clone());
+                    }
+                    let (duration, action) = match unmute {
+                        true => ("0", "Unmuted"),
+                        false => (MUTE_DURATION, "Muted"),
+                    };
+                    let args = [service.clone(), duration.to_string()];
+                    self.status = match control::request(&self.socket, "mute", &args) {
+                        Ok(_) => format!("{} {} in the doctor", action, service),
+                        Err(e) => format!("{} {} here only: {}", action, service, e),
+                    };
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [globals, services, footer] = Layout::vertical([
+            Constraint::Length(6),
+            Constraint::Min(4),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        self.draw_globals(frame, globals);
+        self.draw_services(frame, services);
+
+        let help = match &self.mode {
+            Mode::ConfirmRestart(service) => format!("Restart {}? y/n", service),
+            _ => format!(
+                "q quit  ↑/↓ select  r restart  j journal  m mute  {}",
+                self.status
+            ),
+        };
+        frame.render_widget(Paragraph::new(help), footer);
+
+        if let Mode::Journal(service, lines) = &self.mode {
+            let area = frame.area().inner(ratatui::layout::Margin::new(2, 1));
+            let height = area.height.saturating_sub(2) as usize;
+            let lines: Vec<Line> = lines
+                .iter()
+                .skip(lines.len().saturating_sub(height))
+                .map(|line| Line::from(line.as_str()))
+                .collect();
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(lines).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!(" journal of {} (any key to close) ", service)),
+                ),
+                area,
+            );
+        }
+    }
+
+    fn draw_globals(&self, frame: &mut Frame, area: Rect) {
+        let [text, spark] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(area);
+        let value = |column: &str| {
+            self.globals
+                .get(column)
+                .map_or("N/A".to_string(), |value| format!("{:.0}", value))
+        };
+        let lines = vec![
+            Line::from(format!(
+                "Memory  total {} MB  free {} MB  available {} MB",
+                value(MEMINFO_COLUMNS[0]),
+                value(MEMINFO_COLUMNS[1]),
+                value(MEMINFO_COLUMNS[2])
+            )),
+            Line::from(format!(
+                "        buffers {} MB  cached {} MB",
+                value(MEMINFO_COLUMNS[3]),
+                value(MEMINFO_COLUMNS[4])
+            )),
+            Line::from(format!(
+                "Disk    total {} MB  used {} MB  free {} MB",
+                value(DISK_COLUMNS[0]),
+                value(DISK_COLUMNS[1]),
+                value(DISK_COLUMNS[2])
+            )),
+            Line::from(format!("CPU     {} °C", value(TEMPERATURE_COLUMNS[0]))),
+        ];
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" device ")),
+            text,
+        );
+
+        let data: Vec<u64> = self
+            .available_history
+            .iter()
+            .map(|value| *value as u64)
+            .collect();
+        frame.render_widget(
+            Sparkline::default()
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(" available memory "),
+                )
+                .data(&data)
+                .style(Style::default().fg(Color::Cyan)),
+            spark,
+        );
+    }
+
+    fn draw_services(&mut self, frame: &mut Frame, area: Rect) {
+        let header = Row::new([
+            "SERVICE", "STATE", "CPU%", "RSS MB", "CPU ROOM", "MEM ROOM", "CPU", "RSS",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let empty = ServiceView::default();
+        let rows: Vec<Row> = self
+            .services
+            .iter()
+            .map(|service| {
+                let view = self.views.get(service).unwrap_or(&empty);
+                let muted = self.muted.contains(service);
+                let state = match (&view.active_state, &view.sub_state) {
+                    (Some(active), Some(sub)) => format!("{}/{}", active, sub),
+                    _ => "N/A".to_string(),
+                };
+                let number =
+                    |value: Option<f64>| value.map_or("N/A".to_string(), |v| format!("{:.1}", v));
+                let mut name = service.clone();
+                if muted {
+                    name.push_str(" (muted)");
+                }
+                let style = if muted {
+                    Style::default()
+                } else if view.active_state.as_deref() == Some("failed")
+                    || view
+                        .alerts
+                        .values()
+                        .any(|(state, _)| *state == AlertState::Firing)
+                {
+                    Style::default().fg(Color::Red)
+                } else if view
+                    .alerts
+                    .values()
+                    .any(|(state, _)| *state == AlertState::Pending)
+                {
+                    Style::default().fg(Color::Yellow)
+                } else {
+                    Style::default()
+                };
+                Row::new([
+                    name,
+                    state,
+                    number(view.cpu),
+                    number(view.memory),
+                    headroom(view.alerts.get(&Metric::Cpu), ""),
+                    headroom(view.alerts.get(&Metric::Memory), "%"),
+                    sparkline(&view.cpu_history, 12),
+                    sparkline(&view.memory_history, 12),
+                ])
+                .style(style)
+            })
+            .collect();
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Min(16),
+                Constraint::Length(18),
+                Constraint::Length(7),
+                Constraint::Length(8),
+                Constraint::Length(9),
+                Constraint::Length(9),
+                Constraint::Length(12),
+                Constraint::Length(12),
+            ],
+        )
+        .header(header)
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .block(Block::default().borders(Borders::ALL).title(" services "));
+        frame.render_stateful_widget(table, area, &mut self.selected);
+    }
+}
+
+fn push(history: &mut VecDeque<f64>, value: f64) {
+    if history.len() == HISTORY {
+        history.pop_front();
+    }
+    history.push_back(value);
+}
+
+/// Room left below the alert threshold, `-` for services without one.
+fn headroom(alert: Option<&(AlertState, f64)>, unit: &str) -> String {
+    match alert {
+        Some((_, room)) => format!("{:+.1}{}", room, unit),
+        None => "-".to_string(),
+    }
+}
+
+/// The last `width` values as block characters scaled to their own maximum.
+fn sparkline(history: &VecDeque<f64>, width: usize) -> String {
+    let values: Vec<f64> = history
+        .iter()
+        .skip(history.len().saturating_sub(width))
+        .copied()
+        .collect();
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|value| {
+            if max <= 0.0 {
+                return SPARK[0];
+            }
+            let index = (value / max * (SPARK.len() - 1) as f64).round() as usize;
+            SPARK[index.min(SPARK.len() - 1)]
+        })
+        .collect()
+}
+
+fn restart(unit: &str) -> Result<(), String> {
+    match SystemdClient::system() {
+        Ok(client) => client
+            .restart_unit(unit)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Err(_) => systemctl(&["restart", unit]).map(|_| ()),
+    }
+}
+
+// collects every metric each `interval` until the dashboard hangs up
+fn spawn_sampler(config: Arc<Config>, interval: Duration) -> Receiver<Collection> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // nothing is persisted, the service's own state file is left alone
+        let state_file = Arc::new(StateFile::default());
+        let anomaly = Arc::new(Mutex::new(AnomalyDetector::new(state_file.clone())));
+        let mut collector = Collector::new(
+            config.clone(),
+            Arc::new(Mutex::new(UnitWatch::new())),
+            anomaly,
+            state_file,
+        );
+        let jobs: Vec<_> = Metric::ALL
+            .iter()
+            .flat_map(|metric| Collector::jobs(&config, *metric))
+            .map(|(_, job)| job)
+            .collect();
+        loop {
+            for job in &jobs {
+                if sender.send(collector.collect(job)).is_err() {
+                    return;
+                }
+            }
+            thread::sleep(interval);
+        }
+    });
+    receiver
+}
+
+/// Runs the dashboard until q is pressed.
+pub fn run(config: &Config, interval: Duration) -> io::Result<()> {
+    let collections = spawn_sampler(Arc::new(config.clone()), interval);
+    let mut keys = termion::async_stdin().keys();
+    let stdout = io::stdout().into_raw_mode()?.into_alternate_screen()?;
+    let mut terminal = Terminal::new(TermionBackend::new(stdout))?;
+    terminal.hide_cursor()?;
+    let mut dashboard = Dashboard::new(config);
+
+    loop {
+        let mut sampled = false;
+        match collections.recv_timeout(Duration::from_millis(100)) {
+            Ok(collection) => {
+                dashboard.apply(&collection);
+                sampled = true;
+                while let Ok(collection) = collections.try_recv() {
+                    dashboard.apply(&collection);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let mut running = true;
+        for key in keys.by_ref().flatten() {
+            running &= dashboard.key(key);
+        }
+        if !running {
+            break;
+        }
+        // with [logging] output = "stderr" collection errors land on the screen, a full redraw wipes them
+        if sampled {
+            terminal.clear()?;
+        }
+        terminal.draw(|frame| dashboard.draw(frame))?;
+    }
+
+    terminal.show_cursor()?;
+    terminal.backend_mut().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alert::{Alert, AlertState};
+    use crate::collector::Collection;
+    use crate::config::Config;
+    use crate::sample::{Metric, Reading, Sample, Value};
+    use crate::top::{headroom, sparkline, Dashboard};
+    use chrono::Local;
+
+    #[test]
+    fn test_apply_collection() {
+        let mut config = Config::default();
+        config.services.list = Some(vec!["ota".to_string()]);
+        let mut dashboard = Dashboard::new(&config);
+
+        for cpu in [10.0, 20.0, 40.0] {
+            let mut sample = Sample::new(Metric::Cpu);
+            sample
+                .readings
+                .push(Reading::service("ota", Metric::Cpu, Value::Number(cpu)));
+            dashboard.apply(&Collection {
+                sample,
+                events: Vec::new(),
+                next_interval: None,
+                alerts: vec![Alert {
+                    service: Some("ota".to_string()),
+                    metric: Metric::Cpu,
+                    state: AlertState::Ok,
+                    value: cpu,
+                    threshold: 50.0,
+                    since: Local::now(),
+                }],
+            });
+        }
+
+        let view = &dashboard.views["ota"];
+        assert_eq!(view.cpu, Some(40.0));
+        assert_eq!(sparkline(&view.cpu_history, 12), "▃▅█");
+        assert_eq!(headroom(view.alerts.get(&Metric::Cpu), ""), "+10.0");
+        assert_eq!(headroom(view.alerts.get(&Metric::Memory), "%"), "-");
+    }
+}
@@ -0,0 +1,438 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDateTime, TimeZone};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use crate::collector::MEMINFO_COLUMNS;
+use crate::config::{Config, TrendConfig};
+use crate::event::{EventKind, HealthEvent};
+use crate::sample::{Metric, Sample, Value};
+use crate::tsdb::{store_dir, TimeSeriesStore};
+
+/// Least squares line through the points, slope per unit of x.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fit {
+    pub slope: f64,
+    pub intercept: f64,
+    // coefficient of determination, how well the line explains the values (0..1)
+    pub r2: f64,
+}
+
+pub fn linear_fit(points: &[(f64, f64)]) -> Option<Fit> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let mut sxx = 0.0;
+    let mut sxy = 0.0;
+    let mut syy = 0.0;
+    for (x, y) in points {
+        sxx += (x - mean_x) * (x - mean_x);
+        sxy += (x - mean_x) * (y - mean_y);
+        syy += (y - mean_y) * (y - mean_y);
+    }
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    // a flat series is explained by nothing
+    let r2 = if syy == 0.0 {
+        0.0
+    } else {
+        (sxy * sxy) / (sxx * syy)
+    };
+    Some(Fit {
+        slope,
+        intercept: mean_y - slope * mean_x,
+        r2,
+    })
+}
+
+/// Memory trend of one service over the window.
+#[derive(Clone, Debug)]
+pub struct Trend {
+    // last sampled value in MB
+    pub current: f64,
+    pub slope_per_hour: f64,
+    pub r2: f64,
+    pub limit: Option<f64>,
+    // when the fitted line reaches the limit, None if it never does
+    pub exhaustion: Option<DateTime<Local>>,
+    // growing steadily enough to call it a leak
+    pub leak: bool,
+}
+
+/*
+Fits a line through the (time, MB) points. A leak is suspected when memory grows and the line
+explains the series with at least `confidence` (r²) over at least half of the window, so the
+sawtooth of a service that frees what it allocates is not reported.
+*/
+pub fn analyze(
+    points: &[(DateTime<Local>, f64)],
+    limit: Option<f64>,
+    settings: &TrendConfig,
+) -> Option<Trend> {
+    if points.len() < settings.min_samples.max(2) {
+        return None;
+    }
+    let (first, _) = points[0];
+    let (last, current) = points[points.len() - 1];
+    let seconds: Vec<(f64, f64)> = points
+        .iter()
+        .map(|(time, value)| ((*time - first).num_milliseconds() as f64 / 1000.0, *value))
+        .collect();
+    let fit = linear_fit(&seconds)?;
+    let span = (last - first).num_seconds() as f64;
+
+    let exhaustion = match limit {
+        Some(limit) if fit.slope > 0.0 => {
+            let fitted = fit.intercept + fit.slope * span;
+            let seconds_left = ((limit - fitted) / fit.slope).max(0.0);
+            Some(last + ChronoDuration::seconds(seconds_left as i64))
+        }
+        _ => None,
+    };
+    Some(Trend {
+        current,
+        slope_per_hour: fit.slope * 3600.0,
+        r2: fit.r2,
+        limit,
+        exhaustion,
+        leak: fit.slope > 0.0
+            && fit.r2 >= settings.confidence
+            && span >= settings.window as f64 / 2.0,
+    })
+}
+
+// The memory threshold of the service in MB, the total memory if it has none
+pub fn memory_limit(config: &Config, service: &str, total_mb: Option<f64>) -> Option<f64> {
+    let total = total_mb?;
+    match config.thresholds.get(service).and_then(|t| t.memory) {
+        Some(percent) => Some(total * percent / 100.0),
+        None => Some(total),
+    }
+}
+
+fn describe(trend: &Trend) -> String {
+    let mut message = format!(
+        "memory growing {:.1} MB/h (r²={:.2}), at {:.1} MB",
+        trend.slope_per_hour, trend.r2, trend.current
+    );
+    if let (Some(limit), Some(exhaustion)) = (trend.limit, trend.exhaustion) {
+        let _ = write!(
+            message,
+            ", limit {:.0} MB reached around {}",
+            limit,
+            exhaustion.format("%Y-%m-%d %H:%M")
+        );
+    }
+    message
+}
+
+/// Keeps each service's memory over the trend window and raises `leak_suspected` once per leak.
+#[derive(Default)]
+pub struct TrendWatch {
+    series: HashMap<String, VecDeque<(DateTime<Local>, f64)>>,
+    suspected: HashSet<String>,
+    mem_total_mb: Option<f64>,
+}
+
+impl TrendWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, config: &Config, sample: &Sample) -> Vec<HealthEvent> {
+        let mut events = Vec::new();
+        if sample.metric != Metric::Memory {
+            return events;
+        }
+        let window = ChronoDuration::seconds(config.trend.window as i64);
+        for reading in &sample.readings {
+            let value = match reading.value {
+                Value::Number(value) => value,
+                _ => continue,
+            };
+            let Some(service) = &reading.service else {
+                if reading.key == MEMINFO_COLUMNS[0] && value > 0.0 {
+                    self.mem_total_mb = Some(value);
+                }
+                continue;
+            };
+            // RSS, PSS or USS, the leak is looked for in the measure thresholds use
+            if reading.key != config.memory_measure(service).key() {
+                continue;
+            }
+
+            let series = self.series.entry(service.clone()).or_default();
+            series.push_back((sample.taken_at, value));
+            while series
+                .front()
+                .is_some_and(|(time, _)| sample.taken_at - *time > window)
+            {
+                series.pop_front();
+            }
+
+            let points: Vec<(DateTime<Local>, f64)> = series.iter().copied().collect();
+            let limit = memory_limit(config, service, self.mem_total_mb);
+            let leak = match analyze(&points, limit, &config.trend) {
+                Some(trend) if trend.leak => {
+                    if self.suspected.insert(service.clone()) {
+                        events.push(HealthEvent::new(
+                            Some(service),
+                            EventKind::LeakSuspected,
+                            describe(&trend),
+                        ));
+                    }
+                    true
+                }
+                _ => false,
+            };
+            if !leak {
+                self.suspected.remove(service);
+            }
+        }
+        events
+    }
+}
+
+// (time, MB) points of a service
+pub type Series = Vec<(DateTime<Local>, f64)>;
+
+// service whose thresholds use the measure of `column`: RSS in `ota(MB)`, PSS in `ota PSS(MB)`, ...
+fn measure_service<'a>(config: &Config, column: &'a str) -> Option<&'a str> {
+    if MEMINFO_COLUMNS.contains(&column) {
+        return None;
+    }
+    let name = column.strip_suffix("(MB)")?;
+    let (service, key) = name
+        .split_once(' ')
+        .unwrap_or((name, Metric::Memory.name()));
+    Some(service).filter(|service| config.memory_measure(service).key() == key)
+}
+
+/*
+Per-service memory of DrViet_memory.csv in the measure of its thresholds, and the last total
+memory, N/A values are skipped.
+*/
+pub fn memory_history(
+    config: &Config,
+    path: &Path,
+) -> io::Result<(BTreeMap<String, Series>, Option<f64>)> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(path)?;
+    let header = reader.headers()?.clone();
+    let mut history: BTreeMap<String, Series> = BTreeMap::new();
+    let mut total = None;
+
+    for record in reader.records() {
+        let record = record?;
+        let Some(time) = record
+            .get(0)
+            .and_then(|time| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").ok())
+            .and_then(|time| Local.from_local_datetime(&time).single())
+        else {
+            continue;
+        };
+        for (column, cell) in header.iter().zip(record.iter()).skip(1) {
+            let Ok(value) = cell.parse::<f64>() else {
+                continue;
+            };
+            if column == MEMINFO_COLUMNS[0] {
+                total = Some(value);
+            } else if let Some(service) = measure_service(config, column) {
+                history
+                    .entry(service.to_string())
+                    .or_default()
+                    .push((time, value));
+            }
+        }
+    }
+    Ok((history, total))
+}
+
+/// Per-service memory of the last `window` seconds in the time-series store, and the last total.
+pub fn store_memory_history(
+    config: &Config,
+    store: &TimeSeriesStore,
+    window: u64,
+    now: DateTime<Local>,
+) -> io::Result<(BTreeMap<String, Series>, Option<f64>)> {
+    let from = now - ChronoDuration::seconds(window as i64);
+    let resolution = store.resolution_for(from, now);
+    let mut history = BTreeMap::new();
+    let mut total = None;
+    let prefix = format!("{}/", Metric::Memory);
+    for key in store.series() {
+        let Some(column) = key.strip_prefix(&prefix) else {
+            continue;
+        };
+        let points = store.query(&key, from, now, resolution)?;
+        if column == MEMINFO_COLUMNS[0] {
+            total = points.last().map(|point| point.avg);
+        } else if let Some(service) = measure_service(config, column) {
+            let series = points.iter().map(|point| (point.time, point.avg)).collect();
+            history.insert(service.to_string(), series);
+        }
+    }
+    Ok((history, total))
+}
+
+/// `check` output: the memory trend of each service from the store or the csv history.
+pub fn check(config: &Config) -> io::Result<String> {
+    let (history, total) = if config.store.enabled {
+        let store = TimeSeriesStore::open(&store_dir(config), config.store.clone())?;
+        store_memory_history(config, &store, config.trend.window, Local::now())?
+    } else {
+        let path = config
+            .monitor
+            .output_dir
+            .clone()
+            .unwrap_or_default()
+            .join("DrViet_memory.csv");
+        memory_history(config, &path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read {}: {}", path.display(), e),
+            )
+        })?
+    };
+
+    let mut out = format!(
+        "{:<20} {:>10} {:>12} {:>6}  {}\n",
+        "SERVICE", "MEMORY(MB)", "TREND(MB/h)", "R2", "EXHAUSTION"
+    );
+    let window = ChronoDuration::seconds(config.trend.window as i64);
+    for (service, points) in history {
+        let Some((last, _)) = points.last().copied() else {
+            continue;
+        };
+        let recent: Vec<(DateTime<Local>, f64)> = points
+            .into_iter()
+            .filter(|(time, _)| last - *time <= window)
+            .collect();
+        let limit = memory_limit(config, &service, total);
+        let Some(trend) = analyze(&recent, limit, &config.trend) else {
+            let _ = writeln!(
+                out,
+                "{:<20} {:>10} {:>12} {:>6}  not enough samples",
+                service, "-", "-", "-"
+            );
+            continue;
+        };
+        let exhaustion = match trend.exhaustion {
+            Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{:<20} {:>10.1} {:>+12.2} {:>6.2}  {}{}",
+            service,
+            trend.current,
+            trend.slope_per_hour,
+            trend.r2,
+            exhaustion,
+            if trend.leak { " (leak suspected)" } else { "" }
+        );
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, MemoryMeasure, ServiceThreshold, TrendConfig};
+    use crate::trend::{analyze, check, linear_fit};
+    use chrono::{Duration, Local, TimeZone};
+    use std::fs;
+
+    #[test]
+    fn test_leak_and_sawtooth() {
+        let fit = linear_fit(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0)]).unwrap();
+        assert_eq!(fit.slope, 2.0);
+        assert_eq!(fit.r2, 1.0);
+
+        let settings = TrendConfig {
+            window: 3600,
+            min_samples: 10,
+            confidence: 0.9,
+        };
+        let start = Local.with_ymd_and_hms(2024, 6, 3, 0, 0, 0).unwrap();
+        let at = |minute: i64| start + Duration::minutes(minute);
+        // 10 MB per hour from 100 MB, with some noise
+        let leak: Vec<_> = (0..60)
+            .map(|minute| {
+                (
+                    at(minute),
+                    100.0 + minute as f64 / 6.0 + (minute % 3) as f64 * 0.1,
+                )
+            })
+            .collect();
+        let trend = analyze(&leak, Some(200.0), &settings).unwrap();
+        assert!(trend.leak);
+        assert!((trend.slope_per_hour - 10.0).abs() < 0.5);
+        // about 90 MB left at 10 MB/h
+        let exhaustion = trend.exhaustion.unwrap();
+        assert!(exhaustion > at(59) + Duration::hours(8));
+        assert!(exhaustion < at(59) + Duration::hours(10));
+
+        // service_test_2: up to 200 MB and back down, every 40 s
+        let sawtooth: Vec<_> = (0..360)
+            .map(|step| {
+                let phase = step % 40;
+                let value = if phase < 20 {
+                    phase * 10
+                } else {
+                    (40 - phase) * 10
+                };
+                (start + Duration::seconds(step * 10), value as f64)
+            })
+            .collect();
+        assert!(!analyze(&sawtooth, Some(400.0), &settings).unwrap().leak);
+    }
+
+    #[test]
+    fn test_check_reads_memory_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut csv = "Timestamp,Total Memory(MB),Free Memory(MB),ota(MB),logging(MB),\
+                       ota PSS(MB),logging PSS(MB)\n"
+            .to_string();
+        for minute in 0..40 {
+            csv.push_str(&format!(
+                "2024-06-03 00:{:02}:00,1000,500,{},N/A,N/A,20\n",
+                minute,
+                100 + minute
+            ));
+        }
+        fs::write(dir.path().join("DrViet_memory.csv"), csv).unwrap();
+        let mut config = Config::default();
+        config.monitor.output_dir = Some(dir.path().to_path_buf());
+        config.thresholds.insert(
+            "ota".to_string(),
+            ServiceThreshold {
+                memory: Some(50.0),
+                ..Default::default()
+            },
+        );
+        // the trend of logging is that of its PSS
+        config.thresholds.insert(
+            "logging".to_string(),
+            ServiceThreshold {
+                memory_metric: Some(MemoryMeasure::Pss),
+                ..Default::default()
+            },
+        );
+
+        let out = check(&config).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("logging"));
+        assert!(lines[1].contains(" 20.0 "));
+        assert!(lines[2].starts_with("ota"));
+        assert!(lines[2].contains("+60.00"));
+        // 50 % of 1000 MB, reached in 6h01 at 60 MB/h from 139 MB
+        assert!(lines[2].contains("2024-06-03 06:40"));
+        assert!(lines[2].ends_with("(leak suspected)"));
+    }
+}
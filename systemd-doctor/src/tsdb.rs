@@ -0,0 +1,557 @@
+use chrono::{DateTime, Local, TimeZone};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, StoreConfig};
+use crate::sample::{Message, Metric, Sample, Value};
+use crate::sink::Sink;
+
+// one series key per line, the line number is its id
+const SERIES_FILE: &str = "series";
+const MAGIC: &[u8; 4] = b"SDTS";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 3] = [Resolution::Raw, Resolution::Minute, Resolution::Hour];
+
+    // also the directory of its segments
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+        }
+    }
+
+    // seconds summarized by one rollup
+    fn step(&self) -> i64 {
+        match self {
+            Resolution::Raw => 1,
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+        }
+    }
+
+    // seconds covered by one segment file
+    fn segment_len(&self) -> i64 {
+        match self {
+            Resolution::Raw => 3600,
+            Resolution::Minute => 86400,
+            Resolution::Hour => 30 * 86400,
+        }
+    }
+
+    fn record_len(&self) -> usize {
+        match self {
+            // offset u32, series u16, value f32
+            Resolution::Raw => 10,
+            // offset u32, series u16, count u16, min f32, max f32, avg f32
+            Resolution::Minute | Resolution::Hour => 20,
+        }
+    }
+
+    fn retention(&self, config: &StoreConfig) -> u64 {
+        match self {
+            Resolution::Raw => config.raw_retention,
+            Resolution::Minute => config.minute_retention,
+            Resolution::Hour => config.hour_retention,
+        }
+    }
+}
+
+/// One value of a series, or the summary of the values of one rollup period.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub time: DateTime<Local>,
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+    pub count: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Rollup {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl Rollup {
+    fn new(value: f64) -> Self {
+        Self {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Rollup) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Series of a reading: the metric and the csv column, e.g. `memory/ota(MB)`.
+pub fn series_key(metric: Metric, column: &str) -> String {
+    format!("{}/{}", metric, column)
+}
+
+/// Directory of the store, `tsdb` in the output directory unless `[store] dir` is set.
+pub fn store_dir(config: &Config) -> PathBuf {
+    match &config.store.dir {
+        Some(dir) => dir.clone(),
+        None => config
+            .monitor
+            .output_dir
+            .clone()
+            .unwrap_or_default()
+            .join("tsdb"),
+    }
+}
+
+fn segment_path(dir: &Path, resolution: Resolution, start: i64) -> PathBuf {
+    dir.join(resolution.name()).join(format!("{}.seg", start))
+}
+
+// (start, path, size) of the segments of `resolution`, oldest first
+fn list_segments(dir: &Path, resolution: Resolution) -> io::Result<Vec<(i64, PathBuf, u64)>> {
+    let entries = match fs::read_dir(dir.join(resolution.name())) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut segments = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Some(start) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".seg"))
+            .and_then(|start| start.parse::<i64>().ok())
+        else {
+            continue;
+        };
+        let size = fs::metadata(&path)?.len();
+        segments.push((start, path, size));
+    }
+    segments.sort_by_key(|(start, _, _)| *start);
+    Ok(segments)
+}
+
+fn encode(resolution: Resolution, offset: u32, series: u16, rollup: &Rollup) -> Vec<u8> {
+    let mut record = Vec::with_capacity(resolution.record_len());
+    record.extend_from_slice(&offset.to_le_bytes());
+    record.extend_from_slice(&series.to_le_bytes());
+    match resolution {
+        Resolution::Raw => record.extend_from_slice(&(rollup.sum as f32).to_le_bytes()),
+        Resolution::Minute | Resolution::Hour => {
+            let count = rollup.count.min(u32::from(u16::MAX));
+            record.extend_from_slice(&(count as u16).to_le_bytes());
+            record.extend_from_slice(&(rollup.min as f32).to_le_bytes());
+            record.extend_from_slice(&(rollup.max as f32).to_le_bytes());
+            record
+                .extend_from_slice(&((rollup.sum / f64::from(rollup.count)) as f32).to_le_bytes());
+        }
+    }
+    record
+}
+
+fn decode(resolution: Resolution, record: &[u8]) -> (u32, u16, Rollup) {
+    let u32_at = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+    let f32_at = |at: usize| f64::from(f32::from_bits(u32_at(at)));
+    let offset = u32_at(0);
+    let series = u16::from_le_bytes([record[4], record[5]]);
+    let rollup = match resolution {
+        Resolution::Raw => Rollup::new(f32_at(6)),
+        Resolution::Minute | Resolution::Hour => {
+            let count = u32::from(u16::from_le_bytes([record[6], record[7]]));
+            Rollup {
+                min: f32_at(8),
+                max: f32_at(12),
+                sum: f32_at(16) * f64::from(count),
+                count,
+            }
+        }
+    };
+    (offset, series, rollup)
+}
+
+// appends `records` to `path`, creating it with its header or dropping a torn last record
+fn append_segment(path: &Path, resolution: Resolution, records: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len() as usize;
+    if len < HEADER_LEN {
+        file.set_len(0)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[FORMAT_VERSION, 0, 0, 0])?;
+    } else {
+        let torn = (len - HEADER_LEN) % resolution.record_len();
+        if torn != 0 {
+            file.set_len((len - torn) as u64)?;
+        }
+    }
+    file.write_all(records)
+}
+
+/*
+Append-only store of every numeric reading. Values are kept at full resolution for
+`raw_retention`, and rolled up into 1-minute and 1-hour min/avg/max for longer. Each resolution
+is a directory of fixed-size binary records, one segment file per hour (raw), day (1m) or 30
+days (1h), named after its start time: expiring data is deleting a file. When the store grows
+over `max_size_mb` the oldest raw segments go first, then the oldest 1-minute ones.
+*/
+pub struct TimeSeriesStore {
+    dir: PathBuf,
+    config: StoreConfig,
+    series: HashMap<String, u16>,
+    // start of the raw segment written to, retention is enforced when it changes
+    raw_segment: Option<i64>,
+    // rollup period being filled of the minute and hour resolutions
+    buckets: HashMap<Resolution, (i64, BTreeMap<u16, Rollup>)>,
+}
+
+impl TimeSeriesStore {
+    /// Opens the store in `dir`, which is created on the first write.
+    pub fn open(dir: &Path, config: StoreConfig) -> io::Result<Self> {
+        let mut store = Self {
+            dir: dir.to_path_buf(),
+            config,
+            series: HashMap::new(),
+            raw_segment: None,
+            buckets: HashMap::new(),
+        };
+        store.load_series()?;
+        Ok(store)
+    }
+
+    fn load_series(&mut self) -> io::Result<()> {
+        let contents = match fs::read_to_string(self.dir.join(SERIES_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.series = contents
+            .lines()
+            .enumerate()
+            .map(|(id, key)| (key.to_string(), id as u16))
+            .collect();
+        Ok(())
+    }
+
+    /// Keys of every series in the store, sorted.
+    pub fn series(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.series.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    fn series_id(&mut self, key: &str) -> io::Result<u16> {
+        if let Some(id) = self.series.get(key) {
+            return Ok(*id);
+        }
+        let id = u16::try_from(self.series.len())
+            .map_err(|_| io::Error::other("too many series in the store"))?;
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(SERIES_FILE))?;
+        writeln!(file, "{}", key)?;
+        self.series.insert(key.to_string(), id);
+        Ok(id)
+    }
+
+    // writes the records of one period, all in the segment holding `time`
+    fn write(
+        &mut self,
+        resolution: Resolution,
+        time: i64,
+        values: &BTreeMap<u16, Rollup>,
+    ) -> io::Result<()> {
+        let start = time - time.rem_euclid(resolution.segment_len());
+        let offset = (time - start) as u32;
+        let records: Vec<u8> = values
+            .iter()
+            .flat_map(|(series, rollup)| encode(resolution, offset, *series, rollup))
+            .collect();
+        append_segment(
+            &segment_path(&self.dir, resolution, start),
+            resolution,
+            &records,
+        )?;
+        if resolution == Resolution::Raw && self.raw_segment != Some(start) {
+            self.raw_segment = Some(start);
+            self.enforce_limits(time)?;
+        }
+        Ok(())
+    }
+
+    pub fn append(&mut self, sample: &Sample) -> io::Result<()> {
+        let time = sample.taken_at.timestamp();
+        let mut values = BTreeMap::new();
+        for reading in &sample.readings {
+            if let Value::Number(value) = reading.value {
+                let id = self.series_id(&series_key(sample.metric, &reading.column))?;
+                values.insert(id, Rollup::new(value));
+            }
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+        self.write(Resolution::Raw, time, &values)?;
+
+        for resolution in [Resolution::Minute, Resolution::Hour] {
+            let bucket = time - time.rem_euclid(resolution.step());
+            if self
+                .buckets
+                .get(&resolution)
+                .is_some_and(|(current, _)| bucket > *current)
+            {
+                self.flush_rollups(resolution)?;
+            }
+            // a sample a collector took late counts in the period being filled
+            let (_, rollups) = self
+                .buckets
+                .entry(resolution)
+                .or_insert_with(|| (bucket, BTreeMap::new()));
+            for (series, value) in &values {
+                rollups
+                    .entry(*series)
+                    .and_modify(|rollup| rollup.merge(value))
+                    .or_insert(*value);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_rollups(&mut self, resolution: Resolution) -> io::Result<()> {
+        match self.buckets.remove(&resolution) {
+            Some((bucket, rollups)) if !rollups.is_empty() => {
+                self.write(resolution, bucket, &rollups)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the periods being filled, a later write to the same period is merged when read.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flush_rollups(Resolution::Minute)?;
+        self.flush_rollups(Resolution::Hour)
+    }
+
+    // drops the segments past their retention, then the oldest ones while over max_size_mb
+    fn enforce_limits(&self, now: i64) -> io::Result<()> {
+        let mut segments = Vec::new();
+        for resolution in Resolution::ALL {
+            let cutoff = now - resolution.retention(&self.config) as i64;
+            let mut kept = Vec::new();
+            for (start, path, size) in list_segments(&self.dir, resolution)? {
+                if start + resolution.segment_len() <= cutoff {
+                    fs::remove_file(&path)?;
+                } else {
+                    kept.push((path, size));
+                }
+            }
+            segments.push(kept);
+        }
+
+        let limit = self.config.max_size_mb * 1024 * 1024;
+        let mut total: u64 = segments.iter().flatten().map(|(_, size)| size).sum();
+        for kept in &mut segments {
+            // the newest segment of each resolution is the one written to
+            while total > limit && kept.len() > 1 {
+                let (path, size) = kept.remove(0);
+                fs::remove_file(&path)?;
+                total -= size;
+            }
+        }
+        Ok(())
+    }
+
+    /// The finest resolution still holding data from `from`.
+    pub fn resolution_for(&self, from: DateTime<Local>, now: DateTime<Local>) -> Resolution {
+        let age = (now - from).num_seconds().max(0) as u64;
+        Resolution::ALL
+            .into_iter()
+            .find(|resolution| age <= resolution.retention(&self.config))
+            .unwrap_or(Resolution::Hour)
+    }
+
+    /// Points of `series` between `from` and `to` (inclusive) at `resolution`, oldest first.
+    pub fn query(
+        &self,
+        series: &str,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+        resolution: Resolution,
+    ) -> io::Result<Vec<Point>> {
+        let Some(id) = self.series.get(series).copied() else {
+            return Ok(Vec::new());
+        };
+        let (from, to) = (from.timestamp(), to.timestamp());
+        let mut points: BTreeMap<i64, Rollup> = BTreeMap::new();
+        let mut add = |time: i64, rollup: &Rollup| {
+            if (from..=to).contains(&time) {
+                points
+                    .entry(time)
+                    .and_modify(|point| point.merge(rollup))
+                    .or_insert(*rollup);
+            }
+        };
+
+        for (start, path, _) in list_segments(&self.dir, resolution)? {
+            if start > to || start + resolution.segment_len() <= from {
+                continue;
+            }
+            let contents = fs::read(&path)?;
+            if contents.len() < HEADER_LEN
+                || &contents[..4] != MAGIC
+                || contents[4] != FORMAT_VERSION
+            {
+                warn!("Skipping {}: not a segment of this version", path.display());
+                continue;
+            }
+            for record in contents[HEADER_LEN..].chunks_exact(resolution.record_len()) {
+                let (offset, record_series, rollup) = decode(resolution, record);
+                if record_series == id {
+                    add(start + i64::from(offset), &rollup);
+                }
+            }
+        }
+        // the period being filled, when queried from the process writing the store
+        if let Some((bucket, rollups)) = self.buckets.get(&resolution) {
+            if let Some(rollup) = rollups.get(&id) {
+                add(*bucket, rollup);
+            }
+        }
+
+        Ok(points
+            .into_iter()
+            .filter_map(|(time, rollup)| {
+                Some(Point {
+                    time: Local.timestamp_opt(time, 0).single()?,
+                    min: rollup.min,
+                    avg: rollup.sum / f64::from(rollup.count),
+                    max: rollup.max,
+                    count: rollup.count,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Writes every sample to the time-series store.
+pub struct StoreSink {
+    store: TimeSeriesStore,
+}
+
+impl StoreSink {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        Ok(Self {
+            store: TimeSeriesStore::open(&store_dir(config), config.store.clone())?,
+        })
+    }
+}
+
+impl Sink for StoreSink {
+    fn name(&self) -> &str {
+        "store"
+    }
+
+    fn handle(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Sample(sample) => self.store.append(sample),
+            Message::Event(_) => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::StoreConfig;
+    use crate::sample::{cpu_sample, Sample};
+    use crate::tsdb::{list_segments, Resolution, TimeSeriesStore};
+    use chrono::{Duration, Local, TimeZone};
+
+    // `sample` taken `secs` after 2024-06-03 00:00 UTC
+    fn at(secs: i64, mut sample: Sample) -> Sample {
+        sample.taken_at = Local.timestamp_opt(1_717_372_800 + secs, 0).unwrap();
+        sample
+    }
+
+    #[test]
+    fn test_rollups_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreConfig::default();
+        let mut store = TimeSeriesStore::open(dir.path(), config.clone()).unwrap();
+        // two minutes of samples every 10 s, then one in the third minute
+        for i in 0..12 {
+            store.append(&at(i * 10, cpu_sample(i as f64))).unwrap();
+        }
+        store.append(&at(125, cpu_sample(50.0))).unwrap();
+        store.flush().unwrap();
+
+        let store = TimeSeriesStore::open(dir.path(), config).unwrap();
+        assert_eq!(store.series(), vec!["cpu/ota(%)".to_string()]);
+        let from = Local.timestamp_opt(1_717_372_800, 0).unwrap();
+        let to = from + Duration::hours(1);
+        let raw = store
+            .query("cpu/ota(%)", from, to, Resolution::Raw)
+            .unwrap();
+        assert_eq!(raw.len(), 13);
+        let minutes = store
+            .query("cpu/ota(%)", from, to, Resolution::Minute)
+            .unwrap();
+        assert_eq!(minutes.len(), 3);
+        assert_eq!(
+            (minutes[0].min, minutes[0].max, minutes[0].count),
+            (0.0, 5.0, 6)
+        );
+        assert_eq!(minutes[1].avg, 8.5);
+        let hours = store
+            .query("cpu/ota(%)", from, to, Resolution::Hour)
+            .unwrap();
+        assert_eq!((hours.len(), hours[0].count, hours[0].max), (1, 13, 50.0));
+    }
+
+    #[test]
+    fn test_old_raw_segments_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreConfig {
+            raw_retention: 2 * 3600,
+            ..StoreConfig::default()
+        };
+        let mut store = TimeSeriesStore::open(dir.path(), config).unwrap();
+        for hour in 0..5 {
+            store.append(&at(hour * 3600, cpu_sample(1.0))).unwrap();
+        }
+        let starts: Vec<i64> = list_segments(dir.path(), Resolution::Raw)
+            .unwrap()
+            .into_iter()
+            .map(|(start, _, _)| start - 1_717_372_800)
+            .collect();
+        assert_eq!(starts, vec![7200, 10800, 14400]);
+    }
+}
@@ -0,0 +1,440 @@
+use chrono::NaiveDateTime;
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::cmd_health_check::CmdHealCheck;
+use crate::config::Config;
+use crate::event::{EventKind, HealthEvent};
+use crate::sample::Metric;
+use crate::systemd_dbus::SystemdClient;
+
+// how far apart the cgroup and the unit result may report the same OOM kill, on top of the
+// unit interval
+const OOM_SLACK: Duration = Duration::from_secs(10);
+
+// properties recorded for every tracked unit, also the csv column labels
+pub const UNIT_FIELDS: [&str; 6] = [
+    "ActiveState",
+    "SubState",
+    "NRestarts",
+    "ExecMainStatus",
+    "ExecMainPID",
+    "ActiveEnterTimestamp",
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnitState {
+    pub active_state: String,
+    pub sub_state: String,
+    pub n_restarts: u32,
+    pub exec_main_status: i32,
+    pub exec_main_pid: u32,
+    pub active_enter_timestamp: String,
+    // not recorded in the csv, used to describe failures
+    // how the main process ended: 1 exited, 2 killed, 3 dumped core
+    pub exec_main_code: i32,
+    // "success", "exit-code", "signal", "oom-kill", ...
+    pub result: String,
+    pub control_group: String,
+}
+
+impl UnitState {
+    /// Parses the `Key=Value` lines of `systemctl show`.
+    pub fn parse(output: &str) -> Self {
+        let mut state = UnitState::default();
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                "ActiveState" => state.active_state = value.to_string(),
+                "SubState" => state.sub_state = value.to_string(),
+                "NRestarts" => state.n_restarts = value.parse().unwrap_or(0),
+                "ExecMainStatus" => state.exec_main_status = value.parse().unwrap_or(0),
+                "ExecMainPID" => state.exec_main_pid = value.parse().unwrap_or(0),
+                "ActiveEnterTimestamp" => state.active_enter_timestamp = systemctl_time(value),
+                "ExecMainCode" => state.exec_main_code = value.parse().unwrap_or(0),
+                "Result" => state.result = value.to_string(),
+                "ControlGroup" => state.control_group = value.to_string(),
+                _ => {}
+            }
+        }
+        state
+    }
+
+    /// Values in the order of `UNIT_FIELDS`.
+    pub fn fields(&self) -> [String; 6] {
+        [
+            self.active_state.clone(),
+            self.sub_state.clone(),
+            self.n_restarts.to_string(),
+            self.exec_main_status.to_string(),
+            self.exec_main_pid.to_string(),
+            self.active_enter_timestamp.clone(),
+        ]
+    }
+
+    // failed for good, or failed and waiting for Restart=
+    pub fn is_failed(&self) -> bool {
+        self.active_state == "failed" || self.sub_state == "auto-restart"
+    }
+
+    /// How the main process ended, e.g. "exited with status 1" or "killed by SIGKILL".
+    pub fn exit_description(&self) -> String {
+        match self.exec_main_code {
+            1 => format!("exited with status {}", self.exec_main_status),
+            2 => format!("killed by {}", signal_name(self.exec_main_status)),
+            3 => format!("dumped core on {}", signal_name(self.exec_main_status)),
+            _ => format!("main process status {}", self.exec_main_status),
+        }
+    }
+}
+
+/*
+systemctl prints timestamps in the local zone, e.g. `Mon 2024-06-03 10:00:00 CEST`, written as
+`2024-06-03 10:00:00` like the ones read over D-Bus. Anything else is kept as it is.
+*/
+fn systemctl_time(value: &str) -> String {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let [_, date, time, ..] = parts[..] else {
+        return value.to_string();
+    };
+    match NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S") {
+        Ok(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        Err(_) => value.to_string(),
+    }
+}
+
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return format!("signal {}", signal),
+    };
+    name.to_string()
+}
+
+// `ota` -> `ota.service`, names that already carry a unit type are kept
+pub fn unit_name(service: &str) -> String {
+    if service.contains('.') {
+        service.to_string()
+    } else {
+        format!("{}.service", service)
+    }
+}
+
+/*
+Reads unit state over D-Bus, the client is connected on first use and systemctl is used while
+the system bus is unavailable.
+*/
+pub struct UnitReader {
+    systemd: Option<SystemdClient>,
+    cmd_checker: CmdHealCheck,
+}
+
+impl UnitReader {
+    pub fn new() -> Self {
+        Self {
+            systemd: None,
+            cmd_checker: CmdHealCheck::new(),
+        }
+    }
+
+    pub fn unit_state(&mut self, unit: &str) -> Result<UnitState, String> {
+        if self.systemd.is_none() {
+            self.systemd = SystemdClient::system().ok();
+        }
+        if let Some(client) = &self.systemd {
+            match client.unit_state(unit) {
+                Ok(state) => return Ok(state),
+                Err(e) => {
+                    warn!("Failed to query {} over D-Bus: {}", unit, e);
+                    self.systemd = None;
+                }
+            }
+        }
+        self.cmd_checker.cmd_get_unit_state(unit)
+    }
+}
+
+#[derive(Default)]
+struct UnitHistory {
+    last_restarts: Option<u32>,
+    // time of every restart seen within the flapping window
+    restarts: VecDeque<Instant>,
+    mismatch: bool,
+    flapping: bool,
+    failed: bool,
+    // last OOM kill reported from memory.events and from Result=oom-kill
+    cgroup_oom: Option<Instant>,
+    unit_oom: Option<Instant>,
+}
+
+/*
+Turns consecutive unit states into events: a unit failing (killed by the OOM killer or not), a
+unit not in its configured `status`, and a unit flapping, i.e. NRestarts rising `flap_restarts`
+times within `flap_window` seconds.
+Events are only raised when the condition starts, not on every sample. An OOM kill is seen both
+in the cgroup's memory.events and in Result=oom-kill, only the first of the two is reported.
+*/
+#[derive(Default)]
+pub struct UnitWatch {
+    history: HashMap<String, UnitHistory>,
+}
+
+impl UnitWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(
+        &mut self,
+        config: &Config,
+        service: &str,
+        state: &UnitState,
+        now: Instant,
+    ) -> Vec<HealthEvent> {
+        let mut events = Vec::new();
+        let threshold = config.thresholds.get(service);
+        let history = self.history.entry(service.to_string()).or_default();
+
+        let failed = state.is_failed();
+        if failed && !history.failed {
+            let unit = unit_name(service);
+            if state.result != "oom-kill" {
+                events.push(HealthEvent::new(
+                    Some(service),
+                    EventKind::UnitFailed,
+                    format!(
+                        "{} failed ({}): {}",
+                        unit,
+                        state.result,
+                        state.exit_description()
+                    ),
+                ));
+            } else if !recent(history.cgroup_oom.take(), oom_window(config), now) {
+                history.unit_oom = Some(now);
+                events.push(HealthEvent::new(
+                    Some(service),
+                    EventKind::OomKill,
+                    format!("{} killed by the OOM killer", unit),
+                ));
+            }
+        }
+        history.failed = failed;
+
+        if let Some(expected) = threshold.and_then(|threshold| threshold.status.as_deref()) {
+            let mismatch = state.active_state != expected;
+            if mismatch && !history.mismatch {
+                events.push(HealthEvent::new(
+                    Some(service),
+                    EventKind::StatusMismatch,
+                    format!(
+                        "expected {} but unit is {} ({})",
+                        expected, state.active_state, state.sub_state
+                    ),
+                ));
+            }
+            history.mismatch = mismatch;
+        }
+
+        // NRestarts is reset by a manual start, only count increases
+        if let Some(last_restarts) = history.last_restarts {
+            for _ in last_restarts..state.n_restarts {
+                history.restarts.push_back(now);
+            }
+            if state.n_restarts > last_restarts {
+                events.push(HealthEvent::new(
+                    Some(service),
+                    EventKind::Restarted,
+                    format!("restarted by systemd (NRestarts={})", state.n_restarts),
+                ));
+            }
+        }
+        history.last_restarts = Some(state.n_restarts);
+
+        let flap_restarts = threshold.and_then(|t| t.flap_restarts).unwrap_or(3);
+        let flap_window = Duration::from_secs(threshold.and_then(|t| t.flap_window).unwrap_or(600));
+        while history
+            .restarts
+            .front()
+            .is_some_and(|restart| now.duration_since(*restart) > flap_window)
+        {
+            history.restarts.pop_front();
+        }
+        let flapping = flap_restarts > 0 && history.restarts.len() as u32 >= flap_restarts;
+        if flapping && !history.flapping {
+            events.push(HealthEvent::new(
+                Some(service),
+                EventKind::Flapping,
+                format!(
+                    "restarted {} times within {}s (NRestarts={})",
+                    history.restarts.len(),
+                    flap_window.as_secs(),
+                    state.n_restarts
+                ),
+            ));
+        }
+        history.flapping = flapping;
+
+        events
+    }
+
+    /// Whether an OOM kill counted in memory.events is to be reported, i.e. not already by the unit.
+    pub fn cgroup_oom_kill(&mut self, config: &Config, service: &str, now: Instant) -> bool {
+        let history = self.history.entry(service.to_string()).or_default();
+        if recent(history.unit_oom.take(), oom_window(config), now) {
+            return false;
+        }
+        history.cgroup_oom = Some(now);
+        true
+    }
+}
+
+fn oom_window(config: &Config) -> Duration {
+    config.metric_interval(Metric::Unit) + OOM_SLACK
+}
+
+fn recent(time: Option<Instant>, window: Duration, now: Instant) -> bool {
+    time.is_some_and(|time| now.saturating_duration_since(time) <= window)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ServiceThreshold};
+    use crate::event::{EventKind, HealthEvent};
+    use crate::unit_state::{UnitState, UnitWatch};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_parse_systemctl_show() {
+        let state = UnitState::parse(
+            "ActiveState=active\nSubState=running\nNRestarts=2\nExecMainStatus=0\nExecMainPID=812\nActiveEnterTimestamp=Mon 2024-06-03 10:00:00 UTC\n",
+        );
+        assert_eq!(state.active_state, "active");
+        assert_eq!(state.sub_state, "running");
+        assert_eq!(state.n_restarts, 2);
+        assert_eq!(state.exec_main_pid, 812);
+        // the format of the D-Bus backend
+        assert_eq!(state.active_enter_timestamp, "2024-06-03 10:00:00");
+        assert_eq!(
+            UnitState::parse("ActiveEnterTimestamp=\n").active_enter_timestamp,
+            ""
+        );
+    }
+
+    #[test]
+    fn test_status_mismatch_and_flapping() {
+        let mut config = Config::default();
+        config.thresholds.insert(
+            "logging".to_string(),
+            ServiceThreshold {
+                status: Some("inactive".to_string()),
+                flap_restarts: Some(2),
+                ..Default::default()
+            },
+        );
+        let mut watch = UnitWatch::new();
+        let start = Instant::now();
+        let mut state = UnitState {
+            active_state: "active".to_string(),
+            sub_state: "running".to_string(),
+            ..Default::default()
+        };
+
+        let events = watch.update(&config, "logging", &state, start);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::StatusMismatch);
+        // only reported when the mismatch starts
+        assert!(watch.update(&config, "logging", &state, start).is_empty());
+
+        state.n_restarts = 1;
+        let kinds = |events: Vec<HealthEvent>| -> Vec<EventKind> {
+            events.iter().map(|event| event.kind).collect()
+        };
+        assert_eq!(
+            kinds(watch.update(&config, "logging", &state, start)),
+            vec![EventKind::Restarted]
+        );
+        state.n_restarts = 2;
+        let events = watch.update(&config, "logging", &state, start + Duration::from_secs(5));
+        assert_eq!(
+            kinds(events),
+            vec![EventKind::Restarted, EventKind::Flapping]
+        );
+    }
+
+    #[test]
+    fn test_unit_failure() {
+        let config = Config::default();
+        let mut watch = UnitWatch::new();
+        let now = Instant::now();
+        let mut state = UnitState {
+            active_state: "active".to_string(),
+            sub_state: "running".to_string(),
+            ..Default::default()
+        };
+        assert!(watch.update(&config, "can-parser", &state, now).is_empty());
+
+        state.active_state = "activating".to_string();
+        state.sub_state = "auto-restart".to_string();
+        state.result = "signal".to_string();
+        state.exec_main_code = 2;
+        state.exec_main_status = 11;
+        let events = watch.update(&config, "can-parser", &state, now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::UnitFailed);
+        assert_eq!(
+            events[0].message,
+            "can-parser.service failed (signal): killed by SIGSEGV"
+        );
+
+        state.active_state = "active".to_string();
+        state.sub_state = "running".to_string();
+        assert!(watch.update(&config, "can-parser", &state, now).is_empty());
+        state.active_state = "failed".to_string();
+        state.sub_state = "failed".to_string();
+        state.result = "oom-kill".to_string();
+        let events = watch.update(&config, "can-parser", &state, now);
+        assert_eq!(events[0].kind, EventKind::OomKill);
+        // the same kill counted in memory.events afterwards
+        assert!(!watch.cgroup_oom_kill(&config, "can-parser", now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_oom_kill_reported_once() {
+        let config = Config::default();
+        let mut watch = UnitWatch::new();
+        let now = Instant::now();
+        let mut state = UnitState {
+            active_state: "active".to_string(),
+            sub_state: "running".to_string(),
+            ..Default::default()
+        };
+        assert!(watch.update(&config, "ota", &state, now).is_empty());
+
+        assert!(watch.cgroup_oom_kill(&config, "ota", now));
+        state.active_state = "failed".to_string();
+        state.sub_state = "failed".to_string();
+        state.result = "oom-kill".to_string();
+        assert!(watch.update(&config, "ota", &state, now).is_empty());
+
+        // a later kill of the restarted unit is reported again
+        let later = now + Duration::from_secs(3600);
+        state.active_state = "active".to_string();
+        state.sub_state = "running".to_string();
+        assert!(watch.update(&config, "ota", &state, later).is_empty());
+        assert!(watch.cgroup_oom_kill(&config, "ota", later));
+    }
+}
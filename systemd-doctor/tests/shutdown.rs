@@ -0,0 +1,111 @@
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// READY=1 sent by the doctor to the test's NOTIFY_SOCKET
+fn wait_ready(socket: &UnixDatagram) -> bool {
+    socket.set_read_timeout(Some(STARTUP_TIMEOUT)).unwrap();
+    let mut buffer = [0; 1024];
+    while let Ok(len) = socket.recv(&mut buffer) {
+        if String::from_utf8_lossy(&buffer[..len])
+            .lines()
+            .any(|line| line == "READY=1")
+        {
+            return true;
+        }
+    }
+    false
+}
+
+// a data row under the header of every file
+fn wait_for_rows(paths: &[PathBuf]) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < STARTUP_TIMEOUT {
+        if paths.iter().all(|path| {
+            fs::read_to_string(path).is_ok_and(|contents| contents.lines().count() >= 2)
+        }) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+fn assert_csv_intact(path: &Path) {
+    let contents = fs::read_to_string(path).unwrap();
+    assert!(contents.ends_with('\n'), "{} ends mid-row", path.display());
+
+    let mut reader = csv::Reader::from_path(path).unwrap();
+    let columns = reader.headers().unwrap().len();
+    let mut rows = 0;
+    for record in reader.records() {
+        let record = record.unwrap();
+        assert_eq!(record.len(), columns, "short row in {}", path.display());
+        rows += 1;
+    }
+    assert!(rows > 0, "no rows in {}", path.display());
+}
+
+#[test]
+fn test_sigterm_flushes_and_exits_cleanly() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "[monitor]\ninterval = 1\noutput_dir = {:?}\n\n[services]\nlist = [\"sh\", \"sleep\"]\n\n\
+             [control]\nenabled = true\nsocket = {:?}\n",
+            dir.path().join("out"),
+            dir.path().join("control.sock")
+        ),
+    )
+    .unwrap();
+    let notify_path = dir.path().join("notify");
+    let notify_socket = UnixDatagram::bind(&notify_path).unwrap();
+    // without pgrep, ps or df the cells are N/A, the rows are still written
+    let csv_files: Vec<PathBuf> = ["DrViet_memory.csv", "DrViet_cpu.csv", "DrViet_disk.csv"]
+        .iter()
+        .map(|name| dir.path().join("out").join(name))
+        .collect();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_systemd-doctor"))
+        .arg(format!("--config={}", config_path.display()))
+        .env("NOTIFY_SOCKET", &notify_path)
+        .env_remove("WATCHDOG_USEC")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    if !wait_ready(&notify_socket) || !wait_for_rows(&csv_files) {
+        child.kill().unwrap();
+        panic!("systemd-doctor did not start sampling");
+    }
+
+    assert_eq!(
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) },
+        0
+    );
+
+    let started = Instant::now();
+    let exit_status = loop {
+        if let Some(exit_status) = child.try_wait().unwrap() {
+            break exit_status;
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            child.kill().unwrap();
+            panic!("systemd-doctor did not stop on SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(exit_status.success(), "exited with {}", exit_status);
+    assert!(!dir.path().join("control.sock").exists());
+
+    for path in &csv_files {
+        assert_csv_intact(path);
+    }
+}